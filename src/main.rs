@@ -1,16 +1,88 @@
-use std::thread;
-use std::time::{Duration, SystemTime};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use clap::{Command, Arg, ArgAction};
 
 use std::process::Command as ProcessCommand;
 
-use crate::spindown_daemon::{DeviceInfo, get_device_info};
-use crate::spindown_daemon::ata::{do_standby, PowerState};
+use crate::spindown_daemon::{DeviceInfo, get_device_info, get_power_state, StandbyMethod};
+use crate::spindown_daemon::ata::{check_access, check_power_state_with_sense, describe_sense, do_standby,
+                                  do_standby_with_sense, get_apm_level, hex_dump, identify,
+                                  identify_model, load_cycle_count, set_apm, set_standby_timer,
+                                  smart_read_data, AtaResponse, PowerState, PowerTarget, sense_buffer,
+                                  set_sense_len, MIN_SENSE_LEN};
+use crate::spindown_daemon::backend::{AtaBackend, PowerBackend, PowerSource, Probes};
+use crate::spindown_daemon::console::{self, ColorMode};
+use crate::spindown_daemon::control::{send_command, ControlCommand, ControlRequest, ControlServer, DEFAULT_SOCKET};
+use crate::spindown_daemon::daemon::{Action, Daemon};
+#[cfg(feature = "dbus")]
+use crate::spindown_daemon::dbus::{DbusService, SleepEvent};
+use crate::spindown_daemon::errors::{DeviceError, DeviceErrorKind};
+use crate::spindown_daemon::event_log::EventLog;
+use crate::spindown_daemon::privileges::{drop_privileges, Credentials};
+use crate::spindown_daemon::recorder::Recorder;
+use crate::spindown_daemon::events::{EventKind, Notifiers};
+use crate::spindown_daemon::hooks::{HookCommands, Hooks};
+use crate::spindown_daemon::hotplug::{HotplugEvent, HotplugMonitor};
+use crate::spindown_daemon::journald::{self, Journal, LogBackend};
+use crate::spindown_daemon::metrics::Metrics;
+#[cfg(feature = "mqtt")]
+use crate::spindown_daemon::mqtt::{MqttConfig, MqttPublisher};
+use crate::spindown_daemon::paths::Paths;
+use crate::spindown_daemon::pidfile::PidFile;
+use crate::spindown_daemon::resume::ResumeDetector;
+use crate::spindown_daemon::round::{stagger_checks, standby_device, RoundContext};
+use crate::spindown_daemon::signals;
+use crate::spindown_daemon::simulate::{read_trace, simulate};
+use crate::spindown_daemon::spec::{read_device_file, DeviceSpec, MIN_CHECK_INTERVAL};
+use crate::spindown_daemon::state::StateFile;
+use crate::spindown_daemon::status::{self, format_duration, parse_duration, SuspendStatus};
+use crate::spindown_daemon::status_file::StatusFile;
+use crate::spindown_daemon::suspend_check::{CheckLogic, SuspendChecks};
+use crate::spindown_daemon::suspend_gate::{SuspendGate, SuspendMode};
+use crate::spindown_daemon::sysfs::{get_device_model, get_device_serial, get_device_stats, get_root_disks, is_rotational,
+                                    list_block_devices, resolve_mapper_name};
+use crate::spindown_daemon::webhook::Webhook;
+use crate::spindown_daemon::worker::{DeviceWorker, RetryPolicy};
 
 mod spindown_daemon;
 
+// exit code of the power-state command if the state cannot be determined,
+// the other codes are the ones of PowerState::code()
+const POWER_STATE_ERROR: i32 = 100;
+// runs the system sleep unless --suspend-command replaces it
+const SYSTEMCTL: &str = "/usr/bin/systemctl";
+// time a disk gets to settle between STANDBY IMMEDIATE and the power check of the self-test
+const SELF_TEST_SETTLE: Duration = Duration::from_secs(2);
+// exit code of the daemon once every device failed its last --max-failed-checks checks
+const DEVICES_UNUSABLE_EXIT: i32 = 3;
+// share of the rated load/unload cycles a disk is warned about at
+const LOAD_CYCLE_WARN_PERCENT: u64 = 80;
+const LOAD_CYCLE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+// settings of every device, unless overridden by its spec
+struct DeviceDefaults {
+    power_target: PowerTarget,
+    check_interval: u64,
+    apm_level: Option<u8>,
+    disable_standby_timer: bool,
+    monitor_unsupported: bool,
+    standby_retries: u32,
+    rated_load_cycles: u64,
+    iops_tolerance: u64,
+    // source and disks of the root filesystem, which are only watched; None with --allow-root-disk
+    root_filesystem: Option<(String, Vec<String>)>,
+}
+
 fn main() {
+    std::process::exit(run());
+}
+
+// the daemon returns its exit code instead of exiting, so the pid file and sockets are removed on the way out
+fn run() -> i32 {
     let greater_than_zero_value_parser = |val: &str| {
         match val.parse::<u64>() {
             Ok(num) => {
@@ -29,19 +101,111 @@ fn main() {
         .about("Spin-down hard disks without relying on the firmware")
         .arg(Arg::new("check-timeout")
             .short('i')
-            .help("Check interval in seconds (default: 60)")
+            .help(format!("Check interval in seconds, unless set per device (at least {}, default: 60)",
+                          MIN_CHECK_INTERVAL))
             .default_value("60")
             .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("i-know-what-im-doing")
+            .long("i-know-what-im-doing")
+            .help(format!("Allow check intervals below {} seconds, for -i and the interval of a device", MIN_CHECK_INTERVAL))
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("idle-poll-interval")
+            .long("idle-poll-interval")
+            .help("Check interval in seconds while all devices are in standby without I/O (default: the check interval)")
+            .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("stagger")
+            .long("stagger")
+            .help("Spread the checks of the devices evenly over the check interval instead of checking them \
+                   back-to-back, e.g. for enclosures behind a port multiplier")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("check-deadline")
+            .long("check-deadline")
+            .help("Wait at most n-seconds for the devices to answer a check (default: 5)")
+            .long_help("Wait at most n-seconds for the devices to answer a check (default: 5); \
+                devices answering later keep their last known state until the check completes")
+            .default_value("5")
+            .value_parser(greater_than_zero_value_parser))
         .arg(Arg::new("iops-tolerance")
             .short('t')
-            .help("Tolerance for read/write IO operations (default: 1)")
+            .help("Tolerance for read/write IO operations, unless set per device (default: 1)")
             .long_help(
                 "Put device to sleep, even if this amount of IOPS have been read/written; \
-                Checking the power state adds one read, so using 0 would prevent sleep \
-                completely and thus is not allowed."
+                0 means that any I/O at all keeps the device awake. Neither reading the \
+                stat counters nor checking the power state (an ATA pass-through command) \
+                is accounted in the device statistics."
             )
             .default_value("1")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(Arg::new("reconcile-interval")
+            .long("reconcile-interval")
+            .help("Query the power state of devices not in standby at least every n-seconds (default: 900)")
+            .long_help("Query the power state of devices not in standby at least every n-seconds (default: 900); \
+                otherwise, the power state is only queried when I/O starts on a device in a power saving state \
+                and when the timeout of a device has elapsed")
+            .default_value("900")
+            .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("group-timeout")
+            .long("group-timeout")
+            .help("Put all devices to sleep together once none of them had I/O for n-seconds")
+            .long_help("Put all devices to sleep together once none of them had I/O for n-seconds, in addition \
+                to their own timeouts; watch-only devices (timeout 0) count for the I/O, but are not put to sleep")
+            .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("dirty-threshold")
+            .long("dirty-threshold")
+            .help("Postpone the standby of a device while more than n-KiB of dirty data wait to be written to it")
+            .long_help("Postpone the standby of a device while more than n-KiB of dirty data wait to be written \
+                to it, which would spin it up again right away. Per device with debugfs mounted \
+                (/sys/kernel/debug/bdi), otherwise the dirty data of the whole system count")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(Arg::new("power-target")
+            .long("power-target")
+            .help("Power state devices are put into after their timeout: standby, idle or sleep (default: standby)")
+            .long_help("Power state devices are put into after their timeout (default: standby)
+standby: heads unloaded, spindle stopped
+idle: heads unloaded, spindle keeps spinning
+sleep: lowest power, waking up requires a device reset (needs --allow-sleep)")
+            .default_value("standby")
+            .value_parser(|val: &str| val.parse::<PowerTarget>()))
+        .arg(Arg::new("power-source")
+            .long("power-source")
+            .help("Read the power states by ATA pass-through or take standby from the runtime PM status in sysfs \
+                while the kernel has the disk suspended: ata or sysfs (default: ata)")
+            .default_value("ata")
+            .value_parser(PowerSource::from_str))
+        .arg(Arg::new("allow-sleep")
+            .long("allow-sleep")
+            .help("Allow the sleep power target")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("standby-retries")
+            .long("standby-retries")
+            .help("Verify each power command and repeat it up to n times while the disk is not in the target state \
+                (default: 0, no verification)")
+            .default_value("0")
+            .value_parser(clap::value_parser!(u32)))
+        .arg(Arg::new("allow-root-disk")
+            .long("allow-root-disk")
+            .help("Put the disk holding the root filesystem to sleep, too (default: only watch-only is allowed)")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("set-apm")
+            .long("set-apm")
+            .help("Set the APM level of all devices at startup (1-254, 255 disables APM)")
+            .long_help("Set the advanced power management level of all devices at startup; \
+                1-127 allow the firmware to spin down, 128-254 do not and 255 disables APM")
+            .value_parser(clap::value_parser!(u8).range(1..)))
+        .arg(Arg::new("disable-firmware-standby")
+            .long("disable-firmware-standby")
+            .help("Disable the firmware standby timer of all devices at startup")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("rated-load-cycles")
+            .long("rated-load-cycles")
+            .help("Load/unload cycles the disks are rated for, a disk past 80% of them is warned about at startup \
+                (default: 300000)")
+            .default_value("300000")
             .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("daily-load-cycle-check")
+            .long("daily-load-cycle-check")
+            .help("Read the load/unload cycles of the spinning disks once a day as well, not only at startup")
+            .action(ArgAction::SetTrue))
         .arg(Arg::new("suspend")
             .long("suspend")
             .help("Suspend system after all drives are sleeping")
@@ -51,175 +215,1444 @@ fn main() {
             .help("Wait n-seconds before system suspend after all drives are sleeping")
             .default_value("60")
             .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("suspend-cooldown")
+            .long("suspend-cooldown")
+            .help("Wait n-seconds after a suspend attempt before trying again (default: 600)")
+            .long_help("Wait n-seconds after a suspend attempt (successful or blocked by the check scripts) \
+                before trying again, unless there was new disk activity in the meantime")
+            .default_value("600")
+            .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("min-awake-after-resume")
+            .long("min-awake-after-resume")
+            .help("Stay awake at least n-seconds after the system resumed before suspending it again (default: 0)")
+            .default_value("0")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(Arg::new("suspend-ignore-watch-only")
+            .long("suspend-ignore-watch-only")
+            .help("Do not wait for watch-only devices (timeout 0) before system suspend")
+            .action(ArgAction::SetTrue))
         .arg(Arg::new("suspend-check-script")
             .long("suspend-check-script")
-            .help("Path of external script to block the system suspension")
-            .long_help("Exit code 0 allows suspend; every other code will block it"))
+            .help("Path of external script to block the system suspension, can be repeated")
+            .long_help("Exit code 0 allows suspend; every other code will block it. \
+                Can be repeated, the scripts are run in the given order")
+            .action(ArgAction::Append))
+        .arg(Arg::new("suspend-check-logic")
+            .long("suspend-check-logic")
+            .help("Suspend if all or any of the suspend check scripts allow it (default: all)")
+            .default_value("all")
+            .value_parser(CheckLogic::from_str))
+        .arg(Arg::new("suspend-check-shell")
+            .long("suspend-check-shell")
+            .help("Run the suspend check scripts with this interpreter, e.g. bash (default: run them directly)"))
+        .arg(Arg::new("suspend-mode")
+            .long("suspend-mode")
+            .help("Put the system to sleep with systemctl suspend, hibernate, hybrid-sleep or suspend-then-hibernate \
+                   (default: suspend)")
+            .default_value("suspend")
+            .value_parser(SuspendMode::from_str))
+        .arg(Arg::new("suspend-command")
+            .long("suspend-command")
+            .help("Suspend the system with this command line instead of systemctl, run with sh -c, e.g. \
+                   'echo mem > /sys/power/state' (default: /usr/bin/systemctl with --suspend-mode)"))
+        .arg(Arg::new("suspend-check-timeout")
+            .long("suspend-check-timeout")
+            .help("Kill a suspend check script after n-seconds, counting it as blocking (default: 30)")
+            .default_value("30")
+            .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("mqtt-broker")
+            .long("mqtt-broker")
+            .help("Publish power states and events to this MQTT broker (host[:port]; requires the mqtt feature)"))
+        .arg(Arg::new("mqtt-username")
+            .long("mqtt-username")
+            .help("Log in to the MQTT broker as this user"))
+        .arg(Arg::new("mqtt-password-file")
+            .long("mqtt-password-file")
+            .help("Read the password of the MQTT user from this file")
+            .requires("mqtt-username"))
+        .arg(Arg::new("mqtt-topic-prefix")
+            .long("mqtt-topic-prefix")
+            .help("Topic prefix for MQTT messages (default: spindown)")
+            .default_value("spindown"))
+        .arg(Arg::new("mqtt-discovery")
+            .long("mqtt-discovery")
+            .help("Publish Home Assistant MQTT discovery messages")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("webhook-url")
+            .long("webhook-url")
+            .help("POST a JSON message to this URL on standby, wake and error events"))
+        .arg(Arg::new("webhook-events")
+            .long("webhook-events")
+            .help("Comma-separated events sent to the webhook (default: standby,wake,error)")
+            .default_value("standby,wake,error")
+            .value_delimiter(',')
+            .value_parser(|val: &str| val.parse::<EventKind>()))
+        .arg(Arg::new("on-standby")
+            .long("on-standby")
+            .help("Run this executable after a device was put to sleep"))
+        .arg(Arg::new("on-wake")
+            .long("on-wake")
+            .help("Run this executable after a device woke up"))
+        .arg(Arg::new("on-error")
+            .long("on-error")
+            .help("Run this executable after a check or power command failed"))
+        .arg(Arg::new("hook-timeout")
+            .long("hook-timeout")
+            .help("Kill hooks running longer than n-seconds (default: 30)")
+            .default_value("30")
+            .value_parser(greater_than_zero_value_parser))
+        .arg(Arg::new("metrics-listen")
+            .long("metrics-listen")
+            .help("Serve Prometheus metrics at /metrics on this address, e.g. 127.0.0.1:9178, [::1]:9178 or a unix socket path"))
+        .arg(Arg::new("control-socket")
+            .long("control-socket")
+            .help("Accept the commands of `spindown-daemon ctl` on this unix socket, e.g. /run/spindown-daemon.sock")
+            .long_help("Accept the commands of `spindown-daemon ctl` on this unix socket, e.g. \
+                /run/spindown-daemon.sock (the default of ctl --socket). The socket is created with mode 0660, \
+                everyone allowed to write to it can control the daemon. Devices added through it take no hooks, \
+                those are only set on the command line or in --device-file"))
+        .arg(Arg::new("dbus")
+            .long("dbus")
+            .help("Provide org.spindown.Daemon on the system bus and put the disks to sleep before logind suspends \
+                the system (requires the dbus feature)")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("event-log")
+            .long("event-log")
+            .help("Append the events as JSON lines to this file, reopened on SIGHUP"))
+        .arg(Arg::new("record")
+            .long("record")
+            .value_name("TRACE")
+            .help("Append the counters and power state of every device after each check round to this file, \
+                in the trace format of --simulate, reopened on SIGHUP"))
+        .arg(Arg::new("pidfile")
+            .long("pidfile")
+            .help("Write the process id to this file, refusing to start if it belongs to a running process"))
+        .arg(Arg::new("user")
+            .long("user")
+            .help("Run as this user once the devices were probed, keeping only CAP_SYS_RAWIO for the \
+                   pass-through commands; the user or group needs read access to the device nodes"))
+        .arg(Arg::new("group")
+            .long("group")
+            .requires("user")
+            .help("Group to run as with --user, e.g. disk (default: the primary group of the user)"))
+        .arg(Arg::new("state-file")
+            .long("state-file")
+            .help("Keep idle timers and statistics in this file across restarts, e.g. /var/lib/spindown-daemon/state.json"))
+        .arg(Arg::new("status-file")
+            .long("status-file")
+            .help("Rewrite this file with the time and a summary after every check, e.g. for a container healthcheck"))
+        .arg(Arg::new("inhibit-file")
+            .long("inhibit-file")
+            .help("Neither put devices to sleep nor suspend the system while this file exists, e.g. during a backup"))
+        .arg(Arg::new("foreground")
+            .long("foreground")
+            .help("Stay in the foreground, which is what the daemon always does; for service managers and scripts \
+                that pass it explicitly")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("require-devices")
+            .long("require-devices")
+            .help("Exit at startup if any device fails to initialize, instead of going on without it")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("max-failed-checks")
+            .long("max-failed-checks")
+            .help("Exit with status 3 once every device failed its last n checks in a row, 0 never exits (default: 10)")
+            .default_value("10")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(Arg::new("max-runtime")
+            .long("max-runtime")
+            .visible_alias("exit-after")
+            .help("Shut down cleanly like on SIGTERM after running for n-seconds or a duration like 90m or 2h, \
+                not counting the time the system is suspended")
+            .value_parser(|val: &str| match parse_duration(val) {
+                Ok(0) => Err(String::from("value must be greater than 0")),
+                result => result,
+            }))
+        .arg(Arg::new("sysfs-root")
+            .long("sysfs-root")
+            .help("Root of the sysfs tree (default: /sys)")
+            .default_value("/sys"))
+        .arg(Arg::new("dev-root")
+            .long("dev-root")
+            .help("Root of the device nodes (default: /dev)")
+            .default_value("/dev"))
+        .arg(Arg::new("sense-len")
+            .long("sense-len")
+            .help(format!("Size of the sense buffer of the ATA commands in bytes, raise it when the sense data \
+                           is reported truncated ({}-255, default: 32)", MIN_SENSE_LEN))
+            .default_value("32")
+            .value_parser(clap::value_parser!(u8).range(MIN_SENSE_LEN as i64..)))
+        .arg(Arg::new("standby-on-exit")
+            .long("standby-on-exit")
+            .help("Put all rotational devices into standby when receiving SIGTERM or SIGINT, e.g. before a power-off")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("monitor-unsupported")
+            .long("monitor-unsupported")
+            .help("Watch devices without ATA pass-through instead of refusing them; they are never spun down")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("simulate")
+            .long("simulate")
+            .value_name("TRACE")
+            .help("Replay a trace of recorded stat files with the timeouts and -t instead of watching the devices, \
+                printing when they would have been put to sleep")
+            .long_help("Replay a trace of recorded stat files with the timeouts and -t instead of watching the \
+                devices, printing when they would have been put to sleep and woken up; one \
+                `<unix time> <device> <content of /sys/block/<device>/stat>` line per sample"))
+        .arg(Arg::new("device-file")
+            .long("device-file")
+            .help("Read additional devices from this file, one DEVICE:TIMEOUT per line, # starts a comment"))
+        .arg(Arg::new("hotplug")
+            .long("hotplug")
+            .help("Watch devices as soon as they appear, e.g. in hot-swap bays, and drop them when they are removed")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("heartbeat")
+            .long("heartbeat")
+            .help("Log a one-line summary of all devices after every check")
+            .action(ArgAction::SetTrue))
         .arg(Arg::new("debug")
             .short('d')
             .help("Enable debug output")
             .action(ArgAction::SetTrue))
+        .arg(Arg::new("color")
+            .long("color")
+            .help("Color the output: auto (on a terminal), always or never (default: auto)")
+            .default_value("auto")
+            .value_parser(ColorMode::from_str))
+        .arg(Arg::new("log-backend")
+            .long("log-backend")
+            .help("Write the log to stderr or to the systemd journal with SPINDOWN_DEVICE and SPINDOWN_EVENT fields \
+                   on the events: stderr or journald (default: stderr)")
+            .default_value("stderr")
+            .value_parser(LogBackend::from_str))
         .arg(Arg::new("DEVICE:TIMEOUT")
             .long_help(
-                "Device-names and timeout in seconds
-Example: sda1:3600 md127:600")
-            .required(true)
+                "Device-names and timeout in seconds; a timeout of 0 only watches the device
+Device-mapper devices (dm-N or /dev/mapper/NAME) put their underlying disks to sleep
+Options can be appended with commas: target=standby|idle|sleep overrides --power-target,
+interval=SECONDS (at least 5) overrides the check interval (-i),
+on-standby=PATH, on-wake=PATH and on-error=PATH override the hooks,
+load-cycles=N overrides --rated-load-cycles, tolerance=N overrides -t
+Patterns like sd* or sd[b-e] are expanded against /sys/block
+Example: sda:3600 sdb:600,target=idle,interval=15 sdc:0 /dev/mapper/vg-data:3600")
+            .required_unless_present("device-file")
             .num_args(1..)
-            .value_parser(|val: &str| -> Result<String, &str> {
-                let (device_name_str, device_timeout_str) = if let Some((a, b)) = val.split_once(':') {
-                    (a, b)
-                } else {
-                    return Err("invalid amount of elements");
-                };
-
-                let device_name = String::from(device_name_str);
-                if !device_name.starts_with("sd") || !device_name.ends_with(|v: char| {
-                    // allow a-z only
-                    let unicode = v as u32;
-                    unicode >= 97 && unicode <= 122
-                }) {
-                    return Err("device name must have format `sd[a-z]`");
-                }
-                match device_timeout_str.parse::<u64>() {
-                    Ok(device_timeout) => {
-                        if device_timeout < 1 {
-                            return Err("device timeout must be greater than 0");
-                        }
-                    }
-                    Err(_) => return Err("device timeout must be a number")
-                }
-                Ok(String::from(val))
-            })
+            .value_parser(|val: &str| val.parse::<DeviceSpec>())
         )
+        .subcommand_negates_reqs(true)
+        .subcommand(Command::new("power-state")
+            .about("Print the power state of a device and exit with its code")
+            .long_about("Print the power state of a device and exit with its code:
+0 Standby, 1 Idle, 2 IdleA, 3 IdleB, 4 IdleC, 5 ActiveOrIdle, 255 Unknown,
+100 if the state cannot be determined (e.g. device not found)")
+            .arg(Arg::new("DEVICE")
+                .help("Device-name, e.g. sdb, dm-0 or /dev/mapper/NAME")
+                .required(true)))
+        .subcommand(Command::new("self-test")
+            .about("Test whether the daemon can check the power state of a disk and put it to sleep")
+            .long_about("Test whether the daemon can check the power state of a disk and put it to sleep:
+checks the power state, then (after confirming or with --yes) issues STANDBY IMMEDIATE and
+checks that the disk reports standby. Prints PASS or FAIL with the raw sense data and exits
+with 0 or 1")
+            .arg(Arg::new("yes")
+                .long("yes")
+                .help("Put the disk into standby without asking")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("DEVICE")
+                .help("Device-name of a disk, e.g. sdb or /dev/sdb")
+                .required(true)))
+        .subcommand(Command::new("test")
+            .about("Check step by step whether the daemon can work with a disk")
+            .long_about("Check step by step whether the daemon can work with a disk: opening the device node,
+reading its stats, whether it is rotational, CHECK POWER MODE and IDENTIFY DEVICE, and with
+--park whether it reports standby after STANDBY IMMEDIATE. Prints a PASS, FAIL or SKIP line
+per step and exits with 1 if a step failed")
+            .arg(Arg::new("park")
+                .long("park")
+                .help("Put the disk into standby and check that it reports it")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("DEVICE")
+                .help("Device-name of a disk, e.g. sdb or /dev/sdb")
+                .required(true)))
+        .subcommand(Command::new("list-devices")
+            .about("List the block devices with their power state and whether they support ATA pass-through"))
+        .subcommand(Command::new("ctl")
+            .about("Send a command to the running daemon, see --control-socket")
+            .long_about("Send a command to the running daemon, see --control-socket:
+status                      print the state of every device and of the suspend logic
+standby DEVICE              put a device to sleep now, regardless of its timeout
+add DEVICE:TIMEOUT[,...]    watch a device, like the DEVICE:TIMEOUT arguments without the hook options
+remove DEVICE               stop watching a device
+reset                       clear the standby and wake counts, failed checks and not parking flags of all devices")
+            .arg(Arg::new("socket")
+                .long("socket")
+                .help(format!("Unix socket of the daemon (default: {})", DEFAULT_SOCKET))
+                .default_value(DEFAULT_SOCKET))
+            .arg(Arg::new("COMMAND")
+                .help("status, standby DEVICE, add DEVICE:TIMEOUT, remove DEVICE or reset")
+                .required(true)
+                .num_args(1..)))
         .get_matches();
 
-    if matches.get_flag("debug") {
-        stderrlog::new().
-            verbosity(log::LevelFilter::Debug as usize).
+    let heartbeat: bool = matches.get_flag("heartbeat");
+    let log_level = if matches.get_flag("debug") {
+        log::LevelFilter::Debug
+    } else if heartbeat {
+        log::LevelFilter::Info
+    } else {
+        log::LevelFilter::Warn
+    };
+    let color_mode = *matches.get_one::<ColorMode>("color").unwrap();
+    console::init(color_mode);
+    let log_backend = *matches.get_one::<LogBackend>("log-backend").unwrap();
+    match log_backend {
+        // stderrlog counts verbosity from Error = 0
+        LogBackend::Stderr => stderrlog::new().
+            verbosity(log_level as usize - 1).
+            color(color_mode.color_choice()).
             module(module_path!()).
-            init().unwrap();
+            init().unwrap(),
+        LogBackend::Journald => if let Err(e) = journald::init(log_level, module_path!()) {
+            println!("{}. exiting...", e);
+            return 1;
+        },
     }
 
+    set_sense_len(*matches.get_one::<u8>("sense-len").unwrap());
+    let paths = Paths::new(matches.get_one::<String>("sysfs-root").unwrap(),
+                           matches.get_one::<String>("dev-root").unwrap());
+
+    if let Some(("power-state", sub_matches)) = matches.subcommand() {
+        let device = sub_matches.get_one::<String>("DEVICE").unwrap();
+        return power_state_command(device, &paths);
+    }
+    if let Some(("self-test", sub_matches)) = matches.subcommand() {
+        let device = sub_matches.get_one::<String>("DEVICE").unwrap();
+        return self_test_command(device, sub_matches.get_flag("yes"), &paths);
+    }
+    if let Some(("test", sub_matches)) = matches.subcommand() {
+        let device = sub_matches.get_one::<String>("DEVICE").unwrap();
+        return test_command(device, sub_matches.get_flag("park"), &paths);
+    }
+    if let Some(("list-devices", _)) = matches.subcommand() {
+        return list_devices_command(&paths);
+    }
+    if let Some(trace) = matches.get_one::<String>("simulate") {
+        let code = match command_line_specs(&matches) {
+            Ok(specs) => simulate_command(trace, &specs, *matches.get_one("iops-tolerance").unwrap()),
+            Err(e) => {
+                println!("{}", e);
+                1
+            }
+        };
+        return code;
+    }
+    if let Some(("ctl", sub_matches)) = matches.subcommand() {
+        let command: Vec<&str> = sub_matches.get_many::<String>("COMMAND").unwrap().map(|s| s.as_str()).collect();
+        return ctl_command(sub_matches.get_one::<String>("socket").unwrap(), &command.join(" "));
+    }
+
+    let check_interval: u64 = *matches.get_one("check-timeout").unwrap();
+    let allow_short_interval: bool = matches.get_flag("i-know-what-im-doing");
+    if check_interval < MIN_CHECK_INTERVAL && !allow_short_interval {
+        println!("check interval of {}s is below the minimum of {}s, see --i-know-what-im-doing. exiting...",
+                 check_interval, MIN_CHECK_INTERVAL);
+        return 1;
+    }
+
+    let credentials: Option<Credentials> = match matches.get_one::<String>("user") {
+        Some(user) => match Credentials::lookup(user, matches.get_one::<String>("group").map(|group| group.as_str())) {
+            Ok(credentials) => Some(credentials),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    if let Some(credentials) = &credentials {
+        for path in ["pidfile", "state-file", "status-file"].iter().filter_map(|id| matches.get_one::<String>(id)) {
+            if let Err(e) = credentials.check_writable_dir(path) {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        }
+    }
+
+    // removed when run returns, on fatal errors as well as on the shutdown on SIGTERM and SIGINT
+    let _pidfile: Option<PidFile> = match matches.get_one::<String>("pidfile") {
+        Some(path) => match PidFile::create(path) {
+            Ok(pidfile) => Some(pidfile),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+
+    let defaults = DeviceDefaults {
+        power_target: *matches.get_one("power-target").unwrap(),
+        check_interval,
+        apm_level: matches.get_one::<u8>("set-apm").copied(),
+        disable_standby_timer: matches.get_flag("disable-firmware-standby"),
+        monitor_unsupported: matches.get_flag("monitor-unsupported"),
+        standby_retries: *matches.get_one("standby-retries").unwrap(),
+        rated_load_cycles: *matches.get_one("rated-load-cycles").unwrap(),
+        iops_tolerance: *matches.get_one("iops-tolerance").unwrap(),
+        root_filesystem: if matches.get_flag("allow-root-disk") { None } else { get_root_disks(&paths) },
+    };
+    if let Some((source, disks)) = &defaults.root_filesystem {
+        log::debug!("root filesystem {} is on {}", source, disks.join(", "));
+    }
+    let hotplug: bool = matches.get_flag("hotplug");
+    let standby_on_exit: bool = matches.get_flag("standby-on-exit");
     let mut devices: Vec<Box<DeviceInfo>> = vec![];
-    for item in matches.get_many::<String>("DEVICE:TIMEOUT").unwrap() {
-        let (device_name, device_timeout_str) = item.split_once(':').unwrap();
-        let device_timeout: u64 = device_timeout_str.parse().unwrap();
+    // specs of the watched devices by their kernel name and of the ones not present (yet)
+    let mut device_specs: HashMap<String, DeviceSpec> = HashMap::new();
+    let mut pending_specs: Vec<DeviceSpec> = vec![];
+    let mut failed_devices: Vec<String> = vec![];
+    let probes = Probes::new(matches.get_one::<PowerSource>("power-source").unwrap().backend());
+    let mut state_file: Option<StateFile> = matches.get_one::<String>("state-file").map(|path| StateFile::new(path));
+    if let Some(state_file) = state_file.as_mut() {
+        if let Err(e) = state_file.load() {
+            println!("{}, starting without the saved state", e);
+        }
+    }
+    let mut specs: Vec<DeviceSpec> = match command_line_specs(&matches) {
+        Ok(specs) => specs,
+        Err(e) => {
+            println!("{}. exiting...", e);
+            return 1;
+        }
+    };
+    // patterns are expanded against /sys/block, devices given by name take precedence;
+    // with --hotplug, devices appearing later are matched, too
+    let block_devices = list_block_devices(&paths).unwrap_or_default();
+    let pattern_specs: Vec<DeviceSpec> = specs.iter().filter(|spec| spec.is_pattern()).cloned().collect();
+    specs.retain(|spec| !spec.is_pattern());
+    for pattern in pattern_specs.iter() {
+        let expanded = pattern.expand(&block_devices);
+        if expanded.is_empty() && !hotplug {
+            println!("pattern {} matches no device. exiting...", pattern.name);
+            return 1;
+        }
+        for spec in expanded {
+            if !specs.iter().any(|other| other.name == spec.name) {
+                specs.push(spec);
+            }
+        }
+    }
+    let allow_sleep: bool = matches.get_flag("allow-sleep");
+    for spec in specs.iter() {
+        if let Err(e) = validate_spec(spec, &defaults, allow_sleep, allow_short_interval) {
+            println!("{}", e);
+            return 1;
+        }
 
-        match get_device_info(&device_name.to_owned().to_string()) {
+        match init_device(spec, &paths, &probes, &defaults) {
             Ok(mut dev_info) => {
-                dev_info.timeout = device_timeout;
-                log::debug!("added {:?}", dev_info);
+                if let Some(state_file) = &state_file {
+                    state_file.restore(&mut dev_info, probes.clock.now());
+                }
+                device_specs.insert(dev_info.name.clone(), spec.clone());
                 devices.push(Box::new(dev_info));
             }
-            Err(e) => println!("unable to get device information for {}: {}", e.filepath, e.message)
+            Err(e) if e.kind == DeviceErrorKind::Refused => {
+                println!("refusing to manage {}: {}", e.filepath, e.message);
+                failed_devices.push(format!("{}: {}", spec.name, e.message));
+            }
+            // waiting for the device would not help
+            Err(e) if e.kind == DeviceErrorKind::PermissionDenied => {
+                println!("{}", e.message);
+                failed_devices.push(format!("{}: {}", spec.name, e.message));
+            }
+            Err(e) => {
+                println!("unable to get device information for {}: {}", e.filepath, e.message);
+                failed_devices.push(format!("{}: {}", spec.name, e.message));
+                pending_specs.push(spec.clone());
+            }
         }
     }
 
-    if devices.len() < 1 {
-        println!("no devices to watch. exiting...");
-        return;
+    if matches.get_flag("require-devices") && !failed_devices.is_empty() {
+        println!("some devices failed to initialize ({}). exiting...", failed_devices.join("; "));
+        return 1;
+    }
+    if devices.is_empty() && hotplug {
+        println!("no devices available yet, waiting for them to appear");
+    } else if devices.is_empty() {
+        println!("all devices failed to initialize ({}). exiting...", failed_devices.join("; "));
+        return 1;
     }
 
-    let check_interval: u64 = *matches.get_one("check-timeout").unwrap();
-    let iops_tolerance: u64 = *matches.get_one("iops-tolerance").unwrap();
-    log::debug!("iops_tolerance: {:?}", iops_tolerance);
+    let reconcile_interval = Duration::from_secs(*matches.get_one::<u64>("reconcile-interval").unwrap());
+    log::debug!("iops_tolerance: {:?}", defaults.iops_tolerance);
 
     let suspend: bool = matches.get_flag("suspend");
-    let suspend_timeout: u64 = *matches.get_one("suspend-timeout").unwrap();
-    let suspend_check_script: Option<&String> = matches.get_one::<String>("suspend-check-script");
+    let suspend_gate: Option<SuspendGate> = suspend.then(|| SuspendGate::new(
+        Duration::from_secs(*matches.get_one::<u64>("suspend-timeout").unwrap()),
+        Duration::from_secs(*matches.get_one::<u64>("suspend-cooldown").unwrap()),
+        Duration::from_secs(*matches.get_one::<u64>("min-awake-after-resume").unwrap())));
+    let suspend_ignore_watch_only: bool = matches.get_flag("suspend-ignore-watch-only");
+    let suspend_checks = SuspendChecks::new(
+        matches.get_many::<String>("suspend-check-script").unwrap_or_default().cloned().collect(),
+        matches.get_one::<String>("suspend-check-shell").cloned(),
+        Duration::from_secs(*matches.get_one::<u64>("suspend-check-timeout").unwrap()),
+        *matches.get_one::<CheckLogic>("suspend-check-logic").unwrap());
+    suspend_checks.validate();
+    let suspend_command: Option<&String> = matches.get_one::<String>("suspend-command");
+    let suspend_mode: SuspendMode = *matches.get_one("suspend-mode").unwrap();
 
-    loop {
-        log::debug!("sleeping for {} seconds", check_interval);
-        thread::sleep(Duration::from_secs(check_interval));
-
-        let mut disks_running: bool = false;
-        let mut latest_update: SystemTime = SystemTime::UNIX_EPOCH;
-
-        for cache in devices.iter_mut() {
-            match get_device_info(&cache.name) {
-                Ok(current) => {
-                    log::debug!("cache {:?}", cache);
-                    log::debug!("current {:?}", current);
-
-                    cache.power_state = current.power_state;
-                    let mut no_iops = false;
-
-                    if cache.last_read_iops == current.last_read_iops &&
-                        cache.last_write_iops == current.last_write_iops {
-                        no_iops = true;
-                        log::debug!("device {:?} did not change", current.name)
-                    } else {
-                        if (cache.last_read_iops + iops_tolerance) >= current.last_read_iops &&
-                            (cache.last_write_iops + iops_tolerance) >= current.last_write_iops {
-                            no_iops = true;
-                            log::debug!("device {:?} is within tolerance", current.name)
-                        }
+    #[cfg(feature = "mqtt")]
+    let mqtt: Option<MqttPublisher> = match matches.get_one::<String>("mqtt-broker") {
+        Some(broker) => {
+            let password = match matches.get_one::<String>("mqtt-password-file") {
+                Some(path) => match std::fs::read_to_string(path) {
+                    Ok(password) => Some(password.trim_end_matches(&['\r', '\n'][..]).to_string()),
+                    Err(e) => {
+                        println!("unable to read mqtt password file {}: {}. exiting...", path, e);
+                        return 1;
+                    }
+                },
+                None => None,
+            };
+            let config = MqttConfig {
+                broker: broker.clone(),
+                topic_prefix: matches.get_one::<String>("mqtt-topic-prefix").unwrap().clone(),
+                username: matches.get_one::<String>("mqtt-username").cloned(),
+                password,
+                discovery: matches.get_flag("mqtt-discovery"),
+            };
+            match MqttPublisher::connect(&config) {
+                Ok(publisher) => Some(publisher),
+                Err(e) => {
+                    println!("unable to set up mqtt: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "mqtt"))]
+    if matches.contains_id("mqtt-broker") {
+        println!("--mqtt-broker requires building with the mqtt feature. exiting...");
+        return 1;
+    }
+
+    let webhook: Option<Webhook> = match matches.get_one::<String>("webhook-url") {
+        Some(url) => {
+            let events: Vec<EventKind> = matches.get_many::<EventKind>("webhook-events").unwrap()
+                .copied()
+                .collect();
+            match Webhook::start(url, events) {
+                Ok(webhook) => Some(webhook),
+                Err(e) => {
+                    println!("unable to set up webhook: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let hooks = Hooks::new(
+        HookCommands {
+            standby: matches.get_one::<String>("on-standby").cloned(),
+            wake: matches.get_one::<String>("on-wake").cloned(),
+            error: matches.get_one::<String>("on-error").cloned(),
+        },
+        Duration::from_secs(*matches.get_one::<u64>("hook-timeout").unwrap()),
+    );
+    let event_log: Option<EventLog> = match matches.get_one::<String>("event-log") {
+        Some(path) => match EventLog::open(path) {
+            Ok(event_log) => Some(event_log),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    let mut recorder: Option<Recorder> = match matches.get_one::<String>("record") {
+        Some(path) => match Recorder::open(path) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    // the events as entries of their own with SPINDOWN_DEVICE and SPINDOWN_EVENT fields
+    let journal: Option<Journal> = match log_backend {
+        LogBackend::Journald => match Journal::connect() {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        LogBackend::Stderr => None,
+    };
+    let metrics: Option<Metrics> = match matches.get_one::<String>("metrics-listen") {
+        Some(address) => match Metrics::listen(address, probes.clock.clone()) {
+            Ok(metrics) => Some(metrics),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    let control: Option<ControlServer> = match matches.get_one::<String>("control-socket") {
+        Some(path) => match ControlServer::listen(path) {
+            Ok(control) => Some(control),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        },
+        None => None,
+    };
+    #[cfg(feature = "dbus")]
+    let dbus: Option<DbusService> = if matches.get_flag("dbus") {
+        match DbusService::connect() {
+            Ok(dbus) => Some(dbus),
+            Err(e) => {
+                println!("unable to set up D-Bus: {}. exiting...", e);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(not(feature = "dbus"))]
+    if matches.get_flag("dbus") {
+        println!("--dbus requires building with the dbus feature. exiting...");
+        return 1;
+    }
+    let notifiers = Notifiers {
+        webhook,
+        hooks,
+        event_log,
+        journal,
+        #[cfg(feature = "mqtt")]
+        mqtt,
+    };
+    for dev in devices.iter() {
+        notifiers.device_added(dev, probes.clock.now());
+    }
+    notifiers.daemon_event("startup");
+
+    if let Err(e) = signals::install_handlers() {
+        println!("unable to install signal handlers: {}", e);
+    }
+    let started = Instant::now();
+    let status_file: Option<StatusFile> = matches.get_one::<String>("status-file").map(|path| StatusFile::new(path));
+    let max_failed_checks: u64 = *matches.get_one("max-failed-checks").unwrap();
+    let mut next_load_cycle_check: Option<Instant> = matches.get_flag("daily-load-cycle-check")
+        .then(|| started + LOAD_CYCLE_CHECK_INTERVAL);
+    let mut exit_code: i32 = 0;
+    // monotonic, so the time the system is suspended does not count
+    let max_runtime: Option<Duration> = matches.get_one::<u64>("max-runtime")
+        .map(|secs| Duration::from_secs(*secs));
+
+    let hotplug_monitor: Option<HotplugMonitor> = if hotplug {
+        match HotplugMonitor::start() {
+            Ok(monitor) => Some(monitor),
+            Err(e) => {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
 
-                        cache.last_read_iops = current.last_read_iops;
-                        cache.last_write_iops = current.last_write_iops;
+    let check_deadline = Duration::from_secs(*matches.get_one::<u64>("check-deadline").unwrap());
+    let inhibit_file: Option<&String> = matches.get_one::<String>("inhibit-file");
+    let context = RoundContext {
+        reconcile_interval,
+        check_deadline,
+        suspend_ignore_watch_only,
+        group_timeout: matches.get_one::<u64>("group-timeout").copied(),
+        dirty_threshold: matches.get_one::<u64>("dirty-threshold").copied(),
+        idle_poll_interval: matches.get_one::<u64>("idle-poll-interval").map(|secs| Duration::from_secs(*secs)),
+        paths: &paths,
+        probes: &probes,
+        notifiers: &notifiers,
+        inhibited: false,
+    };
+    let stagger: bool = matches.get_flag("stagger");
+    // the checks are spread again whenever devices were added or removed
+    let mut staggered_devices: Vec<String> = vec![];
+    // everything needing root is set up, the device threads only need CAP_SYS_RAWIO and
+    // inherit it from here
+    if let Some(credentials) = &credentials {
+        // the files created so far are reopened on SIGHUP or removed on exit as the user
+        for path in ["pidfile", "event-log", "record"].iter().filter_map(|id| matches.get_one::<String>(id)) {
+            if let Err(e) = credentials.chown(path) {
+                println!("{}. exiting...", e);
+                return 1;
+            }
+        }
+        if let Err(e) = drop_privileges(credentials) {
+            println!("{}. exiting...", e);
+            return 1;
+        }
+        log::info!("running as {} with only CAP_SYS_RAWIO", credentials.user);
+    }
+    let mut workers: HashMap<String, DeviceWorker> = HashMap::new();
+    for dev in devices.iter() {
+        match DeviceWorker::spawn(&dev.name, &paths, probes.clone(), dev.standby_method,
+                                  RetryPolicy::new(defaults.standby_retries)) {
+            Ok(worker) => {
+                workers.insert(dev.name.clone(), worker);
+            }
+            Err(e) => {
+                println!("unable to start check thread for {}: {}", dev.name, e);
+                return 1;
+            }
+        }
+    }
+
+    let mut daemon = Daemon::new(devices, workers, context, suspend_gate);
+    let mut suspend_count: u64 = 0;
+    let mut resume_detector = ResumeDetector::start();
+    if let Some(metrics) = &metrics {
+        metrics.update(&daemon.devices, suspend_count);
+    }
+    #[cfg(feature = "dbus")]
+    if let Some(dbus) = &dbus {
+        dbus.update(&daemon.devices);
+    }
 
-                        if !no_iops {
-                            cache.last_update = current.last_update;
+    loop {
+        if stagger && !daemon.devices.iter().map(|dev| &dev.name).eq(staggered_devices.iter()) {
+            stagger_checks(&mut daemon.devices, probes.clock.now());
+            staggered_devices = daemon.devices.iter().map(|dev| dev.name.clone()).collect();
+        }
+        let mut sleep_duration = daemon.next_tick_delay();
+        if let Some(max_runtime) = max_runtime {
+            sleep_duration = sleep_duration.min(max_runtime.saturating_sub(started.elapsed()));
+        }
+        log::debug!("sleeping for {:.3} seconds", sleep_duration.as_secs_f64());
+        let wake_up = Instant::now() + sleep_duration;
+        let mut forced = false;
+        // by the PrepareForSleep signal of logind
+        #[cfg_attr(not(feature = "dbus"), allow(unused_mut))]
+        let mut resumed = false;
+        let suspend_status = SuspendStatus {
+            enabled: suspend,
+            disks_running: daemon.disks_running,
+            latest_update: daemon.latest_update,
+            inhibited: daemon.context.inhibited,
+        };
+        loop {
+            signals::sleep(wake_up.saturating_duration_since(Instant::now()));
+            if signals::take_status_request() {
+                for line in status::render(&daemon.devices, &suspend_status, probes.clock.now()) {
+                    println!("{}", line);
+                }
+            }
+            #[cfg(feature = "dbus")]
+            if let Some(dbus) = &dbus {
+                if handle_sleep_events(dbus, &daemon.devices, &paths) {
+                    resumed = true;
+                    break;
+                }
+            }
+            // an added device is due right away, so the sleep is planned again
+            let mut added = false;
+            #[cfg_attr(not(feature = "dbus"), allow(unused_mut))]
+            let mut requests: Vec<ControlRequest> = control.iter().flat_map(|control| control.requests()).collect();
+            #[cfg(feature = "dbus")]
+            requests.extend(dbus.iter().flat_map(|dbus| dbus.requests()));
+            for request in requests {
+                let result = match &request.command {
+                    ControlCommand::Status => Ok(status::render(&daemon.devices, &suspend_status, probes.clock.now())),
+                    ControlCommand::Standby(name) => match device_index(name, &daemon.devices, &paths) {
+                        None => Err(format!("{} is not watched", name)),
+                        Some(_) if daemon.context.inhibited => Err(format!("inhibited by {}", inhibit_file.unwrap())),
+                        Some(index) => {
+                            let dev = &mut daemon.devices[index];
+                            match daemon.workers.get(&dev.name) {
+                                Some(worker) => standby_device(dev, worker, daemon.context.probes.clock.now())
+                                    .map(|()| vec![format!("issuing {} for {}", dev.power_target, dev.name)]),
+                                None => Err(format!("{} has no check thread", dev.name)),
+                            }
                         }
+                    },
+                    ControlCommand::Add(spec) => {
+                        let new_device = validate_spec(spec, &defaults, allow_sleep, allow_short_interval)
+                            .and_then(|()| match device_index(&spec.name, &daemon.devices, &paths) {
+                                Some(_) => Err(format!("{} is already watched", spec.name)),
+                                None => start_device(spec, &paths, &probes, &defaults, state_file.as_ref())
+                                    .map_err(|e| format!("unable to add {}: {}", e.filepath, e.message)),
+                            });
+                        new_device.map(|(dev_info, worker)| {
+                            println!("{} added, watching it", dev_info.name);
+                            notifiers.device_added(&dev_info, probes.clock.now());
+                            added = true;
+                            pending_specs.retain(|pending| pending.name != spec.name);
+                            daemon.workers.insert(dev_info.name.clone(), worker);
+                            device_specs.insert(dev_info.name.clone(), spec.clone());
+                            daemon.devices.push(Box::new(dev_info));
+                            vec![]
+                        })
                     }
-
-                    if no_iops &&
-                        cache.last_update.elapsed().unwrap().as_secs() > cache.timeout &&
-                        cache.power_state != PowerState::Standby {
-                        log::debug!("issuing standby for {}", cache.name);
-                        match do_standby(&cache.name) {
-                            Ok(()) => println!("issued standby for {}", cache.name),
-                            Err(e) => println!("unable to issue standby for {}: {}",
-                                               e.filepath, e.message)
+                    ControlCommand::Remove(name) => match device_index(name, &daemon.devices, &paths) {
+                        None => Err(format!("{} is not watched", name)),
+                        Some(index) => {
+                            let dev = daemon.devices.remove(index);
+                            println!("{} removed, no longer watching it", dev.name);
+                            if let Some(worker) = daemon.workers.remove(&dev.name) {
+                                worker.shutdown(Instant::now());
+                            }
+                            device_specs.remove(&dev.name);
+                            Ok(vec![])
                         }
-                        cache.last_update = current.last_update;
+                    },
+                    ControlCommand::Reset => {
+                        let now = probes.clock.now();
+                        for dev in daemon.devices.iter_mut() {
+                            dev.reset_counters(now);
+                        }
+                        println!("reset standby and wake counters and failed checks of all devices");
+                        Ok(vec![])
                     }
+                };
+                request.reply(result);
+            }
+            if added {
+                break;
+            }
+            if signals::take_reopen_request() {
+                if let Some(event_log) = &notifiers.event_log {
+                    event_log.reopen();
+                }
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.reopen();
+                }
+            }
+            if signals::take_force_request() {
+                println!("received SIGUSR2, putting all idle devices to sleep...");
+                forced = true;
+                break;
+            }
+            if signals::shutdown_requested() || Instant::now() >= wake_up {
+                break;
+            }
+        }
 
-                    if cache.power_state != PowerState::Standby {
-                        disks_running = true;
+        if signals::shutdown_requested() {
+            if signals::interrupted() {
+                println!("interrupted, exiting...");
+            } else {
+                println!("received SIGTERM, exiting...");
+            }
+            if standby_on_exit {
+                standby_all(&daemon.devices, &paths);
+            }
+            break;
+        }
+        if max_runtime.is_some_and(|max_runtime| started.elapsed() >= max_runtime) {
+            println!("max runtime reached, exiting...");
+            if standby_on_exit {
+                standby_all(&daemon.devices, &paths);
+            }
+            break;
+        }
+        // logind also reports a sleep too short for the clocks to tell
+        let asleep = resume_detector.check();
+        if asleep.is_some() || resumed {
+            let asleep = asleep.map(|asleep| format!(" ({} asleep)", format_duration(asleep.as_secs()))).unwrap_or_default();
+            println!("system resumed, resetting timers{}", asleep);
+            daemon.resumed();
+            if stagger {
+                stagger_checks(&mut daemon.devices, probes.clock.now());
+            }
+        }
+
+        if let Some(monitor) = &hotplug_monitor {
+            let mut added = false;
+            for event in monitor.events() {
+                match event {
+                    HotplugEvent::Added(name) => {
+                        added = true;
+                        let pattern = pattern_specs.iter().find(|pattern| pattern.matches(&name));
+                        let known = device_specs.contains_key(&name) || pending_specs.iter().any(|spec| spec.name == name);
+                        if let (Some(pattern), false) = (pattern, known) {
+                            pending_specs.push(DeviceSpec { name, ..pattern.clone() });
+                        }
                     }
-                    if cache.last_update > latest_update {
-                        latest_update = cache.last_update;
+                    HotplugEvent::Removed(name) => {
+                        let index = match daemon.devices.iter().position(|dev| dev.name == name) {
+                            Some(index) => index,
+                            None => continue,
+                        };
+                        println!("{} was removed, waiting for it to reappear", name);
+                        daemon.devices.remove(index);
+                        if let Some(worker) = daemon.workers.remove(&name) {
+                            worker.shutdown(Instant::now());
+                        }
+                        if let Some(spec) = device_specs.remove(&name) {
+                            pending_specs.push(spec);
+                        }
                     }
-
-                    log::debug!("updated cache {:?}", cache);
                 }
-                Err(e) => println!("unable to get device information for {}: {}", e.filepath, e.message)
+            }
+            // device-mapper names are only known after the table was loaded (change event),
+            // so all pending devices are tried again on any event
+            if added {
+                let mut still_pending: Vec<DeviceSpec> = vec![];
+                for spec in pending_specs.drain(..) {
+                    let (dev_info, worker) = match start_device(&spec, &paths, &probes, &defaults, state_file.as_ref()) {
+                        Ok(started) => started,
+                        Err(e) => {
+                            log::debug!("{} is not available: {}", spec.name, e.message);
+                            still_pending.push(spec);
+                            continue;
+                        }
+                    };
+                    println!("{} appeared, watching it", dev_info.name);
+                    notifiers.device_added(&dev_info, probes.clock.now());
+                    daemon.workers.insert(dev_info.name.clone(), worker);
+                    device_specs.insert(dev_info.name.clone(), spec);
+                    daemon.devices.push(Box::new(dev_info));
+                }
+                pending_specs = still_pending;
             }
         }
 
-        if suspend {
-            log::debug!("checking system suspend");
-            if disks_running {
-                log::debug!("disk(s) still running");
-                continue;
+        // checked every round, so touching or removing the file takes effect with the next check
+        let inhibited = inhibit_file.is_some_and(|path| Path::new(path).exists());
+        if inhibited != daemon.context.inhibited {
+            if inhibited {
+                println!("{} exists, not putting devices to sleep or suspending until it is removed",
+                         inhibit_file.unwrap());
+            } else {
+                println!("{} was removed, resuming", inhibit_file.unwrap());
             }
+            daemon.context.inhibited = inhibited;
+        }
+        if forced && inhibited {
+            println!("inhibited by {}, not putting devices to sleep", inhibit_file.unwrap());
+        }
 
-            if latest_update.elapsed().unwrap().as_secs() < suspend_timeout {
-                log::debug!("suspend timeout not met");
-                continue;
+        let actions = daemon.tick(forced);
+        let now = daemon.now();
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record(&daemon.devices);
+        }
+        if let Some(metrics) = &metrics {
+            metrics.update(&daemon.devices, suspend_count);
+        }
+        #[cfg(feature = "dbus")]
+        if let Some(dbus) = &dbus {
+            dbus.update(&daemon.devices);
+        }
+
+        if let Some(next) = next_load_cycle_check.filter(|next| Instant::now() >= *next) {
+            // a device due for standby may have been put to sleep by this round already
+            for dev in daemon.devices.iter_mut().filter(|dev| dev.standby_method != StandbyMethod::None &&
+                dev.power_state != PowerState::Standby &&
+                (dev.timeout == 0 || dev.idle_seconds(now) < dev.timeout)) {
+                read_load_cycles(dev, &paths, probes.power.as_ref());
             }
+            next_load_cycle_check = Some(next + LOAD_CYCLE_CHECK_INTERVAL);
+        }
+        if heartbeat {
+            log::info!("{}", summarize_devices(&daemon.devices, now));
+        }
+        if let Some(status_file) = &status_file {
+            if let Err(e) = status_file.write(&daemon.devices, &summarize_devices(&daemon.devices, now), now) {
+                println!("{}", e);
+            }
+        }
+        // devices waiting to (re)appear with --hotplug leave an empty list, which is no failure
+        if max_failed_checks > 0 && !daemon.devices.is_empty() &&
+            daemon.devices.iter().all(|dev| dev.failed_checks >= max_failed_checks) {
+            println!("every device failed its last {} checks. exiting...", max_failed_checks);
+            exit_code = DEVICES_UNUSABLE_EXIT;
+            break;
+        }
+        if let Some(state_file) = state_file.as_mut() {
+            if let Err(e) = state_file.save_if_changed(&daemon.devices, now) {
+                println!("{}", e);
+            }
+        }
 
-            match suspend_check_script {
-                Some(script) => {
-                    log::debug!("executing check script");
-                    let cmd = ProcessCommand::new("bash")
-                        .arg(script)
-                        .output()
-                        .expect("failed to execute process");
-                    if cmd.status.code().unwrap() != 0 {
-                        log::debug!("script exited with non zero code ({})", cmd.status.code().unwrap());
-                        continue;
+        // the check scripts run last, once nothing else keeps the system awake
+        if actions.contains(&Action::Suspend) && suspend_checks.allow_suspend() {
+            log::debug!("suspending system...");
+            match system_suspend_command(suspend_command, suspend_mode).output() {
+                Ok(output) if output.status.success() => {
+                    log::debug!("system suspended");
+                    suspend_count += 1;
+                    if let Some(metrics) = &metrics {
+                        metrics.update(&daemon.devices, suspend_count);
                     }
+                    notifiers.daemon_event("suspend");
                 }
-                None => {}
+                Ok(output) => println!("unable to suspend system ({}): {}",
+                                       output.status, String::from_utf8_lossy(&output.stderr).trim()),
+                Err(e) => println!("unable to run suspend command {}: {}", suspend_command.cloned()
+                    .unwrap_or_else(|| format!("{} {}", SYSTEMCTL, suspend_mode)), e)
             }
+        }
+    }
 
-            log::debug!("suspending system...");
-            ProcessCommand::new("/usr/bin/systemctl")
-                .arg("suspend")
-                .output()
-                .expect("failed to execute process");
+    let now = daemon.now();
+    for dev in daemon.devices.iter() {
+        println!("{}", status::render_summary(dev, now));
+    }
+    if let Some(state_file) = state_file.as_mut() {
+        if let Err(e) = state_file.save(&daemon.devices, now) {
+            println!("{}", e);
+        }
+    }
+    notifiers.daemon_event("shutdown");
+
+    // running commands may finish, but a device hanging in SG_IO does not delay the exit
+    let deadline = Instant::now() + check_deadline;
+    for (name, worker) in daemon.workers.drain() {
+        if !worker.shutdown(deadline) {
+            println!("check thread of {} is still busy, exiting anyway", name);
+        }
+    }
+    exit_code
+}
+
+fn power_state_command(device: &str, paths: &Paths) -> i32 {
+    let device_name = match device.strip_prefix("/dev/mapper/") {
+        Some(mapper_name) => match resolve_mapper_name(mapper_name, paths) {
+            Ok(dm_name) => dm_name,
+            Err(e) => {
+                println!("unable to get device information for {}: {}", e.filepath, e.message);
+                return POWER_STATE_ERROR;
+            }
+        },
+        None => device.trim_start_matches("/dev/").to_string(),
+    };
+    match get_power_state(&device_name, paths, &AtaBackend) {
+        Ok(power_state) => {
+            println!("{}", power_state);
+            power_state.code() as i32
+        }
+        Err(e) => {
+            println!("unable to get power state for {}: {}", e.filepath, e.message);
+            POWER_STATE_ERROR
+        }
+    }
+}
+
+// The devices given as arguments followed by the ones of --device-file
+fn command_line_specs(matches: &clap::ArgMatches) -> Result<Vec<DeviceSpec>, String> {
+    let mut specs: Vec<DeviceSpec> = matches.get_many::<DeviceSpec>("DEVICE:TIMEOUT")
+        .map(|specs| specs.cloned().collect())
+        .unwrap_or_default();
+    if let Some(path) = matches.get_one::<String>("device-file") {
+        specs.extend(read_device_file(path)?);
+    }
+    Ok(specs)
+}
+
+fn simulate_command(trace: &str, specs: &[DeviceSpec], tolerance: u64) -> i32 {
+    let samples = match read_trace(trace) {
+        Ok(samples) => samples,
+        Err(e) => {
+            println!("{}", e);
+            return 1;
+        }
+    };
+    let lines = simulate(&samples, specs, tolerance);
+    if lines.is_empty() {
+        println!("no samples of the given devices in {}", trace);
+        return 1;
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+    0
+}
+
+fn list_devices_command(paths: &Paths) -> i32 {
+    let devices = match list_block_devices(paths) {
+        Ok(devices) => devices,
+        Err(e) => {
+            println!("unable to list devices in {}: {}", e.filepath, e.message);
+            return 1;
+        }
+    };
+    for dev in devices {
+        let rotational = match is_rotational(&dev, paths) {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "-",
+        };
+        // the power check doubles as test of the ATA pass-through
+        let (power_state, passthrough) = match get_power_state(&dev, paths, &AtaBackend) {
+            Ok(power_state) => (power_state.to_string(), "yes".to_string()),
+            Err(e) => ("-".to_string(), format!("no ({})", e.message)),
+        };
+        println!("{} rotational={} power_state={} model={:?} serial={:?} passthrough={}",
+                 dev, rotational, power_state,
+                 get_device_model(&dev, paths).unwrap_or_default(),
+                 get_device_serial(&dev, paths).unwrap_or_default(),
+                 passthrough);
+    }
+    0
+}
+
+// The steps of `self-test` and `test`, which talk to the disk directly with the ATA functions
+// of the daemon, without the fallbacks of get_power_state. Each leaves the sense data of its
+// last command in `sense`.
+fn probe_power_mode(dev: &str, paths: &Paths, sense: &mut Vec<u8>) -> Result<PowerState, String> {
+    match check_power_state_with_sense(dev, paths, sense) {
+        Ok(PowerState::Unknown) => Err("no known power mode returned".to_string()),
+        Ok(power_state) => Ok(power_state),
+        Err(e) => Err(format!("{}: {}", e.filepath, e.message)),
+    }
+}
+
+fn probe_standby(dev: &str, paths: &Paths, sense: &mut Vec<u8>) -> Result<AtaResponse, String> {
+    match do_standby_with_sense(dev, paths, sense) {
+        Ok(response) if response.is_error() => Err(format!("aborted by {} ({})", dev, response)),
+        Ok(response) => Ok(response),
+        Err(e) => Err(format!("{}: {}", e.filepath, e.message)),
+    }
+}
+
+// the disk has to report standby once it had the time to spin down
+fn probe_parked(dev: &str, paths: &Paths, sense: &mut Vec<u8>) -> Result<(), String> {
+    std::thread::sleep(SELF_TEST_SETTLE);
+    match probe_power_mode(dev, paths, sense)? {
+        PowerState::Standby => Ok(()),
+        power_state => Err(format!("still {} after STANDBY IMMEDIATE", power_state)),
+    }
+}
+
+// Shows the sense data of every failure
+fn self_test_command(device: &str, yes: bool, paths: &Paths) -> i32 {
+    let dev = device.trim_start_matches("/dev/");
+    let failed = |message: String, sense: &[u8]| {
+        println!("FAIL: {}", message);
+        println!("sense: {}", hex_dump(sense));
+        println!("decoded: {}", describe_sense(sense));
+        1
+    };
+
+    let mut sense = sense_buffer();
+    match probe_power_mode(dev, paths, &mut sense) {
+        Ok(power_state) => println!("CHECK POWER MODE: {} ({})", power_state, describe_sense(&sense)),
+        Err(e) => return failed(format!("CHECK POWER MODE: {}", e), &sense),
+    }
+
+    if !yes {
+        print!("put {} into standby to test the power commands? [y/N] ", dev);
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+            println!("PASS: the power state of {} can be checked (standby not tested, see --yes)", dev);
+            return 0;
+        }
+    }
+
+    let mut sense = sense_buffer();
+    match probe_standby(dev, paths, &mut sense) {
+        Ok(response) => println!("STANDBY IMMEDIATE: {}", response),
+        Err(e) => return failed(format!("STANDBY IMMEDIATE: {}", e), &sense),
+    }
+
+    let mut sense = sense_buffer();
+    match probe_parked(dev, paths, &mut sense) {
+        Ok(()) => {
+            println!("CHECK POWER MODE: {} ({})", PowerState::Standby, describe_sense(&sense));
+            println!("PASS: {} can be checked and put to sleep", dev);
+            0
+        }
+        Err(e) => failed(format!("CHECK POWER MODE after standby: {}", e), &sense),
+    }
+}
+
+fn test_command(device: &str, park: bool, paths: &Paths) -> i32 {
+    let dev = device.trim_start_matches("/dev/");
+    let describe = |e: DeviceError| format!("{}: {}", e.filepath, e.message);
+    let mut failed = false;
+    let mut report = |step: &str, result: Result<String, String>| match result {
+        Ok(detail) => println!("PASS {}: {}", step, detail),
+        Err(e) => {
+            failed = true;
+            println!("{}", console::failure(format!("FAIL {}: {}", step, e)));
+        }
+    };
+
+    report("open", check_access(dev, paths).map(|_| paths.dev(dev)).map_err(describe));
+    report("stats", get_device_stats(dev, paths)
+        .map(|stats| format!("{} reads, {} writes from {}", stats.read_iops, stats.write_iops, stats.source))
+        .map_err(describe));
+    report("rotational", match is_rotational(dev, paths) {
+        Some(true) => Ok("yes".to_string()),
+        Some(false) => Err("no, there is nothing to spin down".to_string()),
+        None => Err(format!("unable to read {}", paths.sysfs(&format!("block/{}/queue/rotational", dev)))),
+    });
+    report("CHECK POWER MODE", probe_power_mode(dev, paths, &mut sense_buffer()).map(|power_state| power_state.to_string()));
+    report("IDENTIFY DEVICE", identify(dev, paths).map(|words| format!("model {}", identify_model(&words))).map_err(describe));
+    if park {
+        let mut sense = sense_buffer();
+        report("standby", probe_standby(dev, paths, &mut sense)
+            .and_then(|_| probe_parked(dev, paths, &mut sense))
+            .map(|()| "reports Standby after STANDBY IMMEDIATE".to_string()));
+    } else {
+        println!("SKIP standby: use --park to put the disk into standby");
+    }
+    if failed { 1 } else { 0 }
+}
+
+fn ctl_command(socket: &str, command: &str) -> i32 {
+    match send_command(socket, command) {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+            0
+        }
+        Err(e) => {
+            println!("{}", e);
+            1
+        }
+    }
+}
+
+// the sleep target needs a reset to wake up the disk, so it must be allowed explicitly
+fn validate_spec(spec: &DeviceSpec, defaults: &DeviceDefaults, allow_sleep: bool,
+                 allow_short_interval: bool) -> Result<(), String> {
+    if spec.power_target.unwrap_or(defaults.power_target) == PowerTarget::Sleep && !allow_sleep {
+        return Err(format!("power target sleep for {} requires --allow-sleep, as the disk needs a reset to wake up",
+                           spec.name));
+    }
+    if let Some(interval) = spec.check_interval.filter(|interval| *interval < MIN_CHECK_INTERVAL && !allow_short_interval) {
+        return Err(format!("check interval of {}s for {} is below the minimum of {}s, see --i-know-what-im-doing",
+                           interval, spec.name, MIN_CHECK_INTERVAL));
+    }
+    Ok(())
+}
+
+// Index of a watched device given by its kernel name, /dev/NAME or /dev/mapper/NAME
+fn device_index(name: &str, devices: &[Box<DeviceInfo>], paths: &Paths) -> Option<usize> {
+    let kernel_name = match name.strip_prefix("/dev/mapper/").or_else(|| name.strip_prefix("mapper/")) {
+        Some(mapper_name) => resolve_mapper_name(mapper_name, paths).ok()?,
+        None => name.trim_start_matches("/dev/").to_string(),
+    };
+    devices.iter().position(|dev| dev.name == kernel_name)
+}
+
+// Sets up a device appearing after startup (hotplug, ctl add) with its check thread
+fn start_device(spec: &DeviceSpec, paths: &Paths, probes: &Probes, defaults: &DeviceDefaults,
+                state_file: Option<&StateFile>) -> Result<(DeviceInfo, DeviceWorker), DeviceError> {
+    let mut dev_info = init_device(spec, paths, probes, defaults)?;
+    let worker = DeviceWorker::spawn(&dev_info.name, paths, probes.clone(), dev_info.standby_method,
+                                     RetryPolicy::new(defaults.standby_retries))
+        .map_err(|e| DeviceError::new(dev_info.name.clone(), format!("unable to start check thread: {}", e)))?;
+    if let Some(state_file) = state_file {
+        state_file.restore(&mut dev_info, probes.clock.now());
+    }
+    Ok((dev_info, worker))
+}
+
+fn init_device(spec: &DeviceSpec, paths: &Paths, probes: &Probes,
+               defaults: &DeviceDefaults) -> Result<DeviceInfo, DeviceError> {
+    let device_name = match spec.mapper_name() {
+        Some(mapper_name) => resolve_mapper_name(mapper_name, paths)?,
+        None => spec.name.clone(),
+    };
+    let mut dev_info = get_device_info(&device_name, paths, probes)?;
+    dev_info.timeout = spec.timeout;
+    if dev_info.standby_method == StandbyMethod::None {
+        let reason = dev_info.error.take().unwrap_or_default();
+        if !defaults.monitor_unsupported {
+            return Err(DeviceError::new(device_name, format!(
+                "no ATA pass-through ({}), so it cannot be spun down; use --monitor-unsupported to watch it anyway",
+                reason)));
+        }
+        println!("{} has no ATA pass-through ({}), only watching it", device_name, reason);
+        dev_info.timeout = 0;
+    }
+    if let Some((source, root_disks)) = &defaults.root_filesystem {
+        if dev_info.timeout > 0 && dev_info.members.iter().any(|disk| root_disks.contains(disk)) {
+            return Err(DeviceError {
+                filepath: device_name,
+                message: format!("it holds the root filesystem ({}), every access would block while it spins up; \
+                                  use --allow-root-disk to manage it anyway or a timeout of 0 to only watch it", source),
+                kind: DeviceErrorKind::Refused,
+            });
+        }
+    }
+    dev_info.power_target = spec.power_target.unwrap_or(defaults.power_target);
+    dev_info.check_interval = spec.check_interval.unwrap_or(defaults.check_interval);
+    dev_info.next_check = probes.clock.now() + Duration::from_secs(dev_info.check_interval);
+    dev_info.hooks = spec.hooks.clone();
+    dev_info.rated_load_cycles = spec.rated_load_cycles.unwrap_or(defaults.rated_load_cycles);
+    dev_info.iops_tolerance = spec.iops_tolerance.unwrap_or(defaults.iops_tolerance);
+    if dev_info.standby_method != StandbyMethod::None {
+        configure_firmware(&dev_info, paths, defaults.apm_level, defaults.disable_standby_timer);
+        check_firmware_standby(&dev_info, paths);
+        read_load_cycles(&mut dev_info, paths, probes.power.as_ref());
+    }
+    log::debug!("added {:?}", dev_info);
+    log::info!("reading stats of {} from {}", dev_info.name, dev_info.stat_source);
+    Ok(dev_info)
+}
+
+fn configure_firmware(dev: &DeviceInfo, paths: &Paths, apm_level: Option<u8>, disable_standby_timer: bool) {
+    for disk in dev.members.iter() {
+        if let Some(level) = apm_level {
+            match set_apm(disk, paths, level) {
+                Ok(response) => println!("set APM level {} for {} ({})", level, disk, response),
+                Err(e) => println!("unable to set APM level for {}: {}", e.filepath, e.message)
+            }
+        }
+        if disable_standby_timer {
+            match set_standby_timer(disk, paths, 0) {
+                Ok(response) => println!("disabled firmware standby timer for {} ({})", disk, response),
+                Err(e) => println!("unable to disable firmware standby timer for {}: {}", e.filepath, e.message)
+            }
+        }
+    }
+}
+
+// The current value of the standby timer cannot be read back, but an APM level
+// below 128 tells that the firmware spins down the disk on its own.
+fn check_firmware_standby(dev: &DeviceInfo, paths: &Paths) {
+    for disk in dev.members.iter() {
+        match get_apm_level(disk, paths) {
+            Ok(Some(level)) if level < 128 => println!(
+                "warning: {} firmware APM level {} allows spin-down by the firmware; \
+                consider --set-apm 254 and --disable-firmware-standby", disk, level),
+            Ok(level) => log::debug!("{} APM level: {:?}", disk, level),
+            Err(e) => log::debug!("unable to identify {}: {}", e.filepath, e.message)
+        }
+    }
+}
+
+// Reports the Load/Unload Cycle Count of the disks against their rating. Disks in standby
+// are skipped, as reading the SMART data would spin them up.
+fn read_load_cycles(dev: &mut DeviceInfo, paths: &Paths, backend: &dyn PowerBackend) {
+    for disk in dev.members.iter() {
+        match backend.power_state(disk, paths) {
+            Ok(PowerState::Standby) => {
+                log::debug!("{} is in standby, not reading its load cycles", disk);
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::debug!("unable to check power state of {}: {}", e.filepath, e.message);
+                continue;
+            }
+        }
+        let cycles = match smart_read_data(disk, paths).map(|data| load_cycle_count(&data)) {
+            Ok(Some(cycles)) => cycles,
+            Ok(None) => {
+                log::debug!("{} reports no load/unload cycle count", disk);
+                continue;
+            }
+            Err(e) => {
+                log::debug!("unable to read SMART data of {}: {}", e.filepath, e.message);
+                continue;
+            }
+        };
+        let percent = cycles * 100 / dev.rated_load_cycles;
+        if percent >= LOAD_CYCLE_WARN_PERCENT {
+            println!("warning: {} at {}/{} load cycles ({}% of its rating); a longer timeout spins it down less often",
+                     disk, format_count(cycles), format_count(dev.rated_load_cycles), percent);
+        } else {
+            println!("{} at {}/{} load cycles", disk, format_count(cycles), format_count(dev.rated_load_cycles));
+        }
+        // the disk of a stack closest to its rating
+        dev.load_cycles = Some(dev.load_cycles.map_or(cycles, |known| known.max(cycles)));
+    }
+}
+
+// Thousands as `k`, e.g. 180k
+fn format_count(count: u64) -> String {
+    if count >= 10_000 {
+        format!("{}k", count / 1000)
+    } else {
+        count.to_string()
+    }
+}
+
+// a custom command line runs through the shell, so it may redirect, e.g. into /sys/power/state
+fn system_suspend_command(command_line: Option<&String>, mode: SuspendMode) -> ProcessCommand {
+    match command_line {
+        Some(command_line) => {
+            let mut command = ProcessCommand::new("/bin/sh");
+            command.arg("-c").arg(command_line);
+            command
+        }
+        None => {
+            let mut command = ProcessCommand::new(SYSTEMCTL);
+            command.arg(mode.to_string());
+            command
+        }
+    }
+}
+
+fn standby_all(devices: &[Box<DeviceInfo>], paths: &Paths) {
+    let mut disks: Vec<&String> = vec![];
+    for dev in devices.iter().filter(|dev| dev.standby_method != StandbyMethod::None) {
+        for member in dev.members.iter() {
+            if !disks.contains(&member) && is_rotational(member, paths) != Some(false) {
+                disks.push(member);
+            }
+        }
+    }
+    for disk in disks {
+        match do_standby(disk, paths) {
+            Ok(()) => println!("{}", console::event(format!("issued standby for {}", disk))),
+            Err(e) => println!("{}", console::failure(format!("unable to issue standby for {}: {}", e.filepath, e.message))),
         }
     }
+}
+
+// Puts the disks to sleep before the system suspends. Returns whether it resumed, the
+// timers are reset by the main loop.
+#[cfg(feature = "dbus")]
+fn handle_sleep_events(dbus: &DbusService, devices: &[Box<DeviceInfo>], paths: &Paths) -> bool {
+    let mut resumed = false;
+    for event in dbus.sleep_events() {
+        match event {
+            SleepEvent::Suspending => {
+                println!("system is going to sleep, putting the disks to sleep...");
+                standby_all(devices, paths);
+                dbus.release_sleep_lock();
+            }
+            SleepEvent::Resumed => resumed = true,
+        }
+    }
+    resumed
+}
+
+fn summarize_devices(devices: &[Box<DeviceInfo>], now: Instant) -> String {
+    let standby = devices.iter()
+        .filter(|dev| dev.power_state == PowerState::Standby)
+        .count();
+    let active: Vec<String> = devices.iter()
+        .filter(|dev| dev.power_state != PowerState::Standby)
+        .map(|dev| {
+            let idle = dev.idle_seconds(now);
+            let activity = dev.activity().map(|activity| format!(", {}", activity)).unwrap_or_default();
+            if dev.timeout == 0 {
+                format!("{}, idle {}s{}, watch-only", dev.name, idle, activity)
+            } else {
+                format!("{}, idle {}s/{}s{}", dev.name, idle, dev.timeout, activity)
+            }
+        })
+        .collect();
+
+    let mut summary = format!("{} disks watched, {} standby, {} active",
+                              devices.len(), standby, active.len());
+    if !active.is_empty() {
+        summary.push_str(&format!(" ({})", active.join("; ")));
+    }
+    summary
 }
\ No newline at end of file
@@ -1,20 +1,45 @@
 use std::{fmt, io};
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use std::fs::OpenOptions;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{IntoRawFd, RawFd};
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use nix::libc::{c_int, c_uchar, c_uint, c_ulong, c_ushort, c_void, ioctl, O_NONBLOCK};
+use nix::libc::{c_int, c_uchar, c_uint, c_ushort, c_void, ioctl, Ioctl, O_NONBLOCK};
 use nix::unistd::close;
 
 use crate::spindown_daemon::errors::DeviceError;
+use crate::spindown_daemon::paths::Paths;
 
 const SAT_ATA_PASS_THROUGH16: u8 = 0x85;
 const ATA_CHECK_POWER_MODE: u8 = 0xE5;
 const ATA_OP_STANDBYNOW: u8 = 0xE0;
-const SG_IO: c_ulong = 0x2285;
-const SENSE_LEN: usize = 32;
+const ATA_OP_IDLEIMMEDIATE: u8 = 0xE1;
+const ATA_OP_SLEEPNOW: u8 = 0xE6;
+const ATA_OP_SETIDLE: u8 = 0xE3;
+const ATA_OP_SET_FEATURES: u8 = 0xEF;
+const ATA_OP_IDENTIFY: u8 = 0xEC;
+const ATA_OP_SMART: u8 = 0xB0;
+const SETFEATURES_EN_APM: u8 = 0x05;
+const SETFEATURES_DIS_APM: u8 = 0x85;
+const SMART_READ_DATA: u8 = 0xD0;
+// signature in the LBA mid and high registers of every SMART command
+const SMART_LBA_MID: u8 = 0x4F;
+const SMART_LBA_HIGH: u8 = 0xC2;
+// SG_IO of <scsi/sg.h>. It predates the _IOWR() encoding and has the same number on every
+// architecture so far; one that differs gets its own cfg'd constant here. The type of the
+// request argument follows the libc (c_ulong on glibc, c_int on musl).
+#[cfg(target_os = "linux")]
+const SG_IO: Ioctl = 0x2285;
+const DEFAULT_SENSE_LEN: usize = 32;
+// room for the sense header and the ATA Status Return descriptor at its usual offset
+pub const MIN_SENSE_LEN: usize = DEFAULT_DESCRIPTOR_OFFSET + ATA_STATUS_RETURN_LEN;
+
+static SENSE_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_SENSE_LEN);
+// the truncation is the same on every command, it is only warned about once
+static SENSE_TRUNCATION_WARNED: AtomicBool = AtomicBool::new(false);
 
 const PROTOCOL: u8 = 3;  /* non-dat data-in */
 const EXTEND: u8 = 0;
@@ -23,7 +48,31 @@ const T_DIR: u8 = 1; /* 0 -> to device, 1 -> from device */
 const BYTE_BLOCK: u8 = 1; /* 0 -> bytes, 1 -> 512 byte blocks */
 const T_LENGTH: u8 = 0; /* 0 -> no data transferred, 2 -> sector count */
 
-#[derive(Debug, PartialEq)]
+const PROTOCOL_PIO_DATA_IN: u8 = 4;
+const T_LENGTH_SECTOR_COUNT: u8 = 2;
+const SECTOR_SIZE: usize = 512;
+const SG_DXFER_NONE: c_int = -1;
+const SG_DXFER_FROM_DEV: c_int = -3;
+// the driver status carries DRIVER_SENSE (0x08) whenever sense data was returned, which
+// CHK_COND requests on every command, only the lower bits tell of an error
+const SG_DRIVER_MASK: c_ushort = 0x07;
+
+// IDENTIFY DEVICE words, see ACS-3 section 7.12.7
+// 40 ASCII characters, two per word with the first in the high byte
+const ID_MODEL: std::ops::Range<usize> = 27..47;
+const ID_COMMAND_SET_2: usize = 83;
+const ID_COMMAND_SET_ENABLED_2: usize = 86;
+const ID_APM_LEVEL: usize = 91;
+const APM_BIT: u16 = 1 << 3;
+
+// the attribute table of SMART READ DATA: 30 entries of 12 bytes after the revision
+const SMART_ATTRIBUTES_OFFSET: usize = 2;
+const SMART_ATTRIBUTE_LEN: usize = 12;
+const SMART_ATTRIBUTE_COUNT: usize = 30;
+// Load/Unload Cycle Count, 225 on some older drives
+const LOAD_CYCLE_ATTRIBUTES: [u8; 2] = [193, 225];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PowerState {
     Standby,
     Idle,
@@ -34,12 +83,199 @@ pub enum PowerState {
     Unknown,
 }
 
+impl PowerState {
+    /// Decodes the count field returned by CHECK POWER MODE, see ACS-4 section 7.3.2
+    pub fn from_power_mode_byte(value: u8) -> PowerState {
+        match value {
+            // Standby_z and Standby_y
+            0x00 | 0x01 => PowerState::Standby,
+            // NV cache power mode, spindle spun down or spinning down
+            0x40 => PowerState::Standby,
+            // NV cache power mode, spindle spun up or spinning up
+            0x41 => PowerState::ActiveOrIdle,
+            0x80 => PowerState::Idle,
+            0x81 => PowerState::IdleA,
+            0x82 => PowerState::IdleB,
+            0x83 => PowerState::IdleC,
+            0xFF => PowerState::ActiveOrIdle,
+            _ => PowerState::Unknown,
+        }
+    }
+}
+
+impl PowerState {
+    const ALL: [PowerState; 7] = [PowerState::Standby, PowerState::Idle, PowerState::IdleA, PowerState::IdleB,
+        PowerState::IdleC, PowerState::ActiveOrIdle, PowerState::Unknown];
+
+    /// Stable numeric code for scripts and metrics
+    pub fn code(&self) -> u8 {
+        match self {
+            PowerState::Standby => 0,
+            PowerState::Idle => 1,
+            PowerState::IdleA => 2,
+            PowerState::IdleB => 3,
+            PowerState::IdleC => 4,
+            PowerState::ActiveOrIdle => 5,
+            PowerState::Unknown => 255,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<PowerState> {
+        PowerState::ALL.iter().copied().find(|state| state.code() == code)
+    }
+}
+
+impl FromStr for PowerState {
+    type Err = String;
+
+    /// Accepts the displayed name (case-insensitive) or the numeric code
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(code) = s.parse::<u8>() {
+            return PowerState::from_code(code).ok_or(format!("unknown power state code {}", code));
+        }
+        PowerState::ALL.iter().copied()
+            .find(|state| state.to_string().eq_ignore_ascii_case(s))
+            .ok_or(format!("unknown power state `{}`", s))
+    }
+}
+
 impl Display for PowerState {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
+/// Power state the device is put into once its timeout elapsed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerTarget {
+    // heads unloaded, spindle stopped
+    Standby,
+    // heads unloaded, spindle keeps spinning
+    Idle,
+    // lowest power; the drive needs a reset to wake up, which the kernel issues on the next access
+    Sleep,
+}
+
+impl PowerTarget {
+    pub fn is_reached(&self, power_state: PowerState) -> bool {
+        match self {
+            PowerTarget::Standby | PowerTarget::Sleep => power_state == PowerState::Standby,
+            PowerTarget::Idle => matches!(power_state, PowerState::Standby | PowerState::Idle |
+                PowerState::IdleA | PowerState::IdleB | PowerState::IdleC),
+        }
+    }
+}
+
+impl Display for PowerTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerTarget::Standby => write!(f, "standby"),
+            PowerTarget::Idle => write!(f, "idle"),
+            PowerTarget::Sleep => write!(f, "sleep"),
+        }
+    }
+}
+
+impl FromStr for PowerTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standby" => Ok(PowerTarget::Standby),
+            "idle" => Ok(PowerTarget::Idle),
+            "sleep" => Ok(PowerTarget::Sleep),
+            _ => Err(format!("unknown power target `{}`, expected standby, idle or sleep", s)),
+        }
+    }
+}
+
+// registers of an ATA command passed through to the device
+#[derive(Debug, Default)]
+struct AtaTaskfile {
+    command: u8,
+    features: u8,
+    sector_count: u8,
+    lba_mid: u8,
+    lba_high: u8,
+}
+
+impl AtaTaskfile {
+    fn new(command: u8) -> AtaTaskfile {
+        AtaTaskfile { command, ..Default::default() }
+    }
+}
+
+/// Status and error register returned by the device
+#[derive(Debug)]
+pub struct AtaResponse {
+    pub status: u8,
+    pub error: u8,
+}
+
+impl AtaResponse {
+    fn from_sense(sense: &[u8]) -> AtaResponse {
+        let offset = find_ata_status_descriptor(sense).unwrap_or(DEFAULT_DESCRIPTOR_OFFSET);
+        AtaResponse { status: sense[offset + 13], error: sense[offset + 3] }
+    }
+
+    /// The ERR bit of the status register, the device aborted the command
+    pub fn is_error(&self) -> bool {
+        self.status & 0x01 != 0
+    }
+}
+
+const DESCRIPTOR_SENSE_CURRENT: u8 = 0x72;
+const DESCRIPTOR_SENSE_DEFERRED: u8 = 0x73;
+const ATA_STATUS_RETURN_DESCRIPTOR: u8 = 0x09;
+const ATA_STATUS_RETURN_LEN: usize = 14;
+// where most SAT implementations place the ATA Status Return descriptor
+const DEFAULT_DESCRIPTOR_OFFSET: usize = 8;
+
+// Walks the descriptors of descriptor format sense data (SPC-4 section 4.5.2) and returns
+// the offset of the ATA Status Return descriptor (SAT-3 section 12.2.2.7).
+fn find_ata_status_descriptor(sense: &[u8]) -> Option<usize> {
+    let response_code = sense.first()? & 0x7F;
+    if response_code != DESCRIPTOR_SENSE_CURRENT && response_code != DESCRIPTOR_SENSE_DEFERRED {
+        return None;
+    }
+    let end = (8 + *sense.get(7)? as usize).min(sense.len());
+    let mut offset = 8;
+    while offset + 1 < end {
+        let descriptor_len = 2 + sense[offset + 1] as usize;
+        if sense[offset] == ATA_STATUS_RETURN_DESCRIPTOR && offset + ATA_STATUS_RETURN_LEN <= sense.len() {
+            return Some(offset);
+        }
+        offset += descriptor_len;
+    }
+    None
+}
+
+/// Decodes sense data for diagnostics: the sense key and additional sense code, and the
+/// registers of the ATA Status Return descriptor
+pub fn describe_sense(sense: &[u8]) -> String {
+    let response_code = sense.first().map_or(0, |code| code & 0x7F);
+    if response_code == 0 {
+        return "no sense data".to_string();
+    }
+    if response_code != DESCRIPTOR_SENSE_CURRENT && response_code != DESCRIPTOR_SENSE_DEFERRED {
+        return format!("fixed format sense (response code 0x{:02x}), no ATA status return descriptor", response_code);
+    }
+    let key = format!("sense key 0x{:x}, asc 0x{:02x}, ascq 0x{:02x}",
+                      sense.get(1).map_or(0, |key| key & 0x0F), sense.get(2).copied().unwrap_or(0),
+                      sense.get(3).copied().unwrap_or(0));
+    match find_ata_status_descriptor(sense) {
+        Some(offset) => format!("{}, ATA status return at offset {}: {}, sector count 0x{:02x}",
+                                key, offset, AtaResponse::from_sense(sense), sense[offset + 5]),
+        None => format!("{}, no ATA status return descriptor", key),
+    }
+}
+
+impl Display for AtaResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "status 0x{:02x}, error 0x{:02x}", self.status, self.error)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct SgIoHdr {
@@ -89,32 +325,99 @@ struct SgIoHdr {
     info: c_uint,
 }
 
-fn exec_sg(dev: &String, command: u8, sense: Option<&mut Vec<u8>>) -> Result<(), DeviceError> {
-    let raw_fd = open_dev_raw(dev)?;
+// DID_* codes of the host adapter, see include/scsi/scsi_status.h
+fn host_status_name(status: c_ushort) -> String {
+    let name = match status {
+        0x00 => "DID_OK",
+        0x01 => "DID_NO_CONNECT",
+        0x02 => "DID_BUS_BUSY",
+        0x03 => "DID_TIME_OUT",
+        0x04 => "DID_BAD_TARGET",
+        0x05 => "DID_ABORT",
+        0x06 => "DID_PARITY",
+        0x07 => "DID_ERROR",
+        0x08 => "DID_RESET",
+        0x09 => "DID_BAD_INTR",
+        0x0a => "DID_PASSTHROUGH",
+        0x0b => "DID_SOFT_ERROR",
+        0x0c => "DID_IMM_RETRY",
+        0x0d => "DID_REQUEUE",
+        0x0e => "DID_TRANSPORT_DISRUPTED",
+        0x0f => "DID_TRANSPORT_FAILFAST",
+        0x10 => "DID_TARGET_FAILURE",
+        0x11 => "DID_NEXUS_FAILURE",
+        0x12 => "DID_ALLOC_FAILURE",
+        0x13 => "DID_MEDIUM_ERROR",
+        _ => return format!("{:#04x}", status),
+    };
+    format!("{:#04x} ({})", status, name)
+}
+
+// DRIVER_* codes of the SCSI generic driver
+fn driver_status_name(status: c_ushort) -> String {
+    let name = match status {
+        0x00 => "DRIVER_OK",
+        0x01 => "DRIVER_BUSY",
+        0x02 => "DRIVER_SOFT",
+        0x03 => "DRIVER_MEDIA",
+        0x04 => "DRIVER_ERROR",
+        0x05 => "DRIVER_INVALID",
+        0x06 => "DRIVER_TIMEOUT",
+        0x07 => "DRIVER_HARD",
+        _ => return format!("{:#04x}", status),
+    };
+    format!("{:#04x} ({})", status, name)
+}
+
+/// Sets the size of the sense buffer passed to the kernel with every command, some SAT
+/// implementations place the ATA Status Return descriptor behind other descriptors
+pub fn set_sense_len(len: u8) {
+    SENSE_LEN.store((len as usize).max(MIN_SENSE_LEN), Ordering::SeqCst);
+}
+
+/// A zeroed sense buffer of the configured size
+pub fn sense_buffer() -> Vec<u8> {
+    vec![0; SENSE_LEN.load(Ordering::SeqCst)]
+}
+
+// `data` receives the sectors of a PIO data-in command, commands without data pass None
+fn exec_sg(dev: &str, paths: &Paths, taskfile: &AtaTaskfile, sense: Option<&mut Vec<u8>>,
+           data: Option<&mut [u8]>) -> Result<(), DeviceError> {
+    let dev_path = paths.dev(dev);
+    let raw_fd = open_dev_raw(&dev_path)?;
 
-    let tmp_sense = &mut vec![0; SENSE_LEN];
+    let tmp_sense = &mut sense_buffer();
     let sbp = sense.unwrap_or(tmp_sense);
 
     // see https://www.t10.org/ftp/t10/document.04/04-262r8.pdf
     // section 13.2.3 ATA PASS-THROUGH (16) command overview
     let mut cmd: [u8; 16] = [SAT_ATA_PASS_THROUGH16, 0, 0, 0, 0, 0, 0, 0,
         0, 0, 0, 0, 0, 0, 0, 0] as [u8; 16];
-    cmd[1] = (PROTOCOL << 1) | EXTEND;
+    let (protocol, t_length) = if data.is_some() {
+        (PROTOCOL_PIO_DATA_IN, T_LENGTH_SECTOR_COUNT)
+    } else {
+        (PROTOCOL, T_LENGTH)
+    };
+    cmd[1] = (protocol << 1) | EXTEND;
     cmd[2] = (CHK_COND << 5) | (T_DIR << 3) |
-        (BYTE_BLOCK << 2) | T_LENGTH;
-    cmd[14] = command;
+        (BYTE_BLOCK << 2) | t_length;
+    cmd[4] = taskfile.features;
+    cmd[6] = taskfile.sector_count;
+    cmd[10] = taskfile.lba_mid;
+    cmd[12] = taskfile.lba_high;
+    cmd[14] = taskfile.command;
 
     // see https://tldp.org/HOWTO/SCSI-Generic-HOWTO/sg_io_hdr_t.html
-    let sg_io_hdr = SgIoHdr {
+    let mut sg_io_hdr = SgIoHdr {
         interface_id: 'S' as c_int,
 
-        dxfer_direction: -1, // Direction::None
-        dxferp: null_mut() as *mut c_void,
-        dxfer_len: 0 as c_uint,
+        dxfer_direction: if data.is_some() { SG_DXFER_FROM_DEV } else { SG_DXFER_NONE },
+        dxfer_len: data.as_ref().map_or(0, |buf| buf.len()) as c_uint,
+        dxferp: data.map_or(null_mut(), |buf| buf.as_mut_ptr() as *mut c_void),
         resid: 0,
 
         sbp: sbp.as_mut_ptr(),
-        mx_sb_len: sbp.capacity() as c_uchar,
+        mx_sb_len: sbp.len().min(c_uchar::MAX as usize) as c_uchar,
         sb_len_wr: 0,
 
         cmdp: cmd.as_ptr(),
@@ -137,22 +440,52 @@ fn exec_sg(dev: &String, command: u8, sense: Option<&mut Vec<u8>>) -> Result<(),
     };
 
     unsafe {
-        if ioctl(raw_fd, SG_IO, &sg_io_hdr) != 0 {
+        if ioctl(raw_fd, SG_IO, &mut sg_io_hdr) != 0 {
+            let error = io::Error::last_os_error();
             match close(raw_fd) {
                 Ok(()) => (),
-                Err(e) => println!("unable to close {}: {}", dev.to_string(), e.to_string())
+                Err(e) => println!("unable to close {}: {}", dev_path, e)
             }
-            return Err(DeviceError::new(dev.to_string(), io::Error::last_os_error().to_string()));
+            return Err(DeviceError::from_io(dev_path, &error));
         }
     }
     match close(raw_fd) {
         Ok(()) => (),
-        Err(e) => return Err(DeviceError::new(dev.to_string(), e.to_string()))
+        Err(e) => return Err(DeviceError::new(dev_path, e.to_string()))
+    }
+    // the ioctl succeeds as soon as the request was submitted, a command lost on the way
+    // (e.g. a reset USB bridge) only shows up in these
+    let driver_status = sg_io_hdr.driver_status & SG_DRIVER_MASK;
+    if sg_io_hdr.host_status != 0 || driver_status != 0 {
+        return Err(DeviceError::new(dev_path, format!("transport error: host status {}, driver status {}",
+                                                      host_status_name(sg_io_hdr.host_status),
+                                                      driver_status_name(driver_status))));
     }
+    check_sense_len(dev, sbp, sg_io_hdr.mx_sb_len, sg_io_hdr.sb_len_wr);
     Ok(())
 }
 
-fn open_dev_raw(dev: &String) -> Result<RawFd, DeviceError> {
+// Compares the length the sense data declares (byte 7 of descriptor format sense data) with
+// what the kernel wrote; a buffer too small for it cuts off the descriptors behind the first.
+fn check_sense_len(dev: &str, sense: &[u8], max_len: c_uchar, written: c_uchar) {
+    let response_code = sense.first().map_or(0, |code| code & 0x7F);
+    if response_code != DESCRIPTOR_SENSE_CURRENT && response_code != DESCRIPTOR_SENSE_DEFERRED {
+        return;
+    }
+    let declared = 8 + sense[7] as usize;
+    if (written as usize) < declared {
+        if SENSE_TRUNCATION_WARNED.swap(true, Ordering::SeqCst) {
+            log::debug!("sense data of {} truncated to {} of {} bytes", dev, written, declared);
+            return;
+        }
+        log::warn!("sense data of {} truncated: {} bytes declared, {} written into a buffer of {}, see --sense-len",
+                   dev, declared, written, max_len);
+    } else if (written as usize) < MIN_SENSE_LEN {
+        log::debug!("sense data of {} is only {} bytes long", dev, written);
+    }
+}
+
+fn open_dev_raw(dev_path: &str) -> Result<RawFd, DeviceError> {
     let mut options = OpenOptions::new();
     options.read(true);
 
@@ -160,29 +493,214 @@ fn open_dev_raw(dev: &String) -> Result<RawFd, DeviceError> {
         options.custom_flags(O_NONBLOCK);
     }
 
-    match options.open(format!("/dev/{}", dev)) {
+    match options.open(dev_path) {
         Ok(fd) => { Ok(fd.into_raw_fd()) }
-        Err(e) => { Err(DeviceError::new(dev.to_string(), e.to_string())) }
+        Err(e) => { Err(DeviceError::from_io(dev_path.to_string(), &e)) }
     }
 }
 
-pub fn check_power_state(dev: &String) -> Result<PowerState, DeviceError> {
-    let mut sense = vec![0; SENSE_LEN];
-    exec_sg(dev, ATA_CHECK_POWER_MODE, Option::Some(&mut sense))?;
+/// Opens the device node like every command does, e.g. to tell missing permissions apart
+pub fn check_access(dev: &str, paths: &Paths) -> Result<(), DeviceError> {
+    let dev_path = paths.dev(dev);
+    let raw_fd = open_dev_raw(&dev_path)?;
+    close(raw_fd).map_err(|e| DeviceError::new(dev_path, e.to_string()))
+}
+
+pub fn check_power_state(dev: &str, paths: &Paths) -> Result<PowerState, DeviceError> {
+    check_power_state_with_sense(dev, paths, &mut sense_buffer())
+}
 
-    let power_status = match sense[13] {
-        0x00 => PowerState::Standby,
-        0x80 => PowerState::Idle,
-        0x81 => PowerState::IdleA,
-        0x82 => PowerState::IdleB,
-        0x83 => PowerState::IdleC,
-        0xFF => PowerState::ActiveOrIdle,
-        _ => PowerState::Unknown,
+/// Like `check_power_state`, leaving the sense data in `sense`, e.g. to show it after an error
+pub fn check_power_state_with_sense(dev: &str, paths: &Paths, sense: &mut Vec<u8>) -> Result<PowerState, DeviceError> {
+    exec_sg(dev, paths, &AtaTaskfile::new(ATA_CHECK_POWER_MODE), Option::Some(sense), None)?;
+    log::debug!("sense of {}: {}", dev, hex_dump(sense));
+    // sector count register of the ATA Status Return descriptor
+    let count_offset = match find_ata_status_descriptor(sense) {
+        Some(offset) => {
+            if offset != DEFAULT_DESCRIPTOR_OFFSET {
+                log::debug!("ATA status descriptor of {} found at offset {}", dev, offset);
+            }
+            offset + 5
+        }
+        None => {
+            log::debug!("no ATA status descriptor in sense of {}, falling back to byte 13", dev);
+            DEFAULT_DESCRIPTOR_OFFSET + 5
+        }
     };
-    Ok(power_status)
+    log::debug!("power mode byte of {}: 0x{:02x}", dev, sense[count_offset]);
+
+    Ok(PowerState::from_power_mode_byte(sense[count_offset]))
 }
 
-pub fn do_standby(dev: &String) -> Result<(), DeviceError> {
-    exec_sg(dev, ATA_OP_STANDBYNOW, Option::None)?;
+pub fn do_standby(dev: &str, paths: &Paths) -> Result<(), DeviceError> {
+    exec_sg(dev, paths, &AtaTaskfile::new(ATA_OP_STANDBYNOW), Option::None, None)?;
     Ok(())
+}
+
+/// Issues STANDBY IMMEDIATE, leaving the sense data with the returned registers in `sense`
+pub fn do_standby_with_sense(dev: &str, paths: &Paths, sense: &mut Vec<u8>) -> Result<AtaResponse, DeviceError> {
+    exec_sg(dev, paths, &AtaTaskfile::new(ATA_OP_STANDBYNOW), Option::Some(sense), None)?;
+    Ok(AtaResponse::from_sense(sense))
+}
+
+pub fn do_idle(dev: &str, paths: &Paths) -> Result<(), DeviceError> {
+    exec_sg(dev, paths, &AtaTaskfile::new(ATA_OP_IDLEIMMEDIATE), Option::None, None)?;
+    Ok(())
+}
+
+pub fn do_sleep(dev: &str, paths: &Paths) -> Result<(), DeviceError> {
+    exec_sg(dev, paths, &AtaTaskfile::new(ATA_OP_SLEEPNOW), Option::None, None)?;
+    Ok(())
+}
+
+pub fn do_power_target(dev: &str, paths: &Paths, target: PowerTarget) -> Result<(), DeviceError> {
+    match target {
+        PowerTarget::Standby => do_standby(dev, paths),
+        PowerTarget::Idle => do_idle(dev, paths),
+        PowerTarget::Sleep => do_sleep(dev, paths),
+    }
+}
+
+/// Sets the advanced power management level; 1-127 permit spin-down, 128-254 do not
+/// and 255 disables APM.
+pub fn set_apm(dev: &str, paths: &Paths, level: u8) -> Result<AtaResponse, DeviceError> {
+    let taskfile = if level == 255 {
+        AtaTaskfile { command: ATA_OP_SET_FEATURES, features: SETFEATURES_DIS_APM, ..Default::default() }
+    } else {
+        AtaTaskfile { command: ATA_OP_SET_FEATURES, features: SETFEATURES_EN_APM, sector_count: level,
+            ..Default::default() }
+    };
+    let mut sense = sense_buffer();
+    exec_sg(dev, paths, &taskfile, Option::Some(&mut sense), None)?;
+    Ok(AtaResponse::from_sense(&sense))
+}
+
+/// Sets the firmware standby timer without spinning down (same encoding as `hdparm -S`),
+/// 0 disables the timer.
+pub fn set_standby_timer(dev: &str, paths: &Paths, value: u8) -> Result<AtaResponse, DeviceError> {
+    let taskfile = AtaTaskfile { command: ATA_OP_SETIDLE, sector_count: value, ..Default::default() };
+    let mut sense = sense_buffer();
+    exec_sg(dev, paths, &taskfile, Option::Some(&mut sense), None)?;
+    Ok(AtaResponse::from_sense(&sense))
+}
+
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" ")
+}
+
+/// Reads the 256 words of IDENTIFY DEVICE data
+pub fn identify(dev: &str, paths: &Paths) -> Result<Vec<u16>, DeviceError> {
+    let taskfile = AtaTaskfile { command: ATA_OP_IDENTIFY, sector_count: 1, ..Default::default() };
+    let mut data = vec![0u8; SECTOR_SIZE];
+    exec_sg(dev, paths, &taskfile, Option::None, Some(&mut data))?;
+    Ok(data.chunks(2).map(|word| u16::from_le_bytes([word[0], word[1]])).collect())
+}
+
+/// The model number of IDENTIFY DEVICE data
+pub fn identify_model(words: &[u16]) -> String {
+    let bytes: Vec<u8> = words[ID_MODEL].iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+/// Returns the current APM level, or None if APM is unsupported or disabled
+pub fn get_apm_level(dev: &str, paths: &Paths) -> Result<Option<u8>, DeviceError> {
+    let words = identify(dev, paths)?;
+    if words[ID_COMMAND_SET_2] & APM_BIT == 0 || words[ID_COMMAND_SET_ENABLED_2] & APM_BIT == 0 {
+        return Ok(None);
+    }
+    Ok(Some((words[ID_APM_LEVEL] & 0xFF) as u8))
+}
+
+/// Reads the 512 bytes of SMART READ DATA with the attribute table. Like any command
+/// reaching the media, it spins up a disk in standby.
+pub fn smart_read_data(dev: &str, paths: &Paths) -> Result<Vec<u8>, DeviceError> {
+    let taskfile = AtaTaskfile { command: ATA_OP_SMART, features: SMART_READ_DATA, sector_count: 1,
+        lba_mid: SMART_LBA_MID, lba_high: SMART_LBA_HIGH };
+    let mut data = vec![0u8; SECTOR_SIZE];
+    exec_sg(dev, paths, &taskfile, Option::None, Some(&mut data))?;
+    Ok(data)
+}
+
+/// The raw value of the Load/Unload Cycle Count attribute, None if the drive has none
+pub fn load_cycle_count(smart_data: &[u8]) -> Option<u64> {
+    let attributes: Vec<&[u8]> = smart_data.get(SMART_ATTRIBUTES_OFFSET..)?
+        .chunks_exact(SMART_ATTRIBUTE_LEN)
+        .take(SMART_ATTRIBUTE_COUNT)
+        .collect();
+    LOAD_CYCLE_ATTRIBUTES.iter()
+        .find_map(|id| attributes.iter().find(|attribute| attribute[0] == *id))
+        // 48 bit little endian raw value after the id, flags, value and worst
+        .map(|attribute| attribute[5..11].iter().rev().fold(0u64, |raw, byte| raw << 8 | *byte as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CHECK POWER MODE of a spinning disk behind libata: descriptor format sense with the
+    // ATA Status Return descriptor right after the header, count 0xff and status 0x50
+    const LIBATA_SENSE: [u8; 22] = [0x72, 0x01, 0x00, 0x1d, 0x00, 0x00, 0x00, 0x0e,
+        0x09, 0x0c, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x50];
+
+    #[test]
+    fn power_mode_byte_maps_to_its_state() {
+        let expected = [
+            (0x00, PowerState::Standby),
+            (0x01, PowerState::Standby),
+            (0x40, PowerState::Standby),
+            (0x41, PowerState::ActiveOrIdle),
+            (0x80, PowerState::Idle),
+            (0x81, PowerState::IdleA),
+            (0x82, PowerState::IdleB),
+            (0x83, PowerState::IdleC),
+            (0xFF, PowerState::ActiveOrIdle),
+        ];
+        for (value, power_state) in expected {
+            assert_eq!(PowerState::from_power_mode_byte(value), power_state, "0x{:02x}", value);
+        }
+        for value in (0..=u8::MAX).filter(|value| !expected.iter().any(|(known, _)| known == value)) {
+            assert_eq!(PowerState::from_power_mode_byte(value), PowerState::Unknown, "0x{:02x}", value);
+        }
+    }
+
+    #[test]
+    fn ata_status_descriptor_is_found_after_the_header() {
+        assert_eq!(find_ata_status_descriptor(&LIBATA_SENSE), Some(8));
+        let response = AtaResponse::from_sense(&LIBATA_SENSE);
+        assert_eq!(response.status, 0x50);
+        assert_eq!(response.error, 0x00);
+        assert!(!response.is_error());
+    }
+
+    #[test]
+    fn ata_status_descriptor_is_found_after_another_descriptor() {
+        let mut sense = vec![0x72, 0x01, 0x00, 0x1d, 0x00, 0x00, 0x00, 12 + 14];
+        // an information descriptor first, 12 bytes
+        sense.extend_from_slice(&[0x00, 0x0a, 0x80, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]);
+        sense.extend_from_slice(&LIBATA_SENSE[8..]);
+        assert_eq!(find_ata_status_descriptor(&sense), Some(20));
+        assert_eq!(sense[20 + 5], 0xff);
+        assert_eq!(AtaResponse::from_sense(&sense).status, 0x50);
+    }
+
+    #[test]
+    fn fixed_format_sense_falls_back_to_the_default_offset() {
+        let mut sense = vec![0u8; 32];
+        sense[0] = 0x70;
+        sense[2] = 0x01;
+        sense[7] = 0x0a;
+        sense[DEFAULT_DESCRIPTOR_OFFSET + 3] = 0x04;
+        sense[DEFAULT_DESCRIPTOR_OFFSET + 13] = 0x51;
+        assert_eq!(find_ata_status_descriptor(&sense), None);
+        let response = AtaResponse::from_sense(&sense);
+        assert_eq!(response.status, 0x51);
+        assert_eq!(response.error, 0x04);
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn truncated_descriptor_is_not_used() {
+        assert_eq!(find_ata_status_descriptor(&LIBATA_SENSE[..16]), None);
+        assert_eq!(find_ata_status_descriptor(&[]), None);
+    }
 }
\ No newline at end of file
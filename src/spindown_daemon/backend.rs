@@ -0,0 +1,130 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::spindown_daemon::ata::{check_power_state, do_power_target, PowerState, PowerTarget};
+use crate::spindown_daemon::errors::DeviceError;
+use crate::spindown_daemon::paths::Paths;
+use crate::spindown_daemon::sysfs::{get_device_stats, get_runtime_power_state, DiskStats};
+
+/// Queries and changes the power state of physical disks. The daemon uses ATA pass-through,
+/// tests replace it to run the checks without hardware.
+pub trait PowerBackend: Send + Sync {
+    fn power_state(&self, disk: &str, paths: &Paths) -> Result<PowerState, DeviceError>;
+    fn power_target(&self, disk: &str, paths: &Paths, target: PowerTarget) -> Result<(), DeviceError>;
+}
+
+pub struct AtaBackend;
+
+impl PowerBackend for AtaBackend {
+    fn power_state(&self, disk: &str, paths: &Paths) -> Result<PowerState, DeviceError> {
+        check_power_state(disk, paths)
+    }
+
+    fn power_target(&self, disk: &str, paths: &Paths, target: PowerTarget) -> Result<(), DeviceError> {
+        do_power_target(disk, paths, target)
+    }
+}
+
+/// Reads the power state from the runtime PM status in sysfs while the kernel has the disk
+/// suspended, where the pass-through query would resume it; otherwise like AtaBackend.
+pub struct SysfsBackend;
+
+impl PowerBackend for SysfsBackend {
+    fn power_state(&self, disk: &str, paths: &Paths) -> Result<PowerState, DeviceError> {
+        match get_runtime_power_state(disk, paths) {
+            Some(power_state) => {
+                log::debug!("{} is runtime suspended", disk);
+                Ok(power_state)
+            }
+            None => check_power_state(disk, paths),
+        }
+    }
+
+    fn power_target(&self, disk: &str, paths: &Paths, target: PowerTarget) -> Result<(), DeviceError> {
+        do_power_target(disk, paths, target)
+    }
+}
+
+/// Reads the I/O counters of a device, which tell whether it is in use. The daemon reads
+/// sysfs (or /proc/diskstats), tests replace it to script the activity.
+pub trait StatReader: Send + Sync {
+    fn device_stats(&self, dev: &str, paths: &Paths) -> Result<DiskStats, DeviceError>;
+}
+
+pub struct SysfsStats;
+
+impl StatReader for SysfsStats {
+    fn device_stats(&self, dev: &str, paths: &Paths) -> Result<DiskStats, DeviceError> {
+        get_device_stats(dev, paths)
+    }
+}
+
+/// The time the idle times and timeouts of the checks are measured with. Tests advance it
+/// by hand instead of waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// What the devices are checked with, shared by the main loop and the device threads
+#[derive(Clone)]
+pub struct Probes {
+    pub stats: Arc<dyn StatReader>,
+    pub power: Arc<dyn PowerBackend>,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Probes {
+    /// The stats from sysfs and the system clock, with the given power backend
+    pub fn new(power: Arc<dyn PowerBackend>) -> Probes {
+        Probes { stats: Arc::new(SysfsStats), power, clock: Arc::new(SystemClock) }
+    }
+}
+
+/// Where the power states come from, the power commands always use ATA pass-through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSource {
+    Ata,
+    // runtime PM of the kernel, falling back to ATA
+    Sysfs,
+}
+
+impl PowerSource {
+    pub fn backend(&self) -> Arc<dyn PowerBackend> {
+        match self {
+            PowerSource::Ata => Arc::new(AtaBackend),
+            PowerSource::Sysfs => Arc::new(SysfsBackend),
+        }
+    }
+}
+
+impl Display for PowerSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerSource::Ata => write!(f, "ata"),
+            PowerSource::Sysfs => write!(f, "sysfs"),
+        }
+    }
+}
+
+impl FromStr for PowerSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ata" => Ok(PowerSource::Ata),
+            "sysfs" => Ok(PowerSource::Sysfs),
+            _ => Err(format!("unknown power source `{}`, expected ata or sysfs", s)),
+        }
+    }
+}
\ No newline at end of file
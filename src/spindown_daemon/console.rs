@@ -0,0 +1,82 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::unistd::isatty;
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    // only on a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl Display for ColorMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorMode::Auto => write!(f, "auto"),
+            ColorMode::Always => write!(f, "always"),
+            ColorMode::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(format!("unknown color mode `{}`, expected auto, always or never", s)),
+        }
+    }
+}
+
+impl ColorMode {
+    /// The same choice for the log lines on stderr
+    pub fn color_choice(&self) -> stderrlog::ColorChoice {
+        match self {
+            ColorMode::Auto => stderrlog::ColorChoice::Auto,
+            ColorMode::Always => stderrlog::ColorChoice::Always,
+            ColorMode::Never => stderrlog::ColorChoice::Never,
+        }
+    }
+}
+
+/// Enables the colors of the messages on stdout
+pub fn init(mode: ColorMode) {
+    let enabled = match mode {
+        ColorMode::Auto => isatty(1).unwrap_or(false),
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+    };
+    COLOR.store(enabled, Ordering::SeqCst);
+}
+
+/// Highlights a power command or wake-up
+pub fn event(message: String) -> String {
+    paint(GREEN, message)
+}
+
+/// Highlights a failed power command
+pub fn failure(message: String) -> String {
+    paint(RED, message)
+}
+
+fn paint(color: &str, message: String) -> String {
+    if COLOR.load(Ordering::SeqCst) {
+        format!("{}{}{}", color, message, RESET)
+    } else {
+        message
+    }
+}
\ No newline at end of file
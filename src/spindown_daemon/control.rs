@@ -0,0 +1,183 @@
+use std::fs::{remove_file, set_permissions, Permissions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::spindown_daemon::signals;
+use crate::spindown_daemon::spec::DeviceSpec;
+
+pub const DEFAULT_SOCKET: &str = "/run/spindown-daemon.sock";
+// who may connect is decided by the permissions of the socket
+const SOCKET_MODE: u32 = 0o660;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// the main loop answers between its checks, which wait up to --check-deadline for a device
+const REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// A command of `spindown-daemon ctl`, sent as a single line
+pub enum ControlCommand {
+    Status,
+    Standby(String),
+    Add(DeviceSpec),
+    Remove(String),
+    Reset,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["status"] => Ok(ControlCommand::Status),
+            ["standby", device] => Ok(ControlCommand::Standby(device.to_string())),
+            ["add", spec] => {
+                let spec = spec.parse::<DeviceSpec>()?;
+                if spec.is_pattern() {
+                    return Err("add takes a single device, not a pattern".to_string());
+                }
+                // the daemon would run them, possibly as root, for anyone allowed to use the socket
+                if spec.hooks.standby.is_some() || spec.hooks.wake.is_some() || spec.hooks.error.is_some() {
+                    return Err("add does not take on-standby, on-wake or on-error, only target, interval, \
+                                tolerance and load-cycles".to_string());
+                }
+                Ok(ControlCommand::Add(spec))
+            }
+            ["remove", device] => Ok(ControlCommand::Remove(device.to_string())),
+            ["reset"] => Ok(ControlCommand::Reset),
+            _ => Err(format!("unknown command `{}`, expected status, standby DEVICE, add DEVICE:TIMEOUT, \
+                              remove DEVICE or reset", s.trim())),
+        }
+    }
+}
+
+/// The output lines of a command or the error
+pub type ControlReply = Result<Vec<String>, String>;
+
+/// A command received on the control socket (or D-Bus), waiting for the main loop to answer it
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: Sender<ControlReply>,
+}
+
+impl ControlRequest {
+    /// A request whose answer arrives at the returned receiver
+    pub fn new(command: ControlCommand) -> (ControlRequest, Receiver<ControlReply>) {
+        let (reply, answer) = channel();
+        (ControlRequest { command, reply }, answer)
+    }
+
+    /// Sends the output lines or the error to the client
+    pub fn reply(self, result: ControlReply) {
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Accepts the commands of `spindown-daemon ctl` on a unix socket. The commands are
+/// handled by the main loop, so they never race with a check round.
+pub struct ControlServer {
+    requests: Receiver<ControlRequest>,
+}
+
+impl ControlServer {
+    pub fn listen(path: &str) -> Result<ControlServer, String> {
+        // a socket left behind by a previous run would make the bind fail
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            let _ = remove_file(path);
+        }
+        let listener = UnixListener::bind(path).map_err(|e| format!("unable to listen on {}: {}", path, e))?;
+        set_permissions(path, Permissions::from_mode(SOCKET_MODE))
+            .map_err(|e| format!("unable to set the permissions of {}: {}", path, e))?;
+        let (sender, requests) = channel();
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                    serve_request(stream, &sender);
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(ControlServer { requests })
+    }
+
+    /// Returns the requests received since the last call
+    pub fn requests(&self) -> Vec<ControlRequest> {
+        self.requests.try_iter().collect()
+    }
+}
+
+// Answers with the output lines followed by `ok` or by `error MESSAGE`
+fn serve_request(mut stream: UnixStream, requests: &Sender<ControlRequest>) {
+    let mut line = String::new();
+    let read = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader.take(MAX_REQUEST_LEN)).read_line(&mut line),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = read {
+        log::debug!("unable to read control request: {}", e);
+        return;
+    }
+    log::debug!("control request {:?}", line.trim());
+    let result = match line.parse::<ControlCommand>() {
+        Ok(command) => {
+            let (request, answer) = ControlRequest::new(command);
+            if requests.send(request).is_err() {
+                return;
+            }
+            signals::wake();
+            answer.recv_timeout(REPLY_TIMEOUT)
+                .unwrap_or_else(|_| Err("the daemon did not answer in time".to_string()))
+        }
+        Err(e) => Err(e),
+    };
+    let response = match result {
+        Ok(mut lines) => {
+            lines.push("ok".to_string());
+            lines.join("\n") + "\n"
+        }
+        Err(e) => format!("error {}\n", e),
+    };
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        log::debug!("unable to answer control request: {}", e);
+    }
+}
+
+/// Sends a command to the running daemon, returning its output lines
+pub fn send_command(path: &str, command: &str) -> Result<Vec<String>, String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| format!("unable to connect to {}: {}", path, e))?;
+    stream.write_all(format!("{}\n", command).as_bytes())
+        .map_err(|e| format!("unable to send the command to {}: {}", path, e))?;
+    let mut lines: Vec<String> = vec![];
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| format!("unable to read the answer from {}: {}", path, e))?;
+        if line == "ok" {
+            return Ok(lines);
+        }
+        if let Some(message) = line.strip_prefix("error ") {
+            return Err(message.to_string());
+        }
+        lines.push(line);
+    }
+    Err("the daemon closed the connection without an answer".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_refuses_hooks() {
+        for command in ["add sdb:600,on-standby=/x", "add sdb:600,on-wake=/x", "add sdb:600,target=idle,on-error=/x"] {
+            assert!(command.parse::<ControlCommand>().is_err(), "{} was accepted", command);
+        }
+        match "add sdb:600,target=idle,interval=30,tolerance=5,load-cycles=600000".parse::<ControlCommand>() {
+            Ok(ControlCommand::Add(spec)) => assert_eq!(spec.check_interval, Some(30)),
+            _ => panic!("add with the device options was refused"),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::ata::PowerTarget;
+use crate::spindown_daemon::round::{next_check_delay, reset_timers, run_once, RoundContext};
+use crate::spindown_daemon::suspend_gate::SuspendGate;
+use crate::spindown_daemon::worker::DeviceWorker;
+
+/// What a tick did
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    // a power command was queued for a disk of the device, its outcome is handled by a later tick
+    PowerTarget { device: String, disk: String, target: PowerTarget },
+    // the system may be suspended as far as the disks are concerned, the check scripts and
+    // the suspend command are up to the caller
+    Suspend,
+}
+
+/// The watched devices with their check threads and the suspend timing. The stats, the power
+/// states and the time come from the probes of the context, so ticks run without hardware
+/// or waiting in the tests. main() sets it up and feeds it the signals, hotplug events and
+/// control requests.
+// the devices are boxed like everywhere else, the rounds take them as a slice
+#[allow(clippy::vec_box)]
+pub struct Daemon<'a> {
+    pub devices: Vec<Box<DeviceInfo>>,
+    pub workers: HashMap<String, DeviceWorker>,
+    pub context: RoundContext<'a>,
+    // only with --suspend
+    pub suspend_gate: Option<SuspendGate>,
+    // a device that is not ignored for suspend is not in standby
+    pub disks_running: bool,
+    pub latest_update: Option<Instant>,
+    // a forced standby is followed by an immediate check, which sees the disks in standby
+    pub forced_suspend: bool,
+}
+
+impl<'a> Daemon<'a> {
+    #[allow(clippy::vec_box)]
+    pub fn new(devices: Vec<Box<DeviceInfo>>, workers: HashMap<String, DeviceWorker>, context: RoundContext<'a>,
+               suspend_gate: Option<SuspendGate>) -> Daemon<'a> {
+        Daemon {
+            devices,
+            workers,
+            context,
+            suspend_gate,
+            disks_running: false,
+            latest_update: None,
+            forced_suspend: false,
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.context.probes.clock.now()
+    }
+
+    /// Runs a check round and decides about the system suspend. A `forced` tick (SIGUSR2)
+    /// ignores the timeouts of the devices, and the suspend timeout with the tick after it.
+    pub fn tick(&mut self, forced: bool) -> Vec<Action> {
+        let round = run_once(&mut self.devices, &mut self.workers, &self.context, forced || self.forced_suspend, forced);
+        self.disks_running = round.disks_running;
+        self.latest_update = round.latest_update;
+        let mut actions = round.actions;
+
+        let ignore_suspend_timeout = forced || self.forced_suspend;
+        self.forced_suspend = forced && self.suspend_gate.is_some();
+        if self.context.inhibited {
+            return actions;
+        }
+        let now = self.now();
+        if let Some(suspend_gate) = self.suspend_gate.as_mut() {
+            log::debug!("checking system suspend");
+            match suspend_gate.check(self.disks_running, self.latest_update, ignore_suspend_timeout, now) {
+                Ok(()) => {
+                    self.forced_suspend = false;
+                    actions.push(Action::Suspend);
+                }
+                Err(blocker) => log::debug!("{}", blocker),
+            }
+        }
+        actions
+    }
+
+    /// Time until the next tick: the next device check or, with all disks asleep, the end of
+    /// the suspend timeout (or cooldown) rather than the next check after it
+    pub fn next_tick_delay(&self) -> Duration {
+        if self.forced_suspend {
+            return Duration::ZERO;
+        }
+        let now = self.now();
+        let delay = next_check_delay(&self.devices, self.context.group_timeout, now);
+        let suspend_at = self.suspend_gate.as_ref()
+            .zip(self.latest_update)
+            .filter(|_| !self.disks_running && !self.context.inhibited)
+            .map(|(suspend_gate, latest)| suspend_gate.suspend_at(latest));
+        match suspend_at.and_then(|suspend_at| suspend_at.checked_duration_since(now)) {
+            Some(until_suspend) => delay.min(until_suspend),
+            None => delay,
+        }
+    }
+
+    /// Restarts the timers after the system resumed, see reset_timers
+    pub fn resumed(&mut self) {
+        reset_timers(&mut self.devices, self.context.paths, self.context.probes);
+        let now = self.now();
+        if let Some(suspend_gate) = self.suspend_gate.as_mut() {
+            suspend_gate.resumed_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spindown_daemon::get_device_info;
+    use crate::spindown_daemon::ata::PowerState;
+    use crate::spindown_daemon::round::tests::{Harness, TIMEOUT};
+    use crate::spindown_daemon::worker::RetryPolicy;
+
+    // The disk of the round harness, watched by a daemon with its own check thread
+    fn daemon(harness: &Harness, suspend_gate: Option<SuspendGate>) -> Daemon<'_> {
+        let mut dev = get_device_info("sdx", &harness.paths, &harness.probes).ok().unwrap();
+        dev.timeout = TIMEOUT;
+        dev.check_interval = 60;
+        dev.next_check = harness.now() + Duration::from_secs(dev.check_interval);
+        let worker = DeviceWorker::spawn("sdx", &harness.paths, harness.probes.clone(), dev.standby_method,
+                                         RetryPolicy::new(0)).unwrap();
+        let context = RoundContext {
+            reconcile_interval: Duration::from_secs(900),
+            check_deadline: Duration::from_secs(5),
+            suspend_ignore_watch_only: false,
+            group_timeout: None,
+            dirty_threshold: None,
+            idle_poll_interval: None,
+            paths: &harness.paths,
+            probes: &harness.probes,
+            notifiers: &harness.notifiers,
+            inhibited: false,
+        };
+        Daemon::new(vec![Box::new(dev)], HashMap::from([("sdx".to_string(), worker)]), context, suspend_gate)
+    }
+
+    // the clock jumps to the next tick like the main loop sleeps until it
+    fn next_tick(harness: &Harness, daemon: &mut Daemon) -> Vec<Action> {
+        harness.advance(daemon.next_tick_delay().as_secs());
+        daemon.tick(false)
+    }
+
+    // the actions of the ticks within the next `seconds`
+    fn run_for(harness: &Harness, daemon: &mut Daemon, seconds: u64) -> Vec<Action> {
+        let until = harness.now() + Duration::from_secs(seconds);
+        let mut actions = vec![];
+        while harness.now() + daemon.next_tick_delay() <= until {
+            actions.extend(next_tick(harness, daemon));
+        }
+        actions
+    }
+
+    #[test]
+    fn system_is_suspended_after_the_suspend_timeout() {
+        let harness = Harness::new("daemon-suspend");
+        let started = harness.now();
+        let suspend_gate = SuspendGate::new(Duration::from_secs(300), Duration::from_secs(3600), Duration::ZERO);
+        let mut daemon = daemon(&harness, Some(suspend_gate));
+        assert!(run_for(&harness, &mut daemon, TIMEOUT).is_empty());
+        let standby = Action::PowerTarget { device: "sdx".to_string(), disk: "sdx".to_string(), target: PowerTarget::Standby };
+        assert_eq!(next_tick(&harness, &mut daemon), vec![standby]);
+
+        // all disks are asleep since the standby, the suspend follows 300s later rather than
+        // with the next check after that
+        assert!(run_for(&harness, &mut daemon, 299).is_empty());
+        assert_eq!(next_tick(&harness, &mut daemon), vec![Action::Suspend]);
+        assert_eq!(harness.now() - started, Duration::from_secs(TIMEOUT + 1 + 300));
+
+        // not again within the cooldown without new I/O
+        assert!(run_for(&harness, &mut daemon, 3000).is_empty());
+    }
+
+    #[test]
+    fn inhibited_daemon_neither_puts_disks_to_sleep_nor_suspends() {
+        let harness = Harness::new("daemon-inhibited");
+        let suspend_gate = SuspendGate::new(Duration::ZERO, Duration::ZERO, Duration::ZERO);
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::Standby);
+        let mut daemon = daemon(&harness, Some(suspend_gate));
+        daemon.context.inhibited = true;
+        assert!(run_for(&harness, &mut daemon, 2 * TIMEOUT).is_empty());
+
+        daemon.context.inhibited = false;
+        assert_eq!(next_tick(&harness, &mut daemon), vec![Action::Suspend]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,1009 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{IoSliceMut, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixAddr};
+use nix::unistd::getuid;
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::control::{ControlCommand, ControlReply, ControlRequest};
+use crate::spindown_daemon::signals;
+
+const SYSTEM_BUS: &str = "unix:path=/run/dbus/system_bus_socket";
+const SERVICE_NAME: &str = "org.spindown.Daemon";
+const OBJECT_PATH: &str = "/org/spindown/Daemon";
+const INTERFACE: &str = "org.spindown.Daemon";
+const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+const LOGIN_NAME: &str = "org.freedesktop.login1";
+const LOGIN_PATH: &str = "/org/freedesktop/login1";
+const LOGIN_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.spindown.Daemon">
+    <method name="Standby">
+      <arg name="device" type="s" direction="in"/>
+    </method>
+    <property name="Devices" type="a(ss)" access="read"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="property" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+// the Standby calls waiting for the main loop are answered in between
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+const CALL_TIMEOUT: Duration = Duration::from_secs(25);
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+// RequestName flag, fail instead of waiting for the current owner to go away
+const DO_NOT_QUEUE: u32 = 4;
+const PRIMARY_OWNER: u32 = 1;
+const ALREADY_OWNER: u32 = 4;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+// containers (arrays, structs and variants) within each other, see the limits of the specification
+const MAX_NESTING: usize = 64;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+const FIELD_UNIX_FDS: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepEvent {
+    // logind waits for the sleep lock to be released before suspending
+    Suspending,
+    Resumed,
+}
+
+/// Provides org.spindown.Daemon on the system bus and follows the PrepareForSleep signal of
+/// logind, holding a delay lock so the disks can be put to sleep before the system suspends.
+/// The bus is spoken to directly, there are few messages and no need for libdbus.
+pub struct DbusService {
+    requests: Receiver<ControlRequest>,
+    events: Receiver<SleepEvent>,
+    devices: Arc<Mutex<Vec<(String, String)>>>,
+    sleep_lock: Arc<Mutex<Option<OwnedFd>>>,
+}
+
+impl DbusService {
+    pub fn connect() -> Result<DbusService, String> {
+        let mut connection = Connection::open()?;
+        connection.call(Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "Hello", "", vec![]))?;
+        let mut body = Writer::default();
+        body.string(SERVICE_NAME);
+        body.u32(DO_NOT_QUEUE);
+        let reply = connection.call(Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "RequestName", "su",
+                                                         body.data))?;
+        match reply.reader().u32()? {
+            PRIMARY_OWNER | ALREADY_OWNER => {}
+            _ => return Err(format!("{} is already taken by another process", SERVICE_NAME)),
+        }
+        let mut body = Writer::default();
+        body.string(&format!("type='signal',sender='{}',interface='{}',member='PrepareForSleep'",
+                             LOGIN_NAME, LOGIN_INTERFACE));
+        connection.call(Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "AddMatch", "s", body.data))?;
+
+        let (request_sender, requests) = channel();
+        let (event_sender, events) = channel();
+        let service = DbusService {
+            requests,
+            events,
+            devices: Arc::new(Mutex::new(vec![])),
+            sleep_lock: Arc::new(Mutex::new(None)),
+        };
+        let mut server = Server {
+            connection,
+            requests: request_sender,
+            events: event_sender,
+            devices: service.devices.clone(),
+            sleep_lock: service.sleep_lock.clone(),
+            inhibit_serial: None,
+            pending: vec![],
+        };
+        thread::Builder::new()
+            .name("dbus".to_string())
+            .spawn(move || server.run())
+            .map_err(|e| e.to_string())?;
+        Ok(service)
+    }
+
+    /// Returns the Standby calls received since the last call
+    pub fn requests(&self) -> Vec<ControlRequest> {
+        self.requests.try_iter().collect()
+    }
+
+    /// Returns the PrepareForSleep signals received since the last call
+    pub fn sleep_events(&self) -> Vec<SleepEvent> {
+        self.events.try_iter().collect()
+    }
+
+    /// Takes over the devices and their power states for the Devices property
+    pub fn update(&self, devices: &[Box<DeviceInfo>]) {
+        *self.devices.lock().unwrap() = devices.iter()
+            .map(|dev| (dev.name.clone(), dev.power_state.to_string()))
+            .collect();
+    }
+
+    /// Lets logind suspend the system, the lock is taken again after the resume
+    pub fn release_sleep_lock(&self) {
+        if self.sleep_lock.lock().unwrap().take().is_some() {
+            log::debug!("released the sleep delay lock");
+        }
+    }
+}
+
+struct Server {
+    connection: Connection,
+    requests: Sender<ControlRequest>,
+    events: Sender<SleepEvent>,
+    devices: Arc<Mutex<Vec<(String, String)>>>,
+    sleep_lock: Arc<Mutex<Option<OwnedFd>>>,
+    // the Inhibit call waiting for its reply
+    inhibit_serial: Option<u32>,
+    // Standby calls waiting for the main loop
+    pending: Vec<(Message, Receiver<ControlReply>)>,
+}
+
+impl Server {
+    fn run(&mut self) {
+        self.take_sleep_lock();
+        loop {
+            match self.connection.receive() {
+                Ok(Some(message)) => self.handle(message),
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("lost the connection to the system bus: {}", e);
+                    return;
+                }
+            }
+            self.answer_pending();
+        }
+    }
+
+    fn take_sleep_lock(&mut self) {
+        let mut body = Writer::default();
+        body.string("sleep");
+        body.string("spindown-daemon");
+        body.string("Put the disks to sleep before suspending");
+        body.string("delay");
+        let call = Message::method_call(LOGIN_NAME, LOGIN_PATH, LOGIN_INTERFACE, "Inhibit", "ssss", body.data);
+        match self.connection.send(&call) {
+            Ok(serial) => self.inhibit_serial = Some(serial),
+            Err(e) => log::warn!("unable to take a sleep delay lock: {}", e),
+        }
+    }
+
+    fn handle(&mut self, mut message: Message) {
+        match message.kind {
+            METHOD_RETURN if message.reply_serial.is_some() && message.reply_serial == self.inhibit_serial => {
+                self.inhibit_serial = None;
+                match message.fds.pop() {
+                    Some(fd) => {
+                        log::debug!("took a sleep delay lock");
+                        *self.sleep_lock.lock().unwrap() = Some(fd);
+                    }
+                    None => log::warn!("unable to take a sleep delay lock: no file descriptor received"),
+                }
+            }
+            ERROR if message.reply_serial.is_some() && message.reply_serial == self.inhibit_serial => {
+                self.inhibit_serial = None;
+                log::warn!("unable to take a sleep delay lock: {}", message.error_text());
+            }
+            SIGNAL if message.interface.as_deref() == Some(LOGIN_INTERFACE) &&
+                message.member.as_deref() == Some("PrepareForSleep") => {
+                let going_to_sleep = match message.reader().boolean() {
+                    Ok(going_to_sleep) => going_to_sleep,
+                    Err(e) => {
+                        log::warn!("invalid PrepareForSleep signal: {}", e);
+                        return;
+                    }
+                };
+                if going_to_sleep {
+                    let _ = self.events.send(SleepEvent::Suspending);
+                } else {
+                    let _ = self.events.send(SleepEvent::Resumed);
+                    self.take_sleep_lock();
+                }
+                signals::wake();
+            }
+            METHOD_CALL => {
+                if let Some(reply) = self.handle_call(&message) {
+                    self.reply(&message, reply);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Returns the reply unless the call waits for the main loop
+    fn handle_call(&mut self, call: &Message) -> Option<Message> {
+        let member = call.member.as_deref().unwrap_or_default();
+        if call.interface.as_deref() == Some("org.freedesktop.DBus.Peer") && member == "Ping" {
+            return Some(Message::method_return(call, "", vec![]));
+        }
+        if call.path.as_deref() != Some(OBJECT_PATH) {
+            return Some(Message::error(call, "org.freedesktop.DBus.Error.UnknownObject",
+                                       &format!("no object {}", call.path.as_deref().unwrap_or_default())));
+        }
+        let result = match (call.interface.as_deref(), member) {
+            (Some("org.freedesktop.DBus.Introspectable"), "Introspect") => {
+                let mut body = Writer::default();
+                body.string(INTROSPECTION);
+                Ok(Message::method_return(call, "s", body.data))
+            }
+            (Some(PROPERTIES_INTERFACE), "Get") => {
+                let mut reader = call.reader();
+                match (reader.string(), reader.string()) {
+                    (Ok(interface), Ok(property)) if interface == INTERFACE && property == "Devices" => {
+                        let mut body = Writer::default();
+                        body.signature("a(ss)");
+                        self.write_devices(&mut body);
+                        Ok(Message::method_return(call, "v", body.data))
+                    }
+                    (Ok(interface), Ok(property)) => Err(("org.freedesktop.DBus.Error.UnknownProperty",
+                                                          format!("no property {}.{}", interface, property))),
+                    (Err(e), _) | (_, Err(e)) => Err(("org.freedesktop.DBus.Error.InvalidArgs", e)),
+                }
+            }
+            (Some(PROPERTIES_INTERFACE), "GetAll") => {
+                let mut body = Writer::default();
+                body.array(8, |body| {
+                    if call.reader().string().is_ok_and(|interface| interface == INTERFACE) {
+                        body.align(8);
+                        body.string("Devices");
+                        body.signature("a(ss)");
+                        self.write_devices(body);
+                    }
+                });
+                Ok(Message::method_return(call, "a{sv}", body.data))
+            }
+            (Some(INTERFACE) | None, "Standby") => match call.reader().string() {
+                Ok(device) => {
+                    let (request, answer) = ControlRequest::new(ControlCommand::Standby(device));
+                    if self.requests.send(request).is_ok() {
+                        signals::wake();
+                        self.pending.push((call.call_info(), answer));
+                        return None;
+                    }
+                    Err(("org.freedesktop.DBus.Error.Failed", "the daemon is shutting down".to_string()))
+                }
+                Err(e) => Err(("org.freedesktop.DBus.Error.InvalidArgs", e)),
+            },
+            _ => Err(("org.freedesktop.DBus.Error.UnknownMethod", format!("no method {}", member))),
+        };
+        Some(result.unwrap_or_else(|(name, text)| Message::error(call, name, &text)))
+    }
+
+    fn write_devices(&self, writer: &mut Writer) {
+        let devices = self.devices.lock().unwrap();
+        writer.array(8, |writer| {
+            for (name, power_state) in devices.iter() {
+                writer.align(8);
+                writer.string(name);
+                writer.string(power_state);
+            }
+        });
+    }
+
+    fn answer_pending(&mut self) {
+        let mut answered: Vec<(Message, Message)> = vec![];
+        self.pending.retain(|(call, answer)| {
+            let reply = match answer.try_recv() {
+                Ok(Ok(_)) => Message::method_return(call, "", vec![]),
+                Ok(Err(e)) => Message::error(call, "org.spindown.Daemon.Error.Failed", &e),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => {
+                    Message::error(call, "org.freedesktop.DBus.Error.Failed", "the daemon is shutting down")
+                }
+            };
+            answered.push((call.call_info(), reply));
+            false
+        });
+        for (call, reply) in answered {
+            self.reply(&call, reply);
+        }
+    }
+
+    fn reply(&mut self, call: &Message, reply: Message) {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return;
+        }
+        if let Err(e) = self.connection.send(&reply) {
+            log::debug!("unable to answer D-Bus call: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    signature: String,
+    unix_fds: u32,
+    big_endian: bool,
+    body: Vec<u8>,
+    // received along with the message
+    fds: Vec<OwnedFd>,
+}
+
+impl Message {
+    fn method_call(destination: &str, path: &str, interface: &str, member: &str, signature: &str,
+                   body: Vec<u8>) -> Message {
+        Message {
+            kind: METHOD_CALL,
+            path: Some(path.to_string()),
+            interface: Some(interface.to_string()),
+            member: Some(member.to_string()),
+            destination: Some(destination.to_string()),
+            signature: signature.to_string(),
+            body,
+            ..Message::default()
+        }
+    }
+
+    fn method_return(call: &Message, signature: &str, body: Vec<u8>) -> Message {
+        Message {
+            kind: METHOD_RETURN,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            signature: signature.to_string(),
+            body,
+            ..Message::default()
+        }
+    }
+
+    fn error(call: &Message, name: &str, text: &str) -> Message {
+        let mut body = Writer::default();
+        body.string(text);
+        Message {
+            kind: ERROR,
+            error_name: Some(name.to_string()),
+            ..Message::method_return(call, "s", body.data)
+        }
+    }
+
+    // what a reply needs to know about the call
+    fn call_info(&self) -> Message {
+        Message {
+            kind: self.kind,
+            flags: self.flags,
+            serial: self.serial,
+            sender: self.sender.clone(),
+            ..Message::default()
+        }
+    }
+
+    fn reader(&self) -> Reader<'_> {
+        Reader { data: &self.body, position: 0, big_endian: self.big_endian }
+    }
+
+    fn error_text(&self) -> String {
+        let name = self.error_name.clone().unwrap_or_default();
+        match self.reader().string() {
+            Ok(text) if self.signature.starts_with('s') => format!("{}: {}", name, text),
+            _ => name,
+        }
+    }
+
+    fn encode(&self, serial: u32) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.byte(b'l');
+        writer.byte(self.kind);
+        writer.byte(self.flags);
+        writer.byte(1);
+        writer.u32(self.body.len() as u32);
+        writer.u32(serial);
+        let strings = [
+            (FIELD_PATH, "o", &self.path),
+            (FIELD_INTERFACE, "s", &self.interface),
+            (FIELD_MEMBER, "s", &self.member),
+            (FIELD_ERROR_NAME, "s", &self.error_name),
+            (FIELD_DESTINATION, "s", &self.destination),
+        ];
+        writer.array(8, |writer| {
+            for (code, signature, value) in strings.iter() {
+                if let Some(value) = value {
+                    writer.align(8);
+                    writer.byte(*code);
+                    writer.signature(signature);
+                    writer.string(value);
+                }
+            }
+            if let Some(reply_serial) = self.reply_serial {
+                writer.align(8);
+                writer.byte(FIELD_REPLY_SERIAL);
+                writer.signature("u");
+                writer.u32(reply_serial);
+            }
+            if !self.signature.is_empty() {
+                writer.align(8);
+                writer.byte(FIELD_SIGNATURE);
+                writer.signature("g");
+                writer.signature(&self.signature);
+            }
+        });
+        writer.align(8);
+        writer.data.extend_from_slice(&self.body);
+        writer.data
+    }
+
+    // The fixed part of the header is followed by an array of (code, variant) fields and,
+    // aligned to 8 bytes, the body
+    fn decode(data: &[u8]) -> Result<Message, String> {
+        let big_endian = match data.first() {
+            Some(b'l') => false,
+            Some(b'B') => true,
+            _ => return Err("invalid byte order".to_string()),
+        };
+        let mut reader = Reader { data, position: 0, big_endian };
+        let mut message = Message { big_endian, ..Message::default() };
+        reader.byte()?;
+        message.kind = reader.byte()?;
+        message.flags = reader.byte()?;
+        reader.byte()?;
+        let body_length = reader.u32()? as usize;
+        message.serial = reader.u32()?;
+        let fields_end = reader.u32()? as usize + reader.position;
+        while reader.position < fields_end {
+            reader.align(8)?;
+            let code = reader.byte()?;
+            let signature = reader.signature()?;
+            match (code, signature.as_str()) {
+                (FIELD_PATH, "o") => message.path = Some(reader.string()?),
+                (FIELD_INTERFACE, "s") => message.interface = Some(reader.string()?),
+                (FIELD_MEMBER, "s") => message.member = Some(reader.string()?),
+                (FIELD_ERROR_NAME, "s") => message.error_name = Some(reader.string()?),
+                (FIELD_REPLY_SERIAL, "u") => message.reply_serial = Some(reader.u32()?),
+                (FIELD_DESTINATION, "s") => message.destination = Some(reader.string()?),
+                (FIELD_SENDER, "s") => message.sender = Some(reader.string()?),
+                (FIELD_SIGNATURE, "g") => message.signature = reader.signature()?,
+                (FIELD_UNIX_FDS, "u") => message.unix_fds = reader.u32()?,
+                // fields added to the protocol later have to be ignored
+                _ => reader.skip(&signature, 0)?,
+            }
+        }
+        reader.align(8)?;
+        message.body = reader.take(body_length)?.to_vec();
+        Ok(message)
+    }
+
+    // Length of the first message in the buffer, once its fixed header was received
+    fn length(data: &[u8]) -> Option<usize> {
+        if data.len() < 16 {
+            return None;
+        }
+        let mut reader = Reader { data, position: 4, big_endian: data[0] == b'B' };
+        let body_length = reader.u32().ok()? as usize;
+        reader.u32().ok()?;
+        let fields_length = reader.u32().ok()? as usize;
+        Some((16 + fields_length).div_ceil(8) * 8 + body_length)
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        while !self.data.len().is_multiple_of(alignment) {
+            self.data.push(0);
+        }
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.byte(value.len() as u8);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+    }
+
+    // the length in bytes precedes the elements, excluding the padding before the first one
+    fn array(&mut self, element_alignment: usize, write: impl FnOnce(&mut Writer)) {
+        self.u32(0);
+        let length_at = self.data.len() - 4;
+        self.align(element_alignment);
+        let start = self.data.len();
+        write(self);
+        let length = (self.data.len() - start) as u32;
+        self.data[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, alignment: usize) -> Result<(), String> {
+        self.position = self.position.div_ceil(alignment) * alignment;
+        if self.position > self.data.len() {
+            return Err("message too short".to_string());
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let data = self.data.get(self.position..self.position + length).ok_or("message too short")?;
+        self.position += length;
+        Ok(data)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.align(4)?;
+        let bytes: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn boolean(&mut self) -> Result<bool, String> {
+        Ok(self.u32()? != 0)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        let value = String::from_utf8_lossy(self.take(length)?).to_string();
+        self.take(1)?;
+        Ok(value)
+    }
+
+    fn signature(&mut self) -> Result<String, String> {
+        let length = self.byte()? as usize;
+        let value = String::from_utf8_lossy(self.take(length)?).to_string();
+        self.take(1)?;
+        Ok(value)
+    }
+
+    // Skips the values of a signature, `depth` counts the containers around them
+    fn skip(&mut self, signature: &str, depth: usize) -> Result<(), String> {
+        let mut types = signature.as_bytes();
+        while !types.is_empty() {
+            types = self.skip_type(types, depth)?;
+        }
+        Ok(())
+    }
+
+    // Skips a value of the first complete type and returns the types after it
+    fn skip_type<'t>(&mut self, types: &'t [u8], depth: usize) -> Result<&'t [u8], String> {
+        if depth > MAX_NESTING {
+            return Err("values nested too deeply".to_string());
+        }
+        let length = type_length(types)?;
+        match types[0] {
+            b'y' => {
+                self.take(1)?;
+            }
+            b'n' | b'q' => {
+                self.align(2)?;
+                self.take(2)?;
+            }
+            b'b' | b'i' | b'u' | b'h' => {
+                self.u32()?;
+            }
+            b'x' | b't' | b'd' => {
+                self.align(8)?;
+                self.take(8)?;
+            }
+            b's' | b'o' => {
+                self.string()?;
+            }
+            b'g' => {
+                self.signature()?;
+            }
+            b'v' => {
+                let signature = self.signature()?;
+                self.skip(&signature, depth + 1)?;
+            }
+            // the elements are skipped by the length of the array
+            b'a' => {
+                let array_length = self.u32()? as usize;
+                self.align(alignment(types[1]))?;
+                self.take(array_length)?;
+            }
+            b'(' | b'{' => {
+                self.align(8)?;
+                let mut members = &types[1..length - 1];
+                while !members.is_empty() {
+                    members = self.skip_type(members, depth + 1)?;
+                }
+            }
+            code => return Err(format!("invalid type {} in signature", code as char)),
+        }
+        Ok(&types[length..])
+    }
+}
+
+// Length of the first complete type of a signature
+fn type_length(types: &[u8]) -> Result<usize, String> {
+    match types.first() {
+        Some(b'a') => Ok(1 + type_length(&types[1..])?),
+        Some(b'(') | Some(b'{') => {
+            let mut depth = 0;
+            for (index, code) in types.iter().enumerate() {
+                match code {
+                    b'(' | b'{' => depth += 1,
+                    b')' | b'}' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    return Ok(index + 1);
+                }
+            }
+            Err("unbalanced signature".to_string())
+        }
+        Some(_) => Ok(1),
+        None => Err("incomplete signature".to_string()),
+    }
+}
+
+// Alignment of the values of a type, by its first code
+fn alignment(code: u8) -> usize {
+    match code {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'x' | b't' | b'd' | b'(' | b'{' => 8,
+        _ => 4,
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+    serial: u32,
+    // received bytes not yet making up a whole message
+    incoming: Vec<u8>,
+    fds: VecDeque<OwnedFd>,
+    // messages received while waiting for the reply of a call
+    backlog: VecDeque<Message>,
+}
+
+impl Connection {
+    fn open() -> Result<Connection, String> {
+        let address = std::env::var("DBUS_SYSTEM_BUS_ADDRESS").unwrap_or_else(|_| SYSTEM_BUS.to_string());
+        // unix:path=/run/dbus/system_bus_socket[,guid=...], several addresses are separated by `;`
+        let path = address.split(';')
+            .filter_map(|address| address.strip_prefix("unix:"))
+            .flat_map(|keys| keys.split(','))
+            .find_map(|key| key.strip_prefix("path="))
+            .ok_or_else(|| format!("unsupported D-Bus address {}", address))?;
+        let stream = UnixStream::connect(path).map_err(|e| format!("unable to connect to {}: {}", path, e))?;
+        stream.set_read_timeout(Some(RECEIVE_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut connection = Connection {
+            stream,
+            serial: 0,
+            incoming: vec![],
+            fds: VecDeque::new(),
+            backlog: VecDeque::new(),
+        };
+        connection.authenticate().map_err(|e| format!("unable to authenticate to {}: {}", path, e))?;
+        Ok(connection)
+    }
+
+    // SASL EXTERNAL, the bus knows the uid of the socket peer; file descriptors are
+    // needed for the sleep lock
+    fn authenticate(&mut self) -> Result<(), String> {
+        let uid: String = getuid().to_string().bytes().map(|byte| format!("{:02x}", byte)).collect();
+        self.stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", uid).as_bytes()).map_err(|e| e.to_string())?;
+        let response = self.read_line()?;
+        if !response.starts_with("OK ") {
+            return Err(format!("unexpected response `{}`", response));
+        }
+        self.stream.write_all(b"NEGOTIATE_UNIX_FD\r\n").map_err(|e| e.to_string())?;
+        let response = self.read_line()?;
+        if response != "AGREE_UNIX_FD" {
+            return Err(format!("passing file descriptors is not supported: `{}`", response));
+        }
+        self.stream.write_all(b"BEGIN\r\n").map_err(|e| e.to_string())
+    }
+
+    // the server only answers, so nothing is read past the line
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line: Vec<u8> = vec![];
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err("connection closed".to_string()),
+                Ok(_) => line.push(byte[0]),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        line.truncate(line.len() - 2);
+        Ok(String::from_utf8_lossy(&line).to_string())
+    }
+
+    fn send(&mut self, message: &Message) -> Result<u32, String> {
+        self.serial += 1;
+        self.stream.write_all(&message.encode(self.serial)).map_err(|e| e.to_string())?;
+        Ok(self.serial)
+    }
+
+    // Sends a call and waits for its reply
+    fn call(&mut self, message: Message) -> Result<Message, String> {
+        let member = message.member.clone().unwrap_or_default();
+        let serial = self.send(&message)?;
+        let started = Instant::now();
+        while started.elapsed() < CALL_TIMEOUT {
+            let reply = match self.read_message()? {
+                Some(reply) => reply,
+                None => continue,
+            };
+            if reply.reply_serial != Some(serial) {
+                self.backlog.push_back(reply);
+            } else if reply.kind == ERROR {
+                return Err(format!("{} failed: {}", member, reply.error_text()));
+            } else {
+                return Ok(reply);
+            }
+        }
+        Err(format!("no reply to {}", member))
+    }
+
+    /// Returns the next message, None if there was none within the receive timeout
+    fn receive(&mut self) -> Result<Option<Message>, String> {
+        match self.backlog.pop_front() {
+            Some(message) => Ok(Some(message)),
+            None => self.read_message(),
+        }
+    }
+
+    fn read_message(&mut self) -> Result<Option<Message>, String> {
+        loop {
+            if let Some(length) = Message::length(&self.incoming).filter(|length| self.incoming.len() >= *length) {
+                let data: Vec<u8> = self.incoming.drain(..length).collect();
+                // the length was valid, so the next message starts right after this one
+                let mut message = match Message::decode(&data) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        log::warn!("ignoring an invalid D-Bus message: {}", e);
+                        continue;
+                    }
+                };
+                for _ in 0..message.unix_fds {
+                    message.fds.extend(self.fds.pop_front());
+                }
+                return Ok(Some(message));
+            }
+            let mut buffer = vec![0u8; RECEIVE_BUFFER_SIZE];
+            let mut cmsg_buffer = cmsg_space!([i32; 4]);
+            let received = {
+                let mut iov = [IoSliceMut::new(&mut buffer)];
+                recvmsg::<UnixAddr>(self.stream.as_raw_fd(), &mut iov, Some(&mut cmsg_buffer), MsgFlags::MSG_CMSG_CLOEXEC)
+                    .map(|received| {
+                        for cmsg in received.cmsgs() {
+                            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                                // received descriptors are owned by the daemon now
+                                self.fds.extend(fds.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+                            }
+                        }
+                        received.bytes
+                    })
+            };
+            match received {
+                Ok(0) => return Err("connection closed".to_string()),
+                Ok(length) => self.incoming.extend_from_slice(&buffer[..length]),
+                Err(Errno::EAGAIN) | Err(Errno::EINTR) => return Ok(None),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepare_for_sleep(going_to_sleep: bool) -> Message {
+        let mut body = Writer::default();
+        body.u32(going_to_sleep as u32);
+        Message {
+            kind: SIGNAL,
+            path: Some(LOGIN_PATH.to_string()),
+            interface: Some(LOGIN_INTERFACE.to_string()),
+            member: Some("PrepareForSleep".to_string()),
+            signature: "b".to_string(),
+            body: body.data,
+            ..Message::default()
+        }
+    }
+
+    // big-endian values, as a writer of the other byte order sends them
+    fn push_u32(data: &mut Vec<u8>, value: u32) {
+        while !data.len().is_multiple_of(4) {
+            data.push(0);
+        }
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn push_string(data: &mut Vec<u8>, value: &str) {
+        push_u32(data, value.len() as u32);
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+
+    fn push_signature(data: &mut Vec<u8>, value: &str) {
+        data.push(value.len() as u8);
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+    }
+
+    fn pad(data: &mut Vec<u8>) {
+        while !data.len().is_multiple_of(8) {
+            data.push(0);
+        }
+    }
+
+    #[test]
+    fn method_call_survives_encoding() {
+        let mut body = Writer::default();
+        body.string("sleep");
+        body.string("spindown-daemon");
+        let call = Message::method_call(LOGIN_NAME, LOGIN_PATH, LOGIN_INTERFACE, "Inhibit", "ss", body.data.clone());
+        let data = call.encode(7);
+        assert_eq!(Message::length(&data), Some(data.len()));
+
+        let decoded = Message::decode(&data).unwrap();
+        assert_eq!(decoded.kind, METHOD_CALL);
+        assert_eq!(decoded.serial, 7);
+        assert_eq!(decoded.destination.as_deref(), Some(LOGIN_NAME));
+        assert_eq!(decoded.path.as_deref(), Some(LOGIN_PATH));
+        assert_eq!(decoded.interface.as_deref(), Some(LOGIN_INTERFACE));
+        assert_eq!(decoded.member.as_deref(), Some("Inhibit"));
+        assert_eq!(decoded.signature, "ss");
+        assert_eq!(decoded.body, body.data);
+        let mut reader = decoded.reader();
+        assert_eq!(reader.string().unwrap(), "sleep");
+        assert_eq!(reader.string().unwrap(), "spindown-daemon");
+    }
+
+    #[test]
+    fn signal_survives_encoding() {
+        for going_to_sleep in [true, false] {
+            let data = prepare_for_sleep(going_to_sleep).encode(3);
+            let decoded = Message::decode(&data).unwrap();
+            assert_eq!(decoded.kind, SIGNAL);
+            assert_eq!(decoded.interface.as_deref(), Some(LOGIN_INTERFACE));
+            assert_eq!(decoded.member.as_deref(), Some("PrepareForSleep"));
+            assert_eq!(decoded.reader().boolean().unwrap(), going_to_sleep);
+        }
+    }
+
+    #[test]
+    fn big_endian_message_is_decoded() {
+        let mut data = vec![b'B', SIGNAL, 0, 1];
+        push_u32(&mut data, 4);
+        push_u32(&mut data, 9);
+        let mut fields = vec![];
+        fields.push(FIELD_MEMBER);
+        push_signature(&mut fields, "s");
+        push_string(&mut fields, "PrepareForSleep");
+        pad(&mut fields);
+        fields.push(FIELD_REPLY_SERIAL);
+        push_signature(&mut fields, "u");
+        push_u32(&mut fields, 0x01020304);
+        pad(&mut fields);
+        fields.push(FIELD_SIGNATURE);
+        push_signature(&mut fields, "g");
+        push_signature(&mut fields, "b");
+        push_u32(&mut data, fields.len() as u32);
+        // the fields start at offset 16, which keeps their alignment
+        data.extend_from_slice(&fields);
+        pad(&mut data);
+        push_u32(&mut data, 1);
+        assert_eq!(Message::length(&data), Some(data.len()));
+
+        let decoded = Message::decode(&data).unwrap();
+        assert_eq!(decoded.serial, 9);
+        assert_eq!(decoded.member.as_deref(), Some("PrepareForSleep"));
+        assert_eq!(decoded.reply_serial, Some(0x01020304));
+        assert_eq!(decoded.signature, "b");
+        assert!(decoded.reader().boolean().unwrap());
+    }
+
+    #[test]
+    fn truncated_message_is_an_error() {
+        let data = prepare_for_sleep(true).encode(1);
+        for length in 0..data.len() {
+            assert!(Message::decode(&data[..length]).is_err(), "decoded {} of {} bytes", length, data.len());
+        }
+        assert!(Message::decode(b"x").is_err());
+    }
+
+    #[test]
+    fn unknown_header_field_is_skipped() {
+        let mut writer = Writer::default();
+        writer.byte(b'l');
+        writer.byte(SIGNAL);
+        writer.byte(0);
+        writer.byte(1);
+        writer.u32(4);
+        writer.u32(5);
+        writer.array(8, |writer| {
+            writer.align(8);
+            writer.byte(42);
+            writer.signature("a(sv)");
+            writer.array(8, |writer| {
+                writer.align(8);
+                writer.string("key");
+                writer.signature("t");
+                writer.align(8);
+                writer.data.extend_from_slice(&[0; 8]);
+            });
+            writer.align(8);
+            writer.byte(FIELD_MEMBER);
+            writer.signature("s");
+            writer.string("PrepareForSleep");
+            writer.align(8);
+            writer.byte(FIELD_SIGNATURE);
+            writer.signature("g");
+            writer.signature("b");
+        });
+        writer.align(8);
+        writer.u32(1);
+
+        let decoded = Message::decode(&writer.data).unwrap();
+        assert_eq!(decoded.member.as_deref(), Some("PrepareForSleep"));
+        assert!(decoded.reader().boolean().unwrap());
+    }
+}
\ No newline at end of file
@@ -1,13 +1,105 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use nix::libc::{EACCES, EBUSY, EPERM};
+
+use crate::spindown_daemon::status::format_duration;
+
+// repetitions of an error are summarized at most this often
+const REPEAT_SUMMARY_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceErrorKind {
+    // another process holds the device, e.g. hdparm or smartctl
+    Busy,
+    // the daemon does not manage the device, e.g. the disk holding the root filesystem
+    Refused,
+    // no access to the device node, or SG_IO without CAP_SYS_RAWIO
+    PermissionDenied,
+    Other,
+}
+
 pub struct DeviceError {
     pub filepath: String,
     pub message: String,
+    pub kind: DeviceErrorKind,
 }
 
 impl DeviceError {
     pub fn new(filepath: String, message: String) -> DeviceError {
-        return DeviceError {
+        DeviceError {
             filepath,
             message,
-        };
+            kind: DeviceErrorKind::Other,
+        }
+    }
+
+    pub fn from_io(filepath: String, error: &io::Error) -> DeviceError {
+        match error.raw_os_error() {
+            Some(EBUSY) => DeviceError {
+                filepath,
+                message: format!("{} (in use by another process?)", error),
+                kind: DeviceErrorKind::Busy,
+            },
+            Some(EACCES) | Some(EPERM) => DeviceError {
+                message: format!("insufficient permissions to access {}; run as root or grant CAP_SYS_RAWIO ({})",
+                                 filepath, error),
+                filepath,
+                kind: DeviceErrorKind::PermissionDenied,
+            },
+            _ => DeviceError::new(filepath, error.to_string()),
+        }
+    }
+}
+
+/// Logs the first of a series of identical errors of a device and only counts the
+/// repetitions, so a failing device does not flood the journal every check. The count is
+/// logged every hour, when the error changes and when the device recovers.
+#[derive(Debug)]
+pub struct ErrorLog {
+    message: Option<String>,
+    repeated: u64,
+    summarized: Instant,
+}
+
+impl ErrorLog {
+    pub fn new(now: Instant) -> ErrorLog {
+        ErrorLog { message: None, repeated: 0, summarized: now }
+    }
+
+    pub fn error(&mut self, name: &str, message: &str, now: Instant) {
+        if self.message.as_deref() == Some(message) {
+            self.repeated += 1;
+            if now.saturating_duration_since(self.summarized) >= REPEAT_SUMMARY_INTERVAL {
+                self.summarize(name, now);
+            }
+            return;
+        }
+        self.summarize(name, now);
+        log::warn!("{}", message);
+        self.message = Some(message.to_string());
+    }
+
+    pub fn recovered(&mut self, name: &str, now: Instant) {
+        if self.message.is_some() {
+            self.summarize(name, now);
+            log::warn!("{} recovered", name);
+            self.message = None;
+        }
+    }
+
+    /// Forgets the last error after summarizing its repetitions, so it is logged again
+    pub fn reset(&mut self, name: &str, now: Instant) {
+        self.summarize(name, now);
+        self.message = None;
+    }
+
+    fn summarize(&mut self, name: &str, now: Instant) {
+        if self.repeated > 0 {
+            log::warn!("{}: error repeated {} times in the last {}", name, self.repeated,
+                       format_duration(now.saturating_duration_since(self.summarized).as_secs()));
+        }
+        self.repeated = 0;
+        self.summarized = now;
     }
 }
\ No newline at end of file
@@ -0,0 +1,73 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::events::{escape_json, unix_time, Event, EventKind};
+
+// version of the line format, fields are only ever added within a version
+const SCHEMA_VERSION: u32 = 1;
+
+/// Appends one JSON object per line for later analysis, in addition to the human log.
+/// Each line is flushed right away, so the file is complete even after a crash.
+pub struct EventLog {
+    path: String,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl EventLog {
+    pub fn open(path: &str) -> Result<EventLog, String> {
+        Ok(EventLog { path: path.to_string(), writer: Mutex::new(open_append(path, "event log")?) })
+    }
+
+    /// Opens the file again, e.g. after logrotate moved it away (SIGHUP)
+    pub fn reopen(&self) {
+        match open_append(&self.path, "event log") {
+            Ok(writer) => {
+                *self.writer.lock().unwrap() = writer;
+                log::info!("reopened event log {}", self.path);
+            }
+            Err(e) => println!("{}, still writing to the previous file", e),
+        }
+    }
+
+    /// Logs a standby, wake or error event with the state and counters of the device
+    pub fn device_event(&self, event: &Event, dev: &DeviceInfo, now: Instant) {
+        let name = match event.kind {
+            EventKind::Standby => "standby_issued",
+            EventKind::Wake => "wake_detected",
+            EventKind::Error => "error",
+        };
+        let mut line = format!("{{\"v\":{},\"ts\":{},\"event\":\"{}\",\"device\":\"{}\",\"power_state\":\"{}\",\
+                                \"idle_seconds\":{},\"read_iops\":{},\"write_iops\":{},\"standby_count\":{},\
+                                \"wake_count\":{},\"error_count\":{}",
+                               SCHEMA_VERSION, event.ts, name, escape_json(&event.device), dev.power_state,
+                               dev.idle_seconds(now), dev.last_read_iops, dev.last_write_iops,
+                               dev.statistics.standby_count, dev.statistics.wake_count, dev.statistics.error_count);
+        if let Some(message) = &event.message {
+            line.push_str(&format!(",\"message\":\"{}\"", escape_json(message)));
+        }
+        line.push('}');
+        self.write(&line);
+    }
+
+    /// Logs an event of the daemon itself, e.g. startup, shutdown or suspend
+    pub fn daemon_event(&self, name: &str) {
+        self.write(&format!("{{\"v\":{},\"ts\":{},\"event\":\"{}\",\"device\":null}}", SCHEMA_VERSION, unix_time(), name));
+    }
+
+    fn write(&self, line: &str) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+            log::warn!("unable to write to event log {}: {}", self.path, e);
+        }
+    }
+}
+
+/// Opens `path` for appending, creating it if missing, `what` names the file in the error
+pub(crate) fn open_append(path: &str, what: &str) -> Result<BufWriter<File>, String> {
+    OpenOptions::new().create(true).append(true).open(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("unable to open {} {}: {}", what, path, e))
+}
\ No newline at end of file
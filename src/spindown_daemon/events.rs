@@ -0,0 +1,168 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::ata::PowerTarget;
+use crate::spindown_daemon::event_log::EventLog;
+use crate::spindown_daemon::hooks::Hooks;
+use crate::spindown_daemon::journald::Journal;
+#[cfg(feature = "mqtt")]
+use crate::spindown_daemon::mqtt::MqttPublisher;
+use crate::spindown_daemon::webhook::Webhook;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Standby,
+    Wake,
+    Error,
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EventKind::Standby => write!(f, "standby"),
+            EventKind::Wake => write!(f, "wake"),
+            EventKind::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standby" => Ok(EventKind::Standby),
+            "wake" => Ok(EventKind::Wake),
+            "error" => Ok(EventKind::Error),
+            _ => Err(format!("unknown event `{}`, expected standby, wake or error", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub device: String,
+    pub message: Option<String>,
+    // seconds since the unix epoch
+    pub ts: u64,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, device: &str) -> Event {
+        Event {
+            kind,
+            device: device.to_string(),
+            message: None,
+            ts: unix_time(),
+        }
+    }
+
+    pub fn error(device: &str, message: &str) -> Event {
+        let mut event = Event::new(EventKind::Error, device);
+        event.message = Some(message.to_string());
+        event
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"event\":\"{}\",\"device\":\"{}\",\"ts\":{}",
+                               self.kind, escape_json(&self.device), self.ts);
+        if let Some(message) = &self.message {
+            json.push_str(&format!(",\"message\":\"{}\"", escape_json(message)));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Receivers of the standby, wake and error events
+pub struct Notifiers {
+    pub webhook: Option<Webhook>,
+    pub hooks: Hooks,
+    pub event_log: Option<EventLog>,
+    pub journal: Option<Journal>,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttPublisher>,
+}
+
+impl Notifiers {
+    pub fn notify(&self, event: Event, dev: &DeviceInfo, target: Option<PowerTarget>, now: Instant) {
+        self.hooks.run(&event, dev, target, now);
+        if let Some(event_log) = &self.event_log {
+            event_log.device_event(&event, dev, now);
+        }
+        if let Some(journal) = &self.journal {
+            journal.device_event(&event, dev, now);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_event(&event, dev);
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(event);
+        }
+    }
+
+    /// Events of the daemon itself: startup, suspend and shutdown
+    pub fn daemon_event(&self, name: &str) {
+        if let Some(event_log) = &self.event_log {
+            event_log.daemon_event(name);
+        }
+        if let Some(journal) = &self.journal {
+            journal.daemon_event(name);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_daemon_event(name);
+        }
+    }
+
+    /// Publishes the state of a device after it changed
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    pub fn state_changed(&self, dev: &DeviceInfo, now: Instant) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_state(dev, now);
+        }
+    }
+
+    /// Publishes the idle time of a device after a check that did not change its state
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    pub fn checked(&self, dev: &DeviceInfo, now: Instant) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_idle_seconds(dev, now);
+        }
+    }
+
+    /// Announces a newly watched device
+    #[cfg_attr(not(feature = "mqtt"), allow(unused_variables))]
+    pub fn device_added(&self, dev: &DeviceInfo, now: Instant) {
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.announce(dev, now);
+        }
+    }
+}
\ No newline at end of file
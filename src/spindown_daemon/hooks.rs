@@ -0,0 +1,110 @@
+use std::io;
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::ata::PowerTarget;
+use crate::spindown_daemon::events::{Event, EventKind};
+
+const EXIT_POLL: Duration = Duration::from_millis(100);
+
+/// Executables run on events, either for all devices or overriding them for one device
+#[derive(Debug, Clone, Default)]
+pub struct HookCommands {
+    pub standby: Option<String>,
+    pub wake: Option<String>,
+    pub error: Option<String>,
+}
+
+impl HookCommands {
+    pub fn get(&self, kind: EventKind) -> Option<&String> {
+        match kind {
+            EventKind::Standby => self.standby.as_ref(),
+            EventKind::Wake => self.wake.as_ref(),
+            EventKind::Error => self.error.as_ref(),
+        }
+    }
+}
+
+/// Runs the hooks of events, each on its own thread, so a slow hook never delays the
+/// checks. Hooks still running after the timeout are killed.
+pub struct Hooks {
+    defaults: HookCommands,
+    timeout: Duration,
+}
+
+impl Hooks {
+    pub fn new(defaults: HookCommands, timeout: Duration) -> Hooks {
+        Hooks { defaults, timeout }
+    }
+
+    /// `target` is the power target issued by a standby event
+    pub fn run(&self, event: &Event, dev: &DeviceInfo, target: Option<PowerTarget>, now: Instant) {
+        let path = match dev.hooks.get(event.kind).or_else(|| self.defaults.get(event.kind)) {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let mut command = Command::new(&path);
+        command.arg(&event.device)
+            .arg(event.kind.to_string())
+            .env("SPINDOWN_DEVICE", &event.device)
+            .env("SPINDOWN_EVENT", event.kind.to_string())
+            .env("SPINDOWN_POWER_STATE", dev.power_state.to_string())
+            .env("SPINDOWN_IDLE_SECONDS", dev.idle_seconds(now).to_string());
+        if let Some(target) = target {
+            command.env("SPINDOWN_POWER_TARGET", target.to_string());
+        }
+        if let Some(message) = &event.message {
+            command.env("SPINDOWN_MESSAGE", message);
+        }
+
+        let timeout = self.timeout;
+        let name = format!("{} hook for {}", event.kind, event.device);
+        let thread_path = path.clone();
+        let spawned = thread::Builder::new()
+            .name("hook".to_string())
+            .spawn(move || run_command(command, &thread_path, &name, timeout));
+        if let Err(e) = spawned {
+            println!("unable to start thread for {}: {}", path, e);
+        }
+    }
+}
+
+fn run_command(mut command: Command, path: &str, name: &str, timeout: Duration) {
+    log::debug!("running {} {}", name, path);
+    match run_with_timeout(&mut command, timeout) {
+        Ok(status) if status.success() => {}
+        Ok(status) => println!("{} {} failed ({})", name, path, status),
+        Err(CommandError::Spawn(e)) => println!("unable to run {} {}: {}", name, path, e),
+        Err(CommandError::Timeout) => {
+            println!("{} {} did not finish within {} seconds, killed it", name, path, timeout.as_secs())
+        }
+        Err(CommandError::Wait(e)) => println!("unable to wait for {} {}: {}", name, path, e),
+    }
+}
+
+pub enum CommandError {
+    Spawn(io::Error),
+    // the command was killed
+    Timeout,
+    Wait(io::Error),
+}
+
+/// Runs a command, killing it once it runs longer than the timeout
+pub fn run_with_timeout(command: &mut Command, timeout: Duration) -> Result<ExitStatus, CommandError> {
+    let mut child = command.spawn().map_err(CommandError::Spawn)?;
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CommandError::Timeout);
+            }
+            Ok(None) => thread::sleep(EXIT_POLL),
+            Err(e) => return Err(CommandError::Wait(e)),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,88 @@
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use nix::sys::socket::{bind, recv, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockProtocol, SockType};
+
+// multicast group of the uevents sent by the kernel (udev re-broadcasts them in group 2)
+const KERNEL_UEVENT_GROUP: u32 = 1;
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HotplugEvent {
+    // a block device was added or changed, e.g. a device-mapper device got its table
+    Added(String),
+    Removed(String),
+}
+
+/// Listens to the kernel uevents of block devices. This is what udev builds upon, reading
+/// them directly works without libudev and without a running udev daemon (containers).
+pub struct HotplugMonitor {
+    events: Receiver<HotplugEvent>,
+}
+
+impl HotplugMonitor {
+    pub fn start() -> Result<HotplugMonitor, String> {
+        let fd = socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC,
+                        SockProtocol::NetlinkKObjectUEvent)
+            .map_err(|e| format!("unable to open uevent socket: {}", e))?;
+        bind(fd, &NetlinkAddr::new(0, KERNEL_UEVENT_GROUP))
+            .map_err(|e| format!("unable to bind uevent socket: {}", e))?;
+
+        let (sender, events) = channel();
+        thread::Builder::new()
+            .name("hotplug".to_string())
+            .spawn(move || {
+                let mut buffer = vec![0u8; UEVENT_BUFFER_SIZE];
+                loop {
+                    let len = match recv(fd, &mut buffer, MsgFlags::empty()) {
+                        Ok(len) => len,
+                        Err(e) => {
+                            log::warn!("unable to receive uevent: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(event) = parse_uevent(&buffer[..len]) {
+                        log::debug!("hotplug event {:?}", event);
+                        if sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(HotplugMonitor { events })
+    }
+
+    /// Returns the events received since the last call
+    pub fn events(&self) -> Vec<HotplugEvent> {
+        self.events.try_iter().collect()
+    }
+}
+
+// A kernel uevent is `ACTION@DEVPATH` followed by `KEY=VALUE` pairs, all separated by
+// null bytes. Only whole disks are of interest, partitions follow their disk.
+fn parse_uevent(message: &[u8]) -> Option<HotplugEvent> {
+    let message = String::from_utf8_lossy(message);
+    let mut action = None;
+    let mut subsystem = None;
+    let mut devtype = None;
+    let mut devname = None;
+    for field in message.split('\0') {
+        match field.split_once('=') {
+            Some(("ACTION", value)) => action = Some(value),
+            Some(("SUBSYSTEM", value)) => subsystem = Some(value),
+            Some(("DEVTYPE", value)) => devtype = Some(value),
+            Some(("DEVNAME", value)) => devname = Some(value),
+            _ => {}
+        }
+    }
+    if subsystem != Some("block") || devtype != Some("disk") {
+        return None;
+    }
+    let name = devname?.trim_start_matches("/dev/").to_string();
+    match action? {
+        "add" | "change" => Some(HotplugEvent::Added(name)),
+        "remove" => Some(HotplugEvent::Removed(name)),
+        _ => None,
+    }
+}
\ No newline at end of file
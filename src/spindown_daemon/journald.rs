@@ -0,0 +1,155 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::events::{Event, EventKind};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "spindown-daemon";
+
+// syslog priorities of the journal
+const PRIORITY_ERR: u8 = 3;
+const PRIORITY_WARNING: u8 = 4;
+const PRIORITY_INFO: u8 = 6;
+const PRIORITY_DEBUG: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogBackend {
+    Stderr,
+    Journald,
+}
+
+impl Display for LogBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LogBackend::Stderr => write!(f, "stderr"),
+            LogBackend::Journald => write!(f, "journald"),
+        }
+    }
+}
+
+impl FromStr for LogBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogBackend::Stderr),
+            "journald" => Ok(LogBackend::Journald),
+            _ => Err(format!("unknown log backend `{}`, expected stderr or journald", s)),
+        }
+    }
+}
+
+/// Sends entries with structured fields to the native socket of systemd-journald
+pub struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    pub fn connect() -> Result<Journal, String> {
+        let socket = UnixDatagram::unbound().map_err(|e| format!("unable to create journal socket: {}", e))?;
+        socket.connect(JOURNAL_SOCKET).map_err(|e| format!("unable to connect to {}: {}", JOURNAL_SOCKET, e))?;
+        Ok(Journal { socket })
+    }
+
+    pub fn send(&self, priority: u8, message: &str, fields: &[(&str, String)]) -> Result<(), String> {
+        let mut entry: Vec<u8> = vec![];
+        append_field(&mut entry, "PRIORITY", &priority.to_string());
+        append_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        append_field(&mut entry, "MESSAGE", message);
+        for (name, value) in fields {
+            append_field(&mut entry, name, value);
+        }
+        self.socket.send(&entry).map(|_| ()).map_err(|e| format!("unable to write to the journal: {}", e))
+    }
+
+    /// Logs a standby, wake or error event, `journalctl SPINDOWN_DEVICE=sda` shows the events of a device
+    pub fn device_event(&self, event: &Event, dev: &DeviceInfo, now: Instant) {
+        let (priority, message) = match event.kind {
+            EventKind::Standby => (PRIORITY_INFO, format!("{} went to standby", event.device)),
+            EventKind::Wake => (PRIORITY_INFO, format!("{} woke up", event.device)),
+            EventKind::Error => (PRIORITY_ERR, format!("error on {}: {}", event.device,
+                                                       event.message.as_deref().unwrap_or("unknown"))),
+        };
+        let mut fields = vec![
+            ("SPINDOWN_DEVICE", event.device.clone()),
+            ("SPINDOWN_EVENT", event.kind.to_string()),
+            ("SPINDOWN_POWER_STATE", dev.power_state.to_string()),
+            ("SPINDOWN_IDLE_SECONDS", dev.idle_seconds(now).to_string()),
+        ];
+        if let Some(message) = &event.message {
+            fields.push(("SPINDOWN_MESSAGE", message.clone()));
+        }
+        if let Err(e) = self.send(priority, &message, &fields) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Logs an event of the daemon itself, e.g. startup, shutdown or suspend
+    pub fn daemon_event(&self, name: &str) {
+        if let Err(e) = self.send(PRIORITY_INFO, &format!("daemon {}", name), &[("SPINDOWN_EVENT", name.to_string())]) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+// KEY=VALUE lines, values with a newline need the binary form: KEY, newline, little endian length, value
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// The log lines of the daemon as journal entries with their priority
+struct JournalLogger {
+    journal: Journal,
+    level: log::LevelFilter,
+    module: &'static str,
+}
+
+impl log::Log for JournalLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with(self.module)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = match record.level() {
+            log::Level::Error => PRIORITY_ERR,
+            log::Level::Warn => PRIORITY_WARNING,
+            log::Level::Info => PRIORITY_INFO,
+            log::Level::Debug | log::Level::Trace => PRIORITY_DEBUG,
+        };
+        let mut fields = vec![("CODE_MODULE", record.target().to_string())];
+        if let Some(file) = record.file() {
+            fields.push(("CODE_FILE", file.to_string()));
+        }
+        if let Some(line) = record.line() {
+            fields.push(("CODE_LINE", line.to_string()));
+        }
+        if let Err(e) = self.journal.send(priority, &record.args().to_string(), &fields) {
+            eprintln!("{}", e);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the journal as the logger of the `log` macros, for the messages of `module`
+pub fn init(level: log::LevelFilter, module: &'static str) -> Result<(), String> {
+    let logger = JournalLogger { journal: Journal::connect()?, level, module };
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())?;
+    log::set_max_level(level);
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,147 @@
+use std::fs::remove_file;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::backend::Clock;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LEN: usize = 8192;
+
+// what the main loop knows about a device, scrapes never query the devices themselves
+struct DeviceSnapshot {
+    name: String,
+    power_state: u8,
+    last_update: Instant,
+    standby_count: u64,
+    wake_count: u64,
+    error_count: u64,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    devices: Vec<DeviceSnapshot>,
+    suspends: u64,
+}
+
+/// Serves the state of the devices in the Prometheus text format at /metrics, over TCP
+/// (`127.0.0.1:9178`, `[::1]:9178`) or a unix socket (any address containing a `/`).
+pub struct Metrics {
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+impl Metrics {
+    pub fn listen(address: &str, clock: Arc<dyn Clock>) -> Result<Metrics, String> {
+        let snapshot = Arc::new(Mutex::new(Snapshot::default()));
+        let thread_snapshot = snapshot.clone();
+        if address.contains('/') {
+            // a socket left behind by a previous run would make the bind fail
+            if std::fs::symlink_metadata(address).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                let _ = remove_file(address);
+            }
+            let listener = UnixListener::bind(address).map_err(|e| format!("unable to listen on {}: {}", address, e))?;
+            spawn_server(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                    serve_request(stream, &thread_snapshot, clock.as_ref());
+                }
+            })?;
+        } else {
+            let address: SocketAddr = address.parse()
+                .map_err(|e| format!("invalid metrics address {}: {}", address, e))?;
+            let listener = TcpListener::bind(address).map_err(|e| format!("unable to listen on {}: {}", address, e))?;
+            spawn_server(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                    serve_request(stream, &thread_snapshot, clock.as_ref());
+                }
+            })?;
+        }
+        Ok(Metrics { snapshot })
+    }
+
+    /// Takes over the state of the devices after a check round
+    pub fn update(&self, devices: &[Box<DeviceInfo>], suspends: u64) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.devices = devices.iter()
+            .map(|dev| DeviceSnapshot {
+                name: dev.name.clone(),
+                power_state: dev.power_state.code(),
+                last_update: dev.last_update,
+                standby_count: dev.statistics.standby_count,
+                wake_count: dev.statistics.wake_count,
+                error_count: dev.statistics.error_count,
+            })
+            .collect();
+        snapshot.suspends = suspends;
+    }
+}
+
+fn spawn_server(serve: impl FnOnce() + Send + 'static) -> Result<(), String> {
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(serve)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Reads the request head and answers GET /metrics, one request per connection
+fn serve_request<S: Read + Write>(mut stream: S, snapshot: &Mutex<Snapshot>, clock: &dyn Clock) {
+    let mut request: Vec<u8> = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => request.extend_from_slice(&buffer[..len]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(&snapshot.lock().unwrap(), clock.now());
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}", body.len(), body)
+        }
+        (Some("GET"), _) => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        log::debug!("unable to answer metrics request: {}", e);
+    }
+}
+
+fn render(snapshot: &Snapshot, now: Instant) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut device_metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(&DeviceSnapshot) -> u64| {
+        lines.push(format!("# HELP {} {}", name, help));
+        lines.push(format!("# TYPE {} {}", name, kind));
+        for dev in snapshot.devices.iter() {
+            lines.push(format!("{}{{device=\"{}\"}} {}", name, escape_label(&dev.name), value(dev)));
+        }
+    };
+    device_metric("spindown_device_power_state", "gauge",
+                  "Last known power state: 0 standby, 1-4 idle, 5 active or idle, 255 unknown",
+                  &|dev| dev.power_state as u64);
+    device_metric("spindown_device_idle_seconds", "gauge", "Seconds since the last I/O",
+                  &|dev| now.saturating_duration_since(dev.last_update).as_secs());
+    device_metric("spindown_standby_issued_total", "counter", "Power commands issued by the daemon",
+                  &|dev| dev.standby_count);
+    device_metric("spindown_wake_detected_total", "counter", "Wake-ups from standby",
+                  &|dev| dev.wake_count);
+    device_metric("spindown_device_errors_total", "counter", "Failed checks and power commands",
+                  &|dev| dev.error_count);
+    lines.push("# HELP spindown_suspends_total System suspends issued by the daemon".to_string());
+    lines.push("# TYPE spindown_suspends_total counter".to_string());
+    lines.push(format!("spindown_suspends_total {}", snapshot.suspends));
+    lines.join("\n") + "\n"
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
\ No newline at end of file
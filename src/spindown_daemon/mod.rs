@@ -1,33 +1,301 @@
-use std::borrow::Borrow;
-use std::time::SystemTime;
+use std::time::Instant;
 
-use crate::spindown_daemon::ata::{check_power_state, PowerState};
-use crate::spindown_daemon::errors::DeviceError;
-use crate::spindown_daemon::sysfs::get_device_stats;
+use crate::spindown_daemon::ata::{PowerState, PowerTarget};
+use crate::spindown_daemon::backend::{PowerBackend, Probes};
+use crate::spindown_daemon::errors::{DeviceError, DeviceErrorKind, ErrorLog};
+use crate::spindown_daemon::hooks::HookCommands;
+use crate::spindown_daemon::paths::Paths;
+use crate::spindown_daemon::statistics::DeviceStatistics;
+use crate::spindown_daemon::sysfs::{get_device_serial, get_partition_stats, get_physical_disks, DiskStats, StatSource};
 
 pub mod ata;
+pub mod backend;
+pub mod console;
+pub mod control;
+pub mod daemon;
+#[cfg(feature = "dbus")]
+pub mod dbus;
 pub mod sysfs;
 pub mod errors;
+pub mod event_log;
+pub mod events;
+pub mod hooks;
+pub mod hotplug;
+pub mod journald;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod paths;
+pub mod pidfile;
+pub mod privileges;
+pub mod recorder;
+pub mod resume;
+pub mod round;
+pub mod signals;
+pub mod simulate;
+pub mod spec;
+pub mod state;
+pub mod statistics;
+pub mod status;
+pub mod status_file;
+pub mod suspend_check;
+pub mod suspend_gate;
+pub mod webhook;
+pub mod worker;
 
 #[derive(Debug)]
 pub struct DeviceInfo {
     pub name: String,
     pub timeout: u64,
+    pub check_interval: u64,
+    pub next_check: Instant,
+    pub power_target: PowerTarget,
+    pub standby_method: StandbyMethod,
     pub power_state: PowerState,
+    // when the power state was last queried from the device, None forces a query
+    pub power_confirmed: Option<Instant>,
     pub last_read_iops: u64,
     pub last_write_iops: u64,
-    pub last_update: SystemTime,
+    pub last_read_sectors: u64,
+    pub last_write_sectors: u64,
+    // read/write operations between two checks that do not count as activity
+    pub iops_tolerance: u64,
+    // monotonic, so clock changes don't affect idle times; it does not advance while
+    // the system is suspended, which pauses the timers until resume
+    pub last_update: Instant,
+    pub stat_source: StatSource,
+    // physical disks receiving the power commands, the device itself unless it is a stack
+    pub members: Vec<String>,
+    pub serial: Option<String>,
+    pub partitions: Vec<(String, DiskStats)>,
+    // no activity was seen during the last check
+    pub idle: bool,
+    // message of the failed last check
+    pub error: Option<String>,
+    // checks failed in a row, not counting the ones skipped because the device was busy
+    pub failed_checks: u64,
+    // the disk still reported this state after all retries of the last power command
+    pub not_parking: Option<PowerState>,
+    // highest Load/Unload Cycle Count of the disks when they were last read, and their rating
+    pub load_cycles: Option<u64>,
+    pub rated_load_cycles: u64,
+    pub error_log: ErrorLog,
+    // overrides of the global hooks
+    pub hooks: HookCommands,
+    pub standby_since: Option<StandbyStretch>,
+    pub statistics: DeviceStatistics,
 }
 
-pub fn get_device_info(dev: &String) -> Result<DeviceInfo, DeviceError> {
-    let device_stats = get_device_stats(dev.borrow())?;
-    let power_state = check_power_state(dev.borrow())?;
+/// How the power commands reach a device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StandbyMethod {
+    AtaPassthrough,
+    // the device answers no power commands, so it can only be monitored
+    None,
+}
+
+/// Start of the current standby, with the counters at that time
+#[derive(Debug, Clone, Copy)]
+pub struct StandbyStretch {
+    pub since: Instant,
+    pub read_iops: u64,
+    pub write_iops: u64,
+}
+
+/// Last confirmed power state of a device with the counters seen at that time. The power
+/// check is skipped while the counters tell that the state did not change: I/O keeps an
+/// active device active and without I/O, a device stays in its power saving state. Such a
+/// check would be a pointless command to a sleeping disk, some bridges even wake it up.
+/// Unlike for the idle time, `-t` does not apply: any I/O reaching a sleeping disk spins it up.
+#[derive(Debug, Clone, Copy)]
+pub struct KnownState {
+    pub power_state: PowerState,
+    pub confirmed: Instant,
+    pub read_iops: u64,
+    pub write_iops: u64,
+}
+
+impl KnownState {
+    fn still_valid(&self, stats: &DiskStats) -> bool {
+        self.power_state == PowerState::ActiveOrIdle ||
+            (stats.read_iops == self.read_iops && stats.write_iops == self.write_iops)
+    }
+}
+
+/// Probes a device. A device with readable stats but failing power checks (e.g. behind
+/// a USB bridge without ATA pass-through) has the standby method None, the failure is
+/// kept as its error.
+pub fn get_device_info(dev: &str, paths: &Paths, probes: &Probes) -> Result<DeviceInfo, DeviceError> {
+    match check_device(dev, paths, probes, None, StandbyMethod::AtaPassthrough) {
+        Err(e) if e.kind == DeviceErrorKind::Other && probes.stats.device_stats(dev, paths).is_ok() => {
+            let mut dev_info = check_device(dev, paths, probes, None, StandbyMethod::None)?;
+            dev_info.error = Some(e.message);
+            Ok(dev_info)
+        }
+        result => result,
+    }
+}
+
+/// Like get_device_info, but keeps the known power state without a power check while
+/// the counters confirm it.
+pub fn check_device(dev: &str, paths: &Paths, probes: &Probes, known: Option<KnownState>,
+                    standby_method: StandbyMethod) -> Result<DeviceInfo, DeviceError> {
+    // the stats are read before the power check, which is a pass-through request
+    // and never shows up in the counters anyway, so a check is no activity
+    let device_stats = probes.stats.device_stats(dev, paths)?;
+    let members = get_physical_disks(dev, paths)?;
+    let now = probes.clock.now();
+    let (power_state, power_confirmed) = match known {
+        _ if standby_method == StandbyMethod::None => (PowerState::Unknown, now),
+        Some(known) if known.still_valid(&device_stats) => {
+            log::debug!("{} is still {}, skipping power check", dev, known.power_state);
+            (known.power_state, known.confirmed)
+        }
+        _ => (get_members_power_state(dev, &members, paths, probes.power.as_ref())?, probes.clock.now()),
+    };
     Ok(DeviceInfo {
         name: dev.to_string(),
         timeout: 0,
+        check_interval: 0,
+        next_check: now,
+        power_target: PowerTarget::Standby,
+        standby_method,
         power_state,
-        last_read_iops: device_stats.0,
-        last_write_iops: device_stats.1,
-        last_update: SystemTime::now(),
+        power_confirmed: Some(power_confirmed),
+        last_read_iops: device_stats.read_iops,
+        last_write_iops: device_stats.write_iops,
+        last_read_sectors: device_stats.read_sectors,
+        last_write_sectors: device_stats.write_sectors,
+        iops_tolerance: 0,
+        last_update: now,
+        stat_source: device_stats.source,
+        members,
+        serial: get_device_serial(dev, paths),
+        partitions: get_partition_stats(dev, paths),
+        idle: false,
+        error: None,
+        failed_checks: 0,
+        not_parking: None,
+        load_cycles: None,
+        rated_load_cycles: 0,
+        error_log: ErrorLog::new(now),
+        hooks: HookCommands::default(),
+        standby_since: (power_state == PowerState::Standby).then_some(StandbyStretch {
+            since: now,
+            read_iops: device_stats.read_iops,
+            write_iops: device_stats.write_iops,
+        }),
+        statistics: DeviceStatistics::new(now),
     })
+}
+
+/// Power state of a disk or, for a stack, of its physical disks
+pub fn get_power_state(dev: &str, paths: &Paths, backend: &dyn PowerBackend) -> Result<PowerState, DeviceError> {
+    let members = get_physical_disks(dev, paths)?;
+    get_members_power_state(dev, &members, paths, backend)
+}
+
+fn get_members_power_state(dev: &str, members: &[String], paths: &Paths,
+                           backend: &dyn PowerBackend) -> Result<PowerState, DeviceError> {
+    if members.len() == 1 && members[0] == dev {
+        backend.power_state(dev, paths)
+    } else {
+        get_stack_power_state(members, paths, backend)
+    }
+}
+
+// a stack only counts as in standby if all of its disks are
+fn get_stack_power_state(members: &[String], paths: &Paths, backend: &dyn PowerBackend) -> Result<PowerState, DeviceError> {
+    for member in members {
+        let power_state = backend.power_state(member, paths)?;
+        if power_state != PowerState::Standby {
+            return Ok(power_state);
+        }
+    }
+    Ok(PowerState::Standby)
+}
+
+impl DeviceInfo {
+    pub fn stats(&self) -> DiskStats {
+        DiskStats {
+            read_iops: self.last_read_iops,
+            write_iops: self.last_write_iops,
+            read_sectors: self.last_read_sectors,
+            write_sectors: self.last_write_sectors,
+            source: self.stat_source,
+        }
+    }
+
+    /// Seconds since the last I/O or power command
+    pub fn idle_seconds(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.last_update).as_secs()
+    }
+
+    fn current_standby_seconds(&self, now: Instant) -> u64 {
+        self.standby_since.map_or(0, |stretch| now.saturating_duration_since(stretch.since).as_secs())
+    }
+
+    /// Seconds spent in standby, including the current stretch
+    pub fn total_standby_seconds(&self, now: Instant) -> u64 {
+        self.statistics.standby_seconds + self.current_standby_seconds(now)
+    }
+
+    pub fn longest_standby_seconds(&self, now: Instant) -> u64 {
+        self.statistics.longest_standby_seconds.max(self.current_standby_seconds(now))
+    }
+
+    /// Seconds the device was not in standby while being watched
+    pub fn active_seconds(&self, now: Instant) -> u64 {
+        self.statistics.managed_seconds(now).saturating_sub(self.total_standby_seconds(now))
+    }
+
+    /// Tells the ambiguous ActiveOrIdle (0xFF) apart by the I/O of the last check: a drive
+    /// without I/O is `idle-spinning`, one with I/O `active`. None for the other power states.
+    pub fn activity(&self) -> Option<&'static str> {
+        match self.power_state {
+            PowerState::ActiveOrIdle if self.idle => Some("idle-spinning"),
+            PowerState::ActiveOrIdle => Some("active"),
+            _ => None,
+        }
+    }
+
+    /// Starts the standby and wake-up counts over, e.g. after a change of the timeouts, along
+    /// with the failed checks, the not parking flag and the repetitions of the last error
+    pub fn reset_counters(&mut self, now: Instant) {
+        self.statistics.standby_count = 0;
+        self.statistics.wake_count = 0;
+        self.failed_checks = 0;
+        self.not_parking = None;
+        self.error_log.reset(&self.name, now);
+    }
+
+    /// A different serial means that another device now uses the same name.
+    pub fn is_replaced_by(&self, current: &DeviceInfo) -> bool {
+        match (&self.serial, &current.serial) {
+            (Some(serial), Some(current_serial)) => serial != current_serial,
+            _ => false,
+        }
+    }
+
+    /// Stat counters only grow during the lifetime of a device, lower counters mean that
+    /// the device was re-registered (or the counters wrapped around).
+    pub fn counters_reset(&self, current: &DeviceInfo) -> bool {
+        current.last_read_iops < self.last_read_iops || current.last_write_iops < self.last_write_iops
+    }
+
+    /// Describes which partitions saw the I/O since the `previous` partition snapshot
+    pub fn partition_activity(&self, previous: &[(String, DiskStats)]) -> Vec<String> {
+        let mut activity = vec![];
+        for (name, stats) in self.partitions.iter() {
+            let (read_base, write_base) = previous.iter()
+                .find(|(previous_name, _)| previous_name == name)
+                .map_or((0, 0), |(_, base)| (base.read_iops, base.write_iops));
+            let reads = stats.read_iops.saturating_sub(read_base);
+            let writes = stats.write_iops.saturating_sub(write_base);
+            if reads > 0 || writes > 0 {
+                activity.push(format!("{} +{} reads/+{} writes", name, reads, writes));
+            }
+        }
+        activity
+    }
 }
\ No newline at end of file
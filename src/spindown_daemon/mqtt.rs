@@ -0,0 +1,160 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rumqttc::{Client, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::events::{escape_json, unix_time, Event};
+
+const DEFAULT_PORT: u16 = 1883;
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+// messages queued while the broker is unreachable, newer ones are dropped
+const QUEUE_CAPACITY: usize = 64;
+
+pub struct MqttConfig {
+    // host[:port], optionally prefixed with mqtt:// or tcp://
+    pub broker: String,
+    pub topic_prefix: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // publish Home Assistant discovery messages along with the state of a new device
+    pub discovery: bool,
+}
+
+pub struct MqttPublisher {
+    client: Client,
+    topic_prefix: String,
+    discovery: bool,
+}
+
+impl MqttPublisher {
+    /// Connects in a background thread, which keeps reconnecting on its own with an increasing
+    /// delay; publishing never blocks the caller.
+    pub fn connect(config: &MqttConfig) -> Result<MqttPublisher, String> {
+        let (host, port) = parse_broker(&config.broker)?;
+        let topic_prefix = config.topic_prefix.trim_end_matches('/').to_string();
+        let status_topic = format!("{}/status", topic_prefix);
+
+        let mut options = MqttOptions::new(format!("spindown-daemon-{}", std::process::id()), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        // the broker marks the sensors unavailable once the daemon is gone
+        options.set_last_will(LastWill::new(&status_topic, "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+
+        let broker = config.broker.clone();
+        let status_client = client.clone();
+        thread::Builder::new()
+            .name("mqtt".to_string())
+            .spawn(move || {
+                let mut delay = MIN_RECONNECT_DELAY;
+                let mut connected = false;
+                for notification in connection.iter() {
+                    match notification {
+                        Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                            log::info!("connected to mqtt broker {}", broker);
+                            connected = true;
+                            delay = MIN_RECONNECT_DELAY;
+                            // replaces the last will of a previous connection
+                            let _ = status_client.try_publish(&status_topic, QoS::AtLeastOnce, true, "online");
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            if connected {
+                                log::warn!("lost connection to mqtt broker {}: {}", broker, e);
+                            } else {
+                                log::warn!("unable to connect to mqtt broker {}: {}, retrying in {}s",
+                                           broker, e, delay.as_secs());
+                            }
+                            connected = false;
+                            thread::sleep(delay);
+                            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                        }
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(MqttPublisher {
+            client,
+            topic_prefix,
+            discovery: config.discovery,
+        })
+    }
+
+    /// Publishes the discovery messages (with `discovery`) and the state of a newly watched device
+    pub fn announce(&self, dev: &DeviceInfo, now: Instant) {
+        if self.discovery {
+            self.publish_discovery(dev);
+        }
+        self.publish_state(dev, now);
+    }
+
+    pub fn publish_state(&self, dev: &DeviceInfo, now: Instant) {
+        self.publish(&format!("{}/{}/power_state", self.topic_prefix, dev.name),
+                     dev.power_state.to_string(), true);
+        self.publish(&format!("{}/{}/power_state_code", self.topic_prefix, dev.name),
+                     dev.power_state.code().to_string(), true);
+        self.publish_idle_seconds(dev, now);
+    }
+
+    /// Publishes the idle seconds alone, which grow with every check rather than on a state change
+    pub fn publish_idle_seconds(&self, dev: &DeviceInfo, now: Instant) {
+        self.publish(&format!("{}/{}/idle_seconds", self.topic_prefix, dev.name),
+                     dev.idle_seconds(now).to_string(), true);
+    }
+
+    /// Publishes a standby, wake or error event of a device to `<prefix>/<device>/event` (not retained)
+    pub fn publish_event(&self, event: &Event, dev: &DeviceInfo) {
+        self.publish(&format!("{}/{}/event", self.topic_prefix, dev.name), event.to_json(), false);
+    }
+
+    /// Publishes an event of the daemon itself, like a system suspend, to `<prefix>/daemon/event`
+    pub fn publish_daemon_event(&self, name: &str) {
+        self.publish(&format!("{}/daemon/event", self.topic_prefix),
+                     format!("{{\"event\":\"{}\",\"ts\":{}}}", escape_json(name), unix_time()), false);
+    }
+
+    /// Announces the device sensors for Home Assistant MQTT discovery.
+    pub fn publish_discovery(&self, dev: &DeviceInfo) {
+        for (sensor, unit) in [("power_state", None), ("idle_seconds", Some("s"))] {
+            let unique_id = format!("spindown_{}_{}", dev.name, sensor);
+            let unit = unit.map(|u| format!(",\"unit_of_measurement\":\"{}\"", u)).unwrap_or_default();
+            let config = format!(
+                "{{\"name\":\"{} {}\",\"unique_id\":\"{}\",\"state_topic\":\"{}/{}/{}\"{},\
+                \"availability_topic\":\"{}/status\",\
+                \"device\":{{\"identifiers\":[\"spindown_{}\"],\"name\":\"{}\"}}}}",
+                dev.name, sensor.replace('_', " "), unique_id,
+                self.topic_prefix, dev.name, sensor, unit,
+                self.topic_prefix,
+                dev.name, dev.name);
+            self.publish(&format!("{}/sensor/{}/config", DISCOVERY_PREFIX, unique_id), config, true);
+        }
+    }
+
+    fn publish(&self, topic: &str, payload: String, retain: bool) {
+        if let Err(e) = self.client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+            log::debug!("unable to publish {}: {}", topic, e);
+        }
+    }
+}
+
+fn parse_broker(broker: &str) -> Result<(&str, u16), String> {
+    let address = match broker.split_once("://") {
+        Some(("mqtt", address)) | Some(("tcp", address)) => address,
+        Some((scheme, _)) => return Err(format!("unsupported broker scheme {}, expected mqtt or tcp", scheme)),
+        None => broker,
+    };
+    let address = address.trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => Ok((host, port)),
+            Err(e) => Err(format!("invalid broker port {}: {}", port, e)),
+        },
+        None => Ok((address, DEFAULT_PORT)),
+    }
+}
\ No newline at end of file
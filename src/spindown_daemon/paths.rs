@@ -0,0 +1,31 @@
+/// Roots of the sysfs and device trees, /sys and /dev unless overridden for tests or sandboxes
+#[derive(Debug, Clone)]
+pub struct Paths {
+    sysfs_root: String,
+    dev_root: String,
+}
+
+impl Paths {
+    pub fn new(sysfs_root: &str, dev_root: &str) -> Paths {
+        Paths {
+            sysfs_root: sysfs_root.trim_end_matches('/').to_string(),
+            dev_root: dev_root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Path below the sysfs root, e.g. `sysfs("block/sda/stat")`
+    pub fn sysfs(&self, relative: &str) -> String {
+        format!("{}/{}", self.sysfs_root, relative)
+    }
+
+    /// Path below the device root, e.g. `dev("sda")`
+    pub fn dev(&self, relative: &str) -> String {
+        format!("{}/{}", self.dev_root, relative)
+    }
+}
+
+impl Default for Paths {
+    fn default() -> Paths {
+        Paths::new("/sys", "/dev")
+    }
+}
@@ -0,0 +1,34 @@
+use std::fs::{read_to_string, remove_file, write};
+
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+/// Holds the pid file while the daemon is running and removes it when dropped.
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    pub fn create(path: &str) -> Result<PidFile, String> {
+        if let Ok(content) = read_to_string(path) {
+            if let Ok(pid) = content.trim().parse::<i32>() {
+                if pid != std::process::id() as i32 && kill(Pid::from_raw(pid), None).is_ok() {
+                    return Err(format!("already running with pid {} according to {}", pid, path));
+                }
+            }
+            log::debug!("replacing stale pid file {}", path);
+        }
+
+        write(path, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("unable to write {}: {}", path, e))?;
+        Ok(PidFile { path: path.to_string() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = remove_file(&self.path) {
+            println!("unable to remove {}: {}", self.path, e);
+        }
+    }
+}
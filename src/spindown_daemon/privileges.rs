@@ -0,0 +1,113 @@
+use std::fs::metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::libc::{c_int, c_ulong, prctl, syscall, SYS_capset, PR_CAPBSET_DROP, PR_SET_KEEPCAPS};
+use nix::unistd::{chown, setgid, setgroups, setuid, Gid, Group, Uid, User};
+
+// see linux/capability.h
+const CAP_SYS_RAWIO: u32 = 17;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+// capabilities are numbered without gaps, the first one the kernel does not know ends the bounding set
+const MAX_CAPABILITIES: u32 = 64;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The user and group the daemon runs as once the devices were probed
+pub struct Credentials {
+    pub user: String,
+    uid: Uid,
+    gid: Gid,
+}
+
+impl Credentials {
+    /// Looks up the user and the group, by default the primary group of the user
+    pub fn lookup(user: &str, group: Option<&str>) -> Result<Credentials, String> {
+        let found = User::from_name(user).map_err(|e| format!("unable to look up user {}: {}", user, e))?
+            .ok_or_else(|| format!("unknown user {}", user))?;
+        let gid = match group {
+            Some(group) => Group::from_name(group).map_err(|e| format!("unable to look up group {}: {}", group, e))?
+                .ok_or_else(|| format!("unknown group {}", group))?
+                .gid,
+            None => found.gid,
+        };
+        Ok(Credentials { user: user.to_string(), uid: found.uid, gid })
+    }
+
+    /// Fails unless the user may create and remove files in the directory of `path`, which the
+    /// pid file (removed on exit) and the state and status files (replaced through a rename) need
+    pub fn check_writable_dir(&self, path: &str) -> Result<(), String> {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let found = metadata(dir).map_err(|e| format!("unable to check {}: {}", dir.display(), e))?;
+        // write and search permission of the owner, the group or the others, whichever applies
+        let required = if found.uid() == self.uid.as_raw() {
+            0o300
+        } else if found.gid() == self.gid.as_raw() {
+            0o030
+        } else {
+            0o003
+        };
+        if found.mode() & required != required {
+            return Err(format!("{} is not writable by {}, which {} needs; use a directory owned by {}",
+                               dir.display(), self.user, path, self.user));
+        }
+        Ok(())
+    }
+
+    /// Hands a file created as root over to the user, so it can be reopened or removed later
+    pub fn chown(&self, path: &str) -> Result<(), String> {
+        chown(path, Some(self.uid), Some(self.gid))
+            .map_err(|e| format!("unable to change the owner of {} to {}: {}", path, self.user, e))
+    }
+}
+
+/// Switches to the user and group, keeping only CAP_SYS_RAWIO for SG_IO. The device nodes are
+/// opened for every command, so the user or group needs read access to them (e.g. the group
+/// disk). Capabilities are per thread: threads started before lose all of them, the ones sending
+/// pass-through commands have to be started afterwards.
+pub fn drop_privileges(credentials: &Credentials) -> Result<(), String> {
+    // no other capability can come back, not even by running a setuid binary
+    for capability in 0..MAX_CAPABILITIES {
+        if capability == CAP_SYS_RAWIO {
+            continue;
+        }
+        if unsafe { prctl(PR_CAPBSET_DROP, capability as c_ulong, 0, 0, 0) } != 0 {
+            match Errno::last() {
+                Errno::EINVAL => break,
+                e => return Err(format!("unable to drop the capabilities: {}", e)),
+            }
+        }
+    }
+    if unsafe { prctl(PR_SET_KEEPCAPS, 1 as c_ulong, 0, 0, 0) } != 0 {
+        return Err(format!("unable to keep the capabilities: {}", Errno::last()));
+    }
+    setgroups(&[credentials.gid]).map_err(|e| format!("unable to set the groups: {}", e))?;
+    setgid(credentials.gid).map_err(|e| format!("unable to change the group to {}: {}", credentials.gid, e))?;
+    setuid(credentials.uid).map_err(|e| format!("unable to change the user to {}: {}", credentials.user, e))?;
+
+    // the permitted set survived setuid, the effective one has to be raised again
+    let mut header = CapUserHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapUserData::default(); 2];
+    data[0].effective = 1 << CAP_SYS_RAWIO;
+    data[0].permitted = 1 << CAP_SYS_RAWIO;
+    if unsafe { syscall(SYS_capset, &mut header as *mut CapUserHeader, data.as_mut_ptr()) } != 0 {
+        return Err(format!("unable to set CAP_SYS_RAWIO: {}", Errno::last()));
+    }
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,41 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::event_log::open_append;
+use crate::spindown_daemon::events::unix_time;
+use crate::spindown_daemon::simulate::format_sample;
+
+/// Appends the counters and power state of every device after each round, in the trace
+/// format of `--simulate`
+pub struct Recorder {
+    path: String,
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &str) -> Result<Recorder, String> {
+        Ok(Recorder { path: path.to_string(), writer: open_append(path, "trace")? })
+    }
+
+    /// Opens the file again, e.g. after logrotate moved it away (SIGHUP)
+    pub fn reopen(&mut self) {
+        match open_append(&self.path, "trace") {
+            Ok(writer) => {
+                self.writer = writer;
+                log::info!("reopened trace {}", self.path);
+            }
+            Err(e) => println!("{}, still writing to the previous file", e),
+        }
+    }
+
+    pub fn record(&mut self, devices: &[Box<DeviceInfo>]) {
+        let ts = unix_time();
+        let result = devices.iter()
+            .try_for_each(|dev| writeln!(self.writer, "{}", format_sample(ts, &dev.name, &dev.stats(), dev.power_state)))
+            .and_then(|_| self.writer.flush());
+        if let Err(e) = result {
+            log::warn!("unable to write to trace {}: {}", self.path, e);
+        }
+    }
+}
\ No newline at end of file
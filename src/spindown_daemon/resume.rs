@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use nix::time::{clock_gettime, ClockId};
+
+// reading the two clocks one after the other differs by far less
+const MIN_ASLEEP: Duration = Duration::from_secs(2);
+
+/// Notices a resume from suspend or hibernation: CLOCK_BOOTTIME keeps counting while the
+/// system sleeps, CLOCK_MONOTONIC (and with it every Instant) does not.
+pub struct ResumeDetector {
+    asleep: Duration,
+}
+
+impl ResumeDetector {
+    pub fn start() -> ResumeDetector {
+        ResumeDetector { asleep: time_asleep() }
+    }
+
+    /// Returns how long the system was asleep since the last call, if it was
+    pub fn check(&mut self) -> Option<Duration> {
+        let asleep = time_asleep();
+        let slept = asleep.saturating_sub(self.asleep);
+        self.asleep = asleep;
+        (slept >= MIN_ASLEEP).then_some(slept)
+    }
+}
+
+// total time the system spent asleep since it booted
+fn time_asleep() -> Duration {
+    match (clock_gettime(ClockId::CLOCK_BOOTTIME), clock_gettime(ClockId::CLOCK_MONOTONIC)) {
+        (Ok(boottime), Ok(monotonic)) => Duration::from(boottime).saturating_sub(Duration::from(monotonic)),
+        _ => Duration::ZERO,
+    }
+}
\ No newline at end of file
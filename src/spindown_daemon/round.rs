@@ -0,0 +1,1109 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::spindown_daemon::{DeviceInfo, KnownState, StandbyMethod, StandbyStretch};
+use crate::spindown_daemon::ata::{PowerState, PowerTarget};
+use crate::spindown_daemon::backend::Probes;
+use crate::spindown_daemon::console;
+use crate::spindown_daemon::daemon::Action;
+use crate::spindown_daemon::errors::{DeviceError, DeviceErrorKind};
+use crate::spindown_daemon::events::{Event, EventKind, Notifiers};
+use crate::spindown_daemon::status::format_duration;
+use crate::spindown_daemon::paths::Paths;
+use crate::spindown_daemon::sysfs::{get_pending_writeback, is_idle};
+use crate::spindown_daemon::worker::DeviceWorker;
+
+/// Settings and receivers of a check round
+pub struct RoundContext<'a> {
+    pub reconcile_interval: Duration,
+    pub check_deadline: Duration,
+    pub suspend_ignore_watch_only: bool,
+    // all devices are put to sleep together once none of them had I/O for that long
+    pub group_timeout: Option<u64>,
+    // KiB of dirty data postponing a standby, which would flush them and wake up the disk
+    pub dirty_threshold: Option<u64>,
+    // longer check interval while all devices are in standby without I/O
+    pub idle_poll_interval: Option<Duration>,
+    pub paths: &'a Paths,
+    // the idle times are measured with its clock, the stats after a resume read with its reader
+    pub probes: &'a Probes,
+    pub notifiers: &'a Notifiers,
+    // the devices are checked, but not put to sleep
+    pub inhibited: bool,
+}
+
+/// What the suspend logic needs to know about the devices after a round
+pub struct RoundResult {
+    // a device that is not ignored for suspend is not in standby
+    pub disks_running: bool,
+    pub latest_update: Option<Instant>,
+    // the power commands queued
+    pub actions: Vec<Action>,
+}
+
+/// Checks the due devices (all of them with `check_all`) and puts the expired ones to sleep.
+/// A `forced` round ignores the timeouts, every device without I/O since its previous check
+/// is put to sleep.
+pub fn run_once(devices: &mut [Box<DeviceInfo>], workers: &mut HashMap<String, DeviceWorker>, context: &RoundContext,
+                check_all: bool, forced: bool) -> RoundResult {
+    handle_power_results(devices, workers, context.notifiers, context.probes.clock.now());
+
+    let mut round = RoundResult { disks_running: false, latest_update: None, actions: vec![] };
+    // devices without I/O for longer than their timeout, regardless of their power state
+    let mut idle_expired: Vec<bool> = vec![false; devices.len()];
+
+    // all due devices are checked in parallel, slow ones are collected in a later round
+    let now = context.probes.clock.now();
+    let group_due = group_deadline(devices, context.group_timeout).is_some_and(|deadline| deadline <= now);
+    let due: Vec<bool> = devices.iter()
+        .map(|dev| check_all || group_due || next_check_time(dev) <= now)
+        .collect();
+    // devices checked successfully this round
+    let mut checked: Vec<bool> = vec![false; devices.len()];
+    for (index, dev) in devices.iter_mut().enumerate() {
+        if !due[index] {
+            continue;
+        }
+        // the ticks of a device keep their phase, a round taking long does not shift the later ones
+        if dev.next_check <= now {
+            let (next_check, missed) = next_deadline(dev.next_check, Duration::from_secs(dev.check_interval), now);
+            if missed > 0 {
+                log::warn!("checks of {} are {}s behind schedule, skipping {} missed check(s)",
+                           dev.name, now.duration_since(dev.next_check).as_secs(), missed);
+            }
+            dev.next_check = next_check;
+        }
+        // the power state is queried again before a standby decision and, unless the device
+        // is in standby, after the reconcile interval
+        let timeout_elapsed = dev.timeout > 0 && !dev.power_target.is_reached(dev.power_state) &&
+            (group_due || now.saturating_duration_since(dev.last_update).as_secs() > dev.timeout);
+        let reconcile_due = |confirmed: &Instant| {
+            dev.power_state != PowerState::Standby &&
+                now.saturating_duration_since(*confirmed) >= context.reconcile_interval
+        };
+        let known = dev.power_confirmed
+            .filter(|confirmed| !timeout_elapsed && !reconcile_due(confirmed))
+            .map(|confirmed| KnownState {
+                power_state: dev.power_state,
+                confirmed,
+                read_iops: dev.last_read_iops,
+                write_iops: dev.last_write_iops,
+            });
+        if let Some(worker) = workers.get_mut(&dev.name) {
+            worker.request_check(known);
+        }
+    }
+    let deadline = Instant::now() + context.check_deadline;
+
+    for (index, cache) in devices.iter_mut().enumerate() {
+        let result = workers.get_mut(&cache.name).and_then(|worker| worker.collect(deadline));
+        // the results come in while the round waits for them
+        let now = context.probes.clock.now();
+        match result {
+            None if !due[index] && !workers[&cache.name].is_pending() => {
+                // not checked this round, its timer is covered by next_check_time
+                idle_expired[index] = cache.idle && cache.timeout > 0 &&
+                    now.saturating_duration_since(cache.last_update).as_secs() > cache.timeout;
+            }
+            None => {
+                log::debug!("check of {} is still running, using its last known state", cache.name);
+                if forced {
+                    println!("skipping {}: its check is still running", cache.name);
+                }
+            }
+            Some(Ok(current)) => {
+                log::debug!("cache {:?}", cache);
+                log::debug!("current {:?}", current);
+                cache.error = None;
+                cache.failed_checks = 0;
+                cache.error_log.recovered(&cache.name, now);
+                let previous_activity = cache.activity();
+
+                let state_changed = cache.power_state != current.power_state;
+                if cache.power_state == PowerState::Standby && state_changed {
+                    log::debug!("device {:?} woke up", cache.name);
+                    if let Some(stretch) = cache.standby_since.take() {
+                        let seconds = now.saturating_duration_since(stretch.since).as_secs();
+                        cache.statistics.add_standby_stretch(seconds);
+                        let reads = current.last_read_iops.saturating_sub(stretch.read_iops);
+                        let writes = current.last_write_iops.saturating_sub(stretch.write_iops);
+                        // I/O goes through the stat counters, commands like SMART queries don't
+                        let cause = if reads == 0 && writes == 0 { " (no I/O, woken by a command)" } else { "" };
+                        println!("{}", console::event(format!(
+                            "{} spun up after {} in standby; +{} reads / +{} writes since standby{}",
+                            cache.name, format_duration(seconds), reads, writes, cause)));
+                    }
+                    cache.statistics.wake_count += 1;
+                    context.notifiers.notify(Event::new(EventKind::Wake, &cache.name), cache, None, now);
+                } else if current.power_state == PowerState::Standby && state_changed {
+                    cache.standby_since = Some(StandbyStretch {
+                        since: now,
+                        read_iops: current.last_read_iops,
+                        write_iops: current.last_write_iops,
+                    });
+                }
+                cache.power_state = current.power_state;
+                cache.power_confirmed = current.power_confirmed;
+                if cache.stat_source != current.stat_source {
+                    log::info!("reading stats of {} from {}", cache.name, current.stat_source);
+                    cache.stat_source = current.stat_source;
+                }
+                let read_delta = current.last_read_iops.saturating_sub(cache.last_read_iops);
+                let write_delta = current.last_write_iops.saturating_sub(cache.last_write_iops);
+                let mut no_iops = false;
+
+                if cache.is_replaced_by(&current) || cache.counters_reset(&current) {
+                    if cache.is_replaced_by(&current) {
+                        println!("{} appears to be a different/replaced device, resetting timers", cache.name);
+                    } else {
+                        println!("stat counters of {} went backwards (reset or wraparound), resetting timers",
+                                 cache.name);
+                    }
+                    cache.serial = current.serial.clone();
+                    cache.members = current.members.clone();
+                    cache.last_read_iops = current.last_read_iops;
+                    cache.last_write_iops = current.last_write_iops;
+                    cache.last_read_sectors = current.last_read_sectors;
+                    cache.last_write_sectors = current.last_write_sectors;
+                    cache.last_update = current.last_update;
+                } else if read_delta == 0 && write_delta == 0 {
+                    no_iops = true;
+                    log::debug!("device {:?} did not change", current.name)
+                } else {
+                    no_iops = is_idle(&cache.stats(), &current.stats(), cache.iops_tolerance);
+                    if no_iops {
+                        log::debug!("device {:?} is within tolerance", current.name)
+                    }
+
+                    cache.last_read_iops = current.last_read_iops;
+                    cache.last_write_iops = current.last_write_iops;
+                    cache.last_read_sectors = current.last_read_sectors;
+                    cache.last_write_sectors = current.last_write_sectors;
+
+                    if !no_iops {
+                        cache.last_update = current.last_update;
+                    }
+                }
+
+                if !no_iops && cache.idle && !current.partitions.is_empty() {
+                    let activity = current.partition_activity(&cache.partitions);
+                    if activity.is_empty() {
+                        log::info!("activity on idle device {} outside of its partitions", cache.name);
+                    } else {
+                        log::info!("activity on idle device {}: {}", cache.name, activity.join(", "));
+                    }
+                }
+                cache.idle = no_iops;
+                if let Some(activity) = cache.activity().filter(|activity| previous_activity != Some(activity)) {
+                    log::info!("{} is {} (ActiveOrIdle {} I/O)", cache.name, activity,
+                               if no_iops { "without" } else { "with" });
+                }
+                cache.partitions = current.partitions.clone();
+                checked[index] = true;
+
+                let idle_elapsed = now.saturating_duration_since(cache.last_update).as_secs();
+                log::debug!("device {:?}: read_delta {}, write_delta {}, tolerance {}, idle {}s/{}s",
+                            cache.name, read_delta, write_delta, cache.iops_tolerance, idle_elapsed, cache.timeout);
+                idle_expired[index] = no_iops && cache.timeout > 0 && (forced || idle_elapsed > cache.timeout);
+                if forced && !idle_expired[index] {
+                    if cache.timeout == 0 {
+                        println!("skipping {}: watch-only", cache.name);
+                    } else {
+                        println!("skipping {}: {} reads and {} writes since the last check",
+                                 cache.name, read_delta, write_delta);
+                    }
+                }
+
+                if state_changed {
+                    context.notifiers.state_changed(cache, now);
+                } else {
+                    context.notifiers.checked(cache, now);
+                }
+
+                log::debug!("updated cache {:?}", cache);
+            }
+            Some(Err(e)) => {
+                let message = if e.kind == DeviceErrorKind::Busy {
+                    format!("{} is busy, skipping this check: {}", e.filepath, e.message)
+                } else {
+                    cache.failed_checks += 1;
+                    format!("unable to get device information for {}: {}", e.filepath, e.message)
+                };
+                cache.error_log.error(&cache.name, &message, now);
+                context.notifiers.notify(Event::error(&cache.name, &e.message), cache, None, now);
+                cache.error = Some(e.message);
+                cache.statistics.error_count += 1;
+                continue;
+            }
+        }
+
+        if cache.timeout == 0 && context.suspend_ignore_watch_only {
+            log::debug!("device {:?} is watch-only, ignoring it for suspend", cache.name);
+        } else {
+            if cache.power_state != PowerState::Standby {
+                round.disks_running = true;
+            }
+            if round.latest_update.is_none_or(|latest| cache.last_update > latest) {
+                round.latest_update = Some(cache.last_update);
+            }
+        }
+    }
+
+    // the checks may have seen new I/O, which restarts the group timer
+    let now = context.probes.clock.now();
+    let group_expired = group_due &&
+        group_deadline(devices, context.group_timeout).is_some_and(|deadline| deadline <= now);
+    if group_expired {
+        println!("no I/O on any device for {}, putting all of them to sleep",
+                 format_duration(context.group_timeout.unwrap_or_default()));
+        for (index, dev) in devices.iter().enumerate() {
+            idle_expired[index] |= checked[index] && dev.idle && dev.timeout > 0;
+        }
+    }
+
+    if let Some(threshold) = context.dirty_threshold {
+        postpone_dirty(devices, &mut idle_expired, threshold, context.paths, now);
+    }
+
+    if context.inhibited {
+        log::debug!("inhibited, not putting devices to sleep");
+    } else {
+        round.actions = issue_standby(devices, workers, &idle_expired, context.notifiers, now);
+    }
+
+    // nothing to do until a disk wakes up, the check after that is back at the normal interval
+    if let Some(idle_poll_interval) = context.idle_poll_interval {
+        let all_asleep = devices.iter()
+            .all(|dev| dev.power_state == PowerState::Standby && dev.idle && dev.error.is_none());
+        if all_asleep && !devices.is_empty() {
+            log::debug!("all devices asleep, checking them every {}", format_duration(idle_poll_interval.as_secs()));
+            for (index, dev) in devices.iter_mut().enumerate() {
+                if checked[index] {
+                    dev.next_check += idle_poll_interval.saturating_sub(Duration::from_secs(dev.check_interval));
+                }
+            }
+        }
+    }
+    round
+}
+
+/// Spreads the checks of the devices over their intervals, device i is checked next at i/n of
+/// its interval from `now` and keeps that phase afterwards, so they don't all send commands
+/// to the bus at once
+pub fn stagger_checks(devices: &mut [Box<DeviceInfo>], now: Instant) {
+    let count = devices.len() as u32;
+    for (index, dev) in devices.iter_mut().enumerate() {
+        let interval = Duration::from_secs(dev.check_interval);
+        let offset = interval * index as u32 / count;
+        dev.next_check = now + offset;
+        log::debug!("checking {} every {} at +{:.1}s", dev.name, format_duration(dev.check_interval),
+                    offset.as_secs_f64());
+    }
+}
+
+// A device is checked after its own interval, or earlier once it will have reached its
+// timeout, so the standby is not delayed by up to a full check interval.
+pub fn next_check_time(dev: &DeviceInfo) -> Instant {
+    if dev.timeout == 0 || dev.power_target.is_reached(dev.power_state) {
+        return dev.next_check;
+    }
+    // standby is due once the idle time exceeds the timeout
+    dev.next_check.min(dev.last_update + Duration::from_secs(dev.timeout + 1))
+}
+
+/// The first deadline after `now` in steps of `interval` from the `scheduled` one, with the
+/// number of deadlines passed in between, which were missed
+pub fn next_deadline(scheduled: Instant, interval: Duration, now: Instant) -> (Instant, u32) {
+    let interval = interval.max(Duration::from_secs(1));
+    let behind = now.saturating_duration_since(scheduled);
+    let missed = (behind.as_nanos() / interval.as_nanos()).min(u32::MAX as u128 - 1) as u32;
+    (scheduled + interval * (missed + 1), missed)
+}
+
+// With a group timeout, all devices are due once none of them had I/O for that long, as
+// long as one of them is still to be put to sleep.
+fn group_deadline(devices: &[Box<DeviceInfo>], group_timeout: Option<u64>) -> Option<Instant> {
+    let group_timeout = group_timeout?;
+    if !devices.iter().any(|dev| dev.timeout > 0 && !dev.power_target.is_reached(dev.power_state)) {
+        return None;
+    }
+    let latest = devices.iter().map(|dev| dev.last_update).max()?;
+    Some(latest + Duration::from_secs(group_timeout + 1))
+}
+
+// Time until the next device is due. An overdue device that cannot be put to sleep (e.g. a
+// hanging check) waits a second, so it does not cause a busy loop.
+pub fn next_check_delay(devices: &[Box<DeviceInfo>], group_timeout: Option<u64>, now: Instant) -> Duration {
+    devices.iter()
+        .map(|dev| next_check_time(dev))
+        .chain(group_deadline(devices, group_timeout))
+        .map(|time| time.checked_duration_since(now).filter(|delay| !delay.is_zero()).unwrap_or(Duration::from_secs(1)))
+        .min()
+        .unwrap_or(Duration::from_secs(1))
+}
+
+/// Queues the power command for the disks of a device right away (ctl standby), regardless of
+/// its timeout and of other devices sharing them. The outcome is handled with the next round.
+pub fn standby_device(dev: &mut DeviceInfo, worker: &DeviceWorker, now: Instant) -> Result<(), String> {
+    if dev.standby_method == StandbyMethod::None {
+        return Err(format!("{} has no ATA pass-through, it can only be watched", dev.name));
+    }
+    for member in dev.members.iter() {
+        worker.request_power_target(member, dev.power_target)
+            .map_err(|e| format!("unable to issue {} for {}: {}", dev.power_target, e.filepath, e.message))?;
+    }
+    dev.last_update = now;
+    dev.power_confirmed = None;
+    Ok(())
+}
+
+/// Restarts the timers after the system resumed: the disks were powered down and may have
+/// spun up again, so the power states are queried anew on the next round, which is due right
+/// away, and the I/O of the resume does not count as activity.
+pub fn reset_timers(devices: &mut [Box<DeviceInfo>], paths: &Paths, probes: &Probes) {
+    let now = probes.clock.now();
+    for dev in devices.iter_mut() {
+        if let Some(stretch) = dev.standby_since.take() {
+            dev.statistics.add_standby_stretch(now.saturating_duration_since(stretch.since).as_secs());
+        }
+        dev.power_state = PowerState::Unknown;
+        dev.power_confirmed = None;
+        dev.last_update = now;
+        dev.next_check = now;
+        match probes.stats.device_stats(&dev.name, paths) {
+            Ok(stats) => {
+                dev.last_read_iops = stats.read_iops;
+                dev.last_write_iops = stats.write_iops;
+                dev.last_read_sectors = stats.read_sectors;
+                dev.last_write_sectors = stats.write_sectors;
+            }
+            Err(e) => log::debug!("unable to read the stats of {}: {}", dev.name, e.message),
+        }
+    }
+}
+
+// Dirty data would be written as soon as the disk is put to sleep, spinning it up again; the
+// write-back shows up as I/O, so the timer is restarted as if it already happened.
+fn postpone_dirty(devices: &mut [Box<DeviceInfo>], idle_expired: &mut [bool], threshold: u64, paths: &Paths,
+                  now: Instant) {
+    for (index, dev) in devices.iter_mut().enumerate() {
+        if !idle_expired[index] || dev.power_target.is_reached(dev.power_state) {
+            continue;
+        }
+        let pending = match get_pending_writeback(&dev.name, paths) {
+            Some(pending) if pending.kib > threshold => pending,
+            _ => continue,
+        };
+        let owner = if pending.system_wide { "the system" } else { dev.name.as_str() };
+        println!("postponing standby of {}: {} KiB of dirty data waiting to be written by {}",
+                 dev.name, pending.kib, owner);
+        idle_expired[index] = false;
+        dev.last_update = now;
+    }
+}
+
+// Puts the disks of every expired device to sleep. A disk shared by several devices
+// (e.g. two logical volumes on one physical volume) is only put to sleep once all of
+// them have expired. The commands run on the threads of the devices, their outcome is
+// handled by handle_power_results. Returns the commands queued.
+fn issue_standby(devices: &mut [Box<DeviceInfo>], workers: &HashMap<String, DeviceWorker>, idle_expired: &[bool],
+                 notifiers: &Notifiers, now: Instant) -> Vec<Action> {
+    let mut actions: Vec<Action> = vec![];
+    let mut standby_disks: Vec<(String, PowerTarget, String)> = vec![];
+    for (index, dev) in devices.iter().enumerate() {
+        if !idle_expired[index] || dev.power_target.is_reached(dev.power_state) {
+            continue;
+        }
+        for member in dev.members.iter() {
+            if standby_disks.iter().any(|(disk, _, _)| disk == member) {
+                continue;
+            }
+            let shared_busy = devices.iter().enumerate()
+                .any(|(other, other_dev)| !idle_expired[other] && other_dev.members.contains(member));
+            if shared_busy {
+                log::debug!("disk {} of {} is still in use by another device", member, dev.name);
+                continue;
+            }
+            standby_disks.push((member.to_string(), dev.power_target, dev.name.clone()));
+        }
+    }
+
+    for (disk, target, owner) in standby_disks.iter() {
+        log::debug!("issuing {} for {}", target, disk);
+        let queued = match workers.get(owner) {
+            Some(worker) => worker.request_power_target(disk, *target),
+            None => Err(DeviceError::new(disk.to_string(), "no check thread".to_string())),
+        };
+        match queued {
+            Ok(()) => actions.push(Action::PowerTarget { device: owner.clone(), disk: disk.clone(), target: *target }),
+            Err(e) => {
+                println!("{}", console::failure(format!("unable to issue {} for {}: {}", target, e.filepath, e.message)));
+                if let Some(dev) = devices.iter_mut().find(|dev| dev.name == *owner) {
+                    dev.statistics.error_count += 1;
+                    notifiers.notify(Event::error(disk, &e.message), dev, Some(*target), now);
+                }
+            }
+        }
+    }
+
+    // the timers restart as soon as the commands are queued, so they are not queued twice
+    for (index, dev) in devices.iter_mut().enumerate() {
+        if idle_expired[index] && !dev.power_target.is_reached(dev.power_state) &&
+            dev.members.iter().any(|member| standby_disks.iter().any(|(disk, _, _)| disk == member)) {
+            dev.last_update = now;
+            dev.power_confirmed = None;
+        }
+    }
+    actions
+}
+
+fn handle_power_results(devices: &mut [Box<DeviceInfo>], workers: &HashMap<String, DeviceWorker>,
+                        notifiers: &Notifiers, now: Instant) {
+    for worker in workers.values() {
+        for power_result in worker.power_results() {
+            let disk = &power_result.disk;
+            for dev in devices.iter_mut().filter(|dev| dev.members.contains(disk)) {
+                dev.power_confirmed = None;
+            }
+            let event = match (power_result.result, power_result.not_reached) {
+                (Ok(()), Some(power_state)) => {
+                    let message = format!("{} still reports {} after {} attempts of {}, consider excluding it",
+                                          disk, power_state, power_result.attempts, power_result.target);
+                    println!("{}", console::failure(message.clone()));
+                    for dev in devices.iter_mut().filter(|dev| dev.members.contains(disk)) {
+                        dev.statistics.standby_count += 1;
+                        dev.statistics.standby_retries += u64::from(power_result.attempts - 1);
+                        dev.statistics.error_count += 1;
+                        dev.not_parking = Some(power_state);
+                    }
+                    Event::error(disk, &message)
+                }
+                (Ok(()), None) => {
+                    if power_result.attempts > 1 {
+                        println!("{}", console::event(format!("issued {} for {} ({} attempts)", power_result.target,
+                                                              disk, power_result.attempts)));
+                    } else {
+                        println!("{}", console::event(format!("issued {} for {}", power_result.target, disk)));
+                    }
+                    for dev in devices.iter_mut().filter(|dev| dev.members.contains(disk)) {
+                        dev.statistics.standby_count += 1;
+                        dev.statistics.standby_retries += u64::from(power_result.attempts - 1);
+                        dev.not_parking = None;
+                    }
+                    Event::new(EventKind::Standby, disk)
+                }
+                (Err(e), _) => {
+                    println!("{}", console::failure(format!("unable to issue {} for {}: {}", power_result.target,
+                                                            e.filepath, e.message)));
+                    for dev in devices.iter_mut().filter(|dev| dev.members.contains(disk)) {
+                        dev.statistics.error_count += 1;
+                    }
+                    Event::error(disk, &e.message)
+                }
+            };
+            if let Some(dev) = devices.iter().find(|dev| dev.members.contains(disk)) {
+                notifiers.notify(event, dev, Some(power_result.target), now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::spindown_daemon::get_device_info;
+    use crate::spindown_daemon::backend::{Clock, PowerBackend, SysfsStats};
+    use crate::spindown_daemon::hooks::{HookCommands, Hooks};
+    use crate::spindown_daemon::worker::RetryPolicy;
+
+    pub(crate) const TIMEOUT: u64 = 600;
+
+    // Disks are active until a power command puts them to sleep
+    #[derive(Default)]
+    pub(crate) struct MockBackend {
+        pub(crate) states: Mutex<HashMap<String, PowerState>>,
+        pub(crate) commands: Mutex<Vec<(String, PowerTarget)>>,
+        // pass-through power state queries
+        pub(crate) queries: Mutex<u32>,
+        // power commands without effect, like the first one through some USB bridges
+        pub(crate) ignored_commands: Mutex<u32>,
+        // power commands failing like a rejected SG_IO
+        pub(crate) failing_commands: Mutex<bool>,
+    }
+
+    impl PowerBackend for MockBackend {
+        fn power_state(&self, disk: &str, _paths: &Paths) -> Result<PowerState, DeviceError> {
+            *self.queries.lock().unwrap() += 1;
+            Ok(self.states.lock().unwrap().get(disk).copied().unwrap_or(PowerState::ActiveOrIdle))
+        }
+
+        fn power_target(&self, disk: &str, _paths: &Paths, target: PowerTarget) -> Result<(), DeviceError> {
+            self.commands.lock().unwrap().push((disk.to_string(), target));
+            if *self.failing_commands.lock().unwrap() {
+                return Err(DeviceError::new(format!("/dev/{}", disk), "Input/output error".to_string()));
+            }
+            let mut ignored_commands = self.ignored_commands.lock().unwrap();
+            if *ignored_commands > 0 {
+                *ignored_commands -= 1;
+                return Ok(());
+            }
+            self.states.lock().unwrap().insert(disk.to_string(), PowerState::Standby);
+            Ok(())
+        }
+    }
+
+    // Time only passes when a test advances it
+    pub(crate) struct ManualClock(Mutex<Instant>);
+
+    impl ManualClock {
+        pub(crate) fn new() -> ManualClock {
+            ManualClock(Mutex::new(Instant::now()))
+        }
+
+        pub(crate) fn advance(&self, seconds: u64) {
+            *self.0.lock().unwrap() += Duration::from_secs(seconds);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    pub(crate) fn quiet_notifiers() -> Notifiers {
+        Notifiers {
+            webhook: None,
+            hooks: Hooks::new(HookCommands::default(), Duration::from_secs(1)),
+            event_log: None,
+            journal: None,
+            #[cfg(feature = "mqtt")]
+            mqtt: None,
+        }
+    }
+
+    // A single disk sdx below a temporary sysfs root, checked by a worker using the mock backend
+    // and the manual clock
+    pub(crate) struct Harness {
+        root: PathBuf,
+        pub(crate) paths: Paths,
+        pub(crate) backend: Arc<MockBackend>,
+        clock: Arc<ManualClock>,
+        pub(crate) probes: Probes,
+        device: Box<DeviceInfo>,
+        workers: HashMap<String, DeviceWorker>,
+        pub(crate) notifiers: Notifiers,
+        inhibited: bool,
+        group_timeout: Option<u64>,
+        idle_poll_interval: Option<Duration>,
+    }
+
+    impl Harness {
+        pub(crate) fn new(name: &str) -> Harness {
+            let root = std::env::temp_dir().join(format!("spindown-test-round-{}-{}", name, std::process::id()));
+            fs::create_dir_all(root.join("block/sdx")).unwrap();
+            let paths = Paths::new(&root.to_string_lossy(), "/dev");
+            let backend = Arc::new(MockBackend::default());
+            let clock = Arc::new(ManualClock::new());
+            let probes = Probes { stats: Arc::new(SysfsStats), power: backend.clone(), clock: clock.clone() };
+            fs::write(root.join("block/sdx/stat"), "0 0 0 0 0 0 0 0 0 0 0").unwrap();
+            let mut dev = get_device_info("sdx", &paths, &probes).ok().unwrap();
+            dev.timeout = TIMEOUT;
+            dev.check_interval = 60;
+            let worker = DeviceWorker::spawn("sdx", &paths, probes.clone(), dev.standby_method,
+                                             RetryPolicy::new(0)).unwrap();
+            Harness {
+                root,
+                paths,
+                backend,
+                clock,
+                probes,
+                device: Box::new(dev),
+                workers: HashMap::from([("sdx".to_string(), worker)]),
+                notifiers: quiet_notifiers(),
+                inhibited: false,
+                group_timeout: None,
+                idle_poll_interval: None,
+            }
+        }
+
+        // power commands are verified and repeated, without the delay of the daemon
+        fn retry(&mut self, retries: u32) {
+            let policy = RetryPolicy { retries, delay: Duration::from_millis(10) };
+            let worker = DeviceWorker::spawn("sdx", &self.paths, self.probes.clone(), self.device.standby_method,
+                                             policy).unwrap();
+            if let Some(previous) = self.workers.insert("sdx".to_string(), worker) {
+                previous.shutdown(Instant::now() + Duration::from_secs(1));
+            }
+        }
+
+        fn write_stat(&self, read_iops: u64, write_iops: u64) {
+            fs::write(self.root.join("block/sdx/stat"),
+                      format!("{} 0 0 0 {} {} 0 0 0 0 0", read_iops, write_iops, write_iops)).unwrap();
+        }
+
+        pub(crate) fn advance(&self, seconds: u64) {
+            self.clock.advance(seconds);
+        }
+
+        pub(crate) fn now(&self) -> Instant {
+            self.clock.now()
+        }
+
+        // time since the last activity
+        fn idle_time(&self) -> Duration {
+            self.now() - self.device.last_update
+        }
+
+        fn run(&mut self, forced: bool) -> RoundResult {
+            let context = RoundContext {
+                reconcile_interval: Duration::from_secs(900),
+                check_deadline: Duration::from_secs(5),
+                suspend_ignore_watch_only: false,
+                group_timeout: self.group_timeout,
+                dirty_threshold: None,
+                idle_poll_interval: self.idle_poll_interval,
+                paths: &self.paths,
+                probes: &self.probes,
+                notifiers: &self.notifiers,
+                inhibited: self.inhibited,
+            };
+            run_once(std::slice::from_mut(&mut self.device), &mut self.workers, &context, true, forced)
+        }
+
+        fn commands(&self) -> Vec<(String, PowerTarget)> {
+            self.backend.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            for (_, worker) in self.workers.drain() {
+                worker.shutdown(Instant::now() + Duration::from_secs(1));
+            }
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn standby_is_issued_once_the_timeout_has_passed() {
+        let mut harness = Harness::new("timeout");
+        harness.write_stat(100, 10);
+        harness.run(false);
+        assert!(!harness.device.idle);
+
+        harness.advance(TIMEOUT);
+        harness.run(false);
+        assert!(harness.device.idle);
+        assert!(harness.commands().is_empty());
+
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        // the command runs before the next check, the outcome is handled by the round after
+        let round = harness.run(false);
+        assert_eq!(harness.commands(), vec![("sdx".to_string(), PowerTarget::Standby)]);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+        assert!(!round.disks_running);
+
+        harness.advance(3 * TIMEOUT);
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.commands().len(), 1);
+        assert_eq!(harness.device.statistics.standby_count, 1);
+    }
+
+    #[test]
+    fn activity_restarts_the_timeout() {
+        let mut harness = Harness::new("activity");
+        harness.advance(TIMEOUT - 10);
+        harness.write_stat(5, 0);
+        harness.run(false);
+        harness.advance(TIMEOUT - 10);
+        harness.run(false);
+        harness.run(false);
+        assert!(harness.commands().is_empty());
+        assert!(harness.idle_time() >= Duration::from_secs(TIMEOUT - 10));
+    }
+
+    #[test]
+    fn io_within_the_tolerance_is_no_activity() {
+        let mut harness = Harness::new("tolerance");
+        harness.device.iops_tolerance = 5;
+        harness.advance(TIMEOUT + 1);
+        harness.write_stat(3, 5);
+        harness.run(false);
+        assert!(harness.device.idle);
+        harness.run(false);
+        assert_eq!(harness.commands(), vec![("sdx".to_string(), PowerTarget::Standby)]);
+    }
+
+    #[test]
+    fn own_power_check_is_no_activity_without_tolerance() {
+        let mut harness = Harness::new("own-check");
+        harness.device.iops_tolerance = 0;
+        harness.advance(TIMEOUT - 10);
+        let last_update = harness.device.last_update;
+        let queries = *harness.backend.queries.lock().unwrap();
+        for round in 1..=3 {
+            // CHECK POWER MODE every round, the stat file stays the same
+            harness.device.power_confirmed = None;
+            harness.device.next_check = harness.now();
+            harness.run(false);
+            assert_eq!(*harness.backend.queries.lock().unwrap(), queries + round);
+            assert!(harness.device.idle);
+            assert_eq!(harness.device.last_update, last_update);
+        }
+    }
+
+    #[test]
+    fn io_equal_to_the_tolerance_is_no_activity() {
+        let mut harness = Harness::new("equal-tolerance");
+        harness.device.iops_tolerance = 5;
+        harness.advance(TIMEOUT - 10);
+        let last_update = harness.device.last_update;
+        harness.write_stat(5, 5);
+        harness.run(false);
+        assert!(harness.device.idle);
+        assert_eq!(harness.device.last_update, last_update);
+        assert_eq!((harness.device.last_read_iops, harness.device.last_write_iops), (5, 5));
+    }
+
+    #[test]
+    fn counters_going_backwards_reset_the_timers() {
+        let mut harness = Harness::new("counters-reset");
+        harness.write_stat(100, 50);
+        harness.run(false);
+        harness.advance(TIMEOUT - 10);
+        harness.write_stat(10, 5);
+        harness.run(false);
+        assert!(harness.idle_time() < Duration::from_secs(10));
+        assert_eq!((harness.device.last_read_iops, harness.device.last_write_iops), (10, 5));
+        assert!(!harness.device.idle);
+
+        // the lower counters are the new base
+        harness.run(false);
+        assert!(harness.device.idle);
+        assert!(harness.commands().is_empty());
+    }
+
+    #[test]
+    fn io_above_the_tolerance_restarts_the_timeout() {
+        let mut harness = Harness::new("above-tolerance");
+        harness.device.iops_tolerance = 5;
+        harness.advance(TIMEOUT + 1);
+        harness.write_stat(0, 6);
+        harness.run(false);
+        assert!(!harness.device.idle);
+        harness.run(false);
+        assert!(harness.commands().is_empty());
+        assert!(harness.idle_time() < Duration::from_secs(TIMEOUT));
+    }
+
+    #[test]
+    fn disk_already_in_standby_gets_no_power_command() {
+        let mut harness = Harness::new("already-standby");
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::Standby);
+        harness.device.power_confirmed = None;
+        harness.advance(TIMEOUT + 1);
+        let round = harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+        assert!(harness.commands().is_empty());
+        assert!(!round.disks_running);
+        assert_eq!(harness.device.statistics.wake_count, 0);
+    }
+
+    #[test]
+    fn disk_in_standby_is_not_queried_without_io() {
+        let mut harness = Harness::new("standby-not-queried");
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::Standby);
+        harness.device.power_confirmed = None;
+        harness.run(false);
+        let queries = *harness.backend.queries.lock().unwrap();
+        // not even after the reconcile interval
+        harness.advance(1000);
+        for _ in 0..3 {
+            harness.device.next_check = harness.now();
+            harness.run(false);
+        }
+        assert_eq!(*harness.backend.queries.lock().unwrap(), queries);
+
+        harness.write_stat(4, 0);
+        harness.device.next_check = harness.now();
+        harness.run(false);
+        assert_eq!(*harness.backend.queries.lock().unwrap(), queries + 1);
+    }
+
+    #[test]
+    fn io_within_the_tolerance_is_a_wake_up_of_a_disk_in_standby() {
+        let mut harness = Harness::new("standby-tolerance");
+        harness.device.iops_tolerance = 5;
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::Standby);
+        harness.device.power_confirmed = None;
+        harness.run(false);
+        let queries = *harness.backend.queries.lock().unwrap();
+
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::ActiveOrIdle);
+        harness.write_stat(1, 0);
+        harness.device.next_check = harness.now();
+        harness.run(false);
+        assert_eq!(*harness.backend.queries.lock().unwrap(), queries + 1);
+        assert_eq!(harness.device.statistics.wake_count, 1);
+    }
+
+    #[test]
+    fn failed_power_command_is_counted_and_issued_after_the_next_timeout() {
+        let mut harness = Harness::new("failed-command");
+        *harness.backend.failing_commands.lock().unwrap() = true;
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(false);
+        // the outcome may only be handled by the round after the check following the command
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::ActiveOrIdle);
+        assert_eq!(harness.device.statistics.error_count, 1);
+        assert_eq!(harness.device.statistics.standby_count, 0);
+        // the timer restarted when the command was queued
+        assert_eq!(harness.commands().len(), 1);
+
+        *harness.backend.failing_commands.lock().unwrap() = false;
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+        assert_eq!(harness.device.statistics.standby_count, 1);
+    }
+
+    #[test]
+    fn forced_round_ignores_the_timeout_but_not_activity() {
+        let mut harness = Harness::new("forced");
+        harness.write_stat(7, 0);
+        harness.run(true);
+        assert!(harness.commands().is_empty());
+
+        harness.run(true);
+        harness.run(false);
+        assert_eq!(harness.commands(), vec![("sdx".to_string(), PowerTarget::Standby)]);
+    }
+
+    #[test]
+    fn inhibited_round_does_not_issue_standby() {
+        let mut harness = Harness::new("inhibited");
+        harness.inhibited = true;
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(true);
+        assert!(harness.commands().is_empty());
+
+        harness.inhibited = false;
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.commands().len(), 1);
+    }
+
+    #[test]
+    fn wake_up_is_counted() {
+        let mut harness = Harness::new("wake");
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::ActiveOrIdle);
+        harness.write_stat(3, 0);
+        let round = harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::ActiveOrIdle);
+        assert_eq!(harness.device.statistics.wake_count, 1);
+        assert!(round.disks_running);
+    }
+
+    #[test]
+    fn standby_and_active_seconds_follow_the_clock() {
+        let mut harness = Harness::new("standby-seconds");
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+
+        harness.advance(3600);
+        let now = harness.now();
+        assert_eq!(harness.device.total_standby_seconds(now), 3600);
+        assert_eq!(harness.device.active_seconds(now), TIMEOUT + 1);
+        assert_eq!(harness.device.idle_seconds(now), 3600);
+
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::ActiveOrIdle);
+        harness.write_stat(3, 0);
+        harness.run(false);
+        harness.advance(60);
+        let now = harness.now();
+        assert_eq!(harness.device.statistics.standby_seconds, 3600);
+        assert_eq!(harness.device.longest_standby_seconds(now), 3600);
+        assert_eq!(harness.device.active_seconds(now), TIMEOUT + 1 + 60);
+    }
+
+    #[test]
+    fn failed_checks_are_counted_until_a_check_succeeds() {
+        let mut harness = Harness::new("failed");
+        fs::remove_file(harness.root.join("block/sdx/stat")).unwrap();
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.device.failed_checks, 2);
+        assert!(harness.device.error.is_some());
+
+        harness.write_stat(0, 0);
+        harness.run(false);
+        assert_eq!(harness.device.failed_checks, 0);
+        assert!(harness.device.error.is_none());
+    }
+
+    #[test]
+    fn reset_clears_the_counters_and_the_failed_checks() {
+        let mut harness = Harness::new("reset");
+        harness.retry(1);
+        *harness.backend.ignored_commands.lock().unwrap() = 5;
+        harness.run(false);
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(false);
+        harness.run(false);
+        fs::remove_file(harness.root.join("block/sdx/stat")).unwrap();
+        harness.run(false);
+        assert_eq!(harness.device.statistics.standby_count, 1);
+        assert_eq!(harness.device.not_parking, Some(PowerState::ActiveOrIdle));
+        assert_eq!(harness.device.failed_checks, 1);
+
+        let now = harness.now();
+        harness.device.reset_counters(now);
+        assert_eq!(harness.device.statistics.standby_count, 0);
+        assert_eq!(harness.device.statistics.wake_count, 0);
+        assert_eq!(harness.device.not_parking, None);
+        assert_eq!(harness.device.failed_checks, 0);
+    }
+
+    #[test]
+    fn active_or_idle_is_told_apart_by_the_io() {
+        let mut harness = Harness::new("activity-kind");
+        harness.write_stat(3, 0);
+        harness.run(false);
+        assert_eq!(harness.device.activity(), Some("active"));
+
+        harness.run(false);
+        assert_eq!(harness.device.activity(), Some("idle-spinning"));
+    }
+
+    #[test]
+    fn checks_keep_their_schedule_when_a_round_is_late() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+        let (next, missed) = next_deadline(start, interval, start + Duration::from_secs(7));
+        assert_eq!(next, start + interval);
+        assert_eq!(missed, 0);
+
+        let (next, missed) = next_deadline(start, interval, start + Duration::from_secs(150));
+        assert_eq!(next, start + Duration::from_secs(180));
+        assert_eq!(missed, 2);
+    }
+
+    #[test]
+    fn standby_is_repeated_until_the_disk_reports_it() {
+        let mut harness = Harness::new("retry");
+        harness.retry(2);
+        *harness.backend.ignored_commands.lock().unwrap() = 1;
+        harness.run(false);
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        // the retries finish before the check of this round, the outcome is handled by the one after
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.commands().len(), 2);
+        assert_eq!(harness.device.statistics.standby_retries, 1);
+        assert_eq!(harness.device.not_parking, None);
+    }
+
+    #[test]
+    fn disk_not_reaching_standby_after_the_retries_is_flagged() {
+        let mut harness = Harness::new("not-parking");
+        harness.retry(1);
+        *harness.backend.ignored_commands.lock().unwrap() = 5;
+        harness.run(false);
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        // the retries finish before the check of this round, the outcome is handled by the one after
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.commands().len(), 2);
+        assert_eq!(harness.device.not_parking, Some(PowerState::ActiveOrIdle));
+        assert_eq!(harness.device.statistics.error_count, 1);
+    }
+
+    #[test]
+    fn devices_all_asleep_are_checked_at_the_idle_poll_interval() {
+        let mut harness = Harness::new("idle-poll");
+        harness.idle_poll_interval = Some(Duration::from_secs(600));
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::Standby);
+        harness.device.power_confirmed = None;
+        harness.run(false);
+        assert!(harness.device.next_check > harness.now() + Duration::from_secs(500));
+
+        // the disk woke up, which the check at the long interval notices
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::ActiveOrIdle);
+        harness.write_stat(4, 0);
+        harness.device.next_check = harness.now();
+        harness.run(false);
+        assert!(harness.device.next_check <= harness.now() + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn staggered_checks_are_spread_over_the_interval() {
+        let harness = Harness::new("stagger");
+        let mut devices: Vec<Box<DeviceInfo>> = (0..4)
+            .map(|_| Box::new(get_device_info("sdx", &harness.paths, &harness.probes).ok().unwrap()))
+            .collect();
+        for dev in devices.iter_mut() {
+            dev.check_interval = 60;
+        }
+        let now = Instant::now();
+        stagger_checks(&mut devices, now);
+        let offsets: Vec<u64> = devices.iter().map(|dev| (dev.next_check - now).as_secs()).collect();
+        assert_eq!(offsets, vec![0, 15, 30, 45]);
+    }
+
+    #[test]
+    fn resume_restarts_the_timeout_without_counting_a_wake_up() {
+        let mut harness = Harness::new("resume");
+        harness.advance(TIMEOUT + 1);
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+
+        // the resume spins the disk up and reads from it
+        harness.backend.states.lock().unwrap().insert("sdx".to_string(), PowerState::ActiveOrIdle);
+        harness.write_stat(5, 0);
+        reset_timers(std::slice::from_mut(&mut harness.device), &harness.paths, &harness.probes);
+        harness.run(false);
+        assert_eq!(harness.device.power_state, PowerState::ActiveOrIdle);
+        assert_eq!(harness.device.statistics.wake_count, 0);
+        assert!(harness.device.idle);
+        assert_eq!(harness.commands().len(), 1);
+    }
+
+    #[test]
+    fn group_timeout_puts_devices_to_sleep_before_their_own_timeout() {
+        let mut harness = Harness::new("group");
+        harness.group_timeout = Some(60);
+        harness.advance(30);
+        harness.run(false);
+        assert!(harness.commands().is_empty());
+
+        harness.advance(61);
+        harness.run(false);
+        harness.run(false);
+        assert_eq!(harness.commands(), vec![("sdx".to_string(), PowerTarget::Standby)]);
+        assert_eq!(harness.device.power_state, PowerState::Standby);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::OFlag;
+use nix::libc::c_int;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{pipe2, read, write};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+// the signal requesting the shutdown
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+static STATUS: AtomicBool = AtomicBool::new(false);
+static FORCE: AtomicBool = AtomicBool::new(false);
+static REOPEN: AtomicBool = AtomicBool::new(false);
+// not a signal, set by the control socket
+static WAKE: AtomicBool = AtomicBool::new(false);
+// self-pipe: the handlers and wake() write a byte to end the poll of sleep() right away
+static WAKE_READ: AtomicI32 = AtomicI32::new(-1);
+static WAKE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+// only used without the pipe
+const SLEEP_STEP: Duration = Duration::from_secs(1);
+
+// write(2) is async-signal-safe; with the pipe full, the poll ends anyway
+fn poke() {
+    let fd = WAKE_WRITE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let _ = write(fd, &[0]);
+    }
+}
+
+extern "C" fn handle_shutdown(signal: c_int) {
+    SHUTDOWN_SIGNAL.store(signal, Ordering::SeqCst);
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    poke();
+}
+
+extern "C" fn handle_status(_: c_int) {
+    STATUS.store(true, Ordering::SeqCst);
+    poke();
+}
+
+extern "C" fn handle_force(_: c_int) {
+    FORCE.store(true, Ordering::SeqCst);
+    poke();
+}
+
+extern "C" fn handle_reopen(_: c_int) {
+    REOPEN.store(true, Ordering::SeqCst);
+    poke();
+}
+
+pub fn install_handlers() -> Result<(), String> {
+    match pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC) {
+        Ok((read_fd, write_fd)) => {
+            WAKE_READ.store(read_fd, Ordering::SeqCst);
+            WAKE_WRITE.store(write_fd, Ordering::SeqCst);
+        }
+        Err(e) => println!("unable to create wake-up pipe, signals are noticed within a second: {}", e),
+    }
+    let action = SigAction::new(SigHandler::Handler(handle_shutdown), SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(Signal::SIGTERM, &action) }.map_err(|e| e.to_string())?;
+    unsafe { sigaction(Signal::SIGINT, &action) }.map_err(|e| e.to_string())?;
+    let action = SigAction::new(SigHandler::Handler(handle_status), SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(Signal::SIGUSR1, &action) }.map_err(|e| e.to_string())?;
+    let action = SigAction::new(SigHandler::Handler(handle_force), SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(Signal::SIGUSR2, &action) }.map_err(|e| e.to_string())?;
+    let action = SigAction::new(SigHandler::Handler(handle_reopen), SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(Signal::SIGHUP, &action) }.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Whether the shutdown was requested by SIGINT (Ctrl-C) rather than SIGTERM
+pub fn interrupted() -> bool {
+    SHUTDOWN_SIGNAL.load(Ordering::SeqCst) == Signal::SIGINT as c_int
+}
+
+/// Returns whether a status dump was requested (SIGUSR1) since the last call.
+pub fn take_status_request() -> bool {
+    STATUS.swap(false, Ordering::SeqCst)
+}
+
+/// Returns whether an immediate standby of all idle devices was requested (SIGUSR2)
+/// since the last call.
+pub fn take_force_request() -> bool {
+    FORCE.swap(false, Ordering::SeqCst)
+}
+
+/// Returns whether the log files should be reopened (SIGHUP) since the last call.
+pub fn take_reopen_request() -> bool {
+    REOPEN.swap(false, Ordering::SeqCst)
+}
+
+/// Ends the current (or next) sleep early, e.g. to answer a request on the control socket.
+pub fn wake() {
+    WAKE.store(true, Ordering::SeqCst);
+    poke();
+}
+
+/// Sleeps for the given duration, returning early once a shutdown, a status dump, an
+/// immediate standby or reopening the logs was requested, or on wake(). The caller tells
+/// these apart by the take_* functions and goes back to sleep for the requests not
+/// needing a check round.
+pub fn sleep(duration: Duration) {
+    let start = Instant::now();
+    while !shutdown_requested() && !STATUS.load(Ordering::SeqCst) && !FORCE.load(Ordering::SeqCst) &&
+        !REOPEN.load(Ordering::SeqCst) && !WAKE.swap(false, Ordering::SeqCst) {
+        let remaining = duration.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        let read_fd = WAKE_READ.load(Ordering::SeqCst);
+        if read_fd < 0 {
+            thread::sleep(remaining.min(SLEEP_STEP));
+            continue;
+        }
+        // a byte written after the flags were checked is still in the pipe, so it is not missed
+        let timeout_ms = remaining.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int;
+        let _ = poll(&mut [PollFd::new(read_fd, PollFlags::POLLIN)], timeout_ms);
+        while read(read_fd, &mut [0u8; 64]).is_ok_and(|len| len > 0) {}
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+
+use crate::spindown_daemon::ata::PowerState;
+use crate::spindown_daemon::spec::DeviceSpec;
+use crate::spindown_daemon::status::format_duration;
+use crate::spindown_daemon::sysfs::{is_idle, parse_stat_file, DiskStats};
+
+/// A recorded snapshot of the stat file of a device
+#[derive(Debug, Clone)]
+pub struct Sample {
+    // seconds since the unix epoch
+    pub ts: u64,
+    pub device: String,
+    pub stats: DiskStats,
+}
+
+/// Reads a trace of `<unix time> <device> <content of /sys/block/<device>/stat>` lines, e.g.
+/// recorded by `echo "$(date +%s) sdb $(cat /sys/block/sdb/stat)"`. Empty lines and
+/// everything after a `#` are ignored.
+pub fn read_trace(path: &str) -> Result<Vec<Sample>, String> {
+    let content = read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    parse_trace(&content).map_err(|e| format!("{}:{}", path, e))
+}
+
+/// A trace line of a sample in the layout of the stat file, with the fields the daemon does
+/// not read as 0 and the power state as a comment, e.g. `1700000000 sdb 812 0 6496 0 40 0 320 0 0 0 0 # Standby`
+pub fn format_sample(ts: u64, device: &str, stats: &DiskStats, power_state: PowerState) -> String {
+    format!("{} {} {} 0 {} 0 {} 0 {} 0 0 0 0 # {}", ts, device, stats.read_iops, stats.read_sectors,
+            stats.write_iops, stats.write_sectors, power_state)
+}
+
+fn parse_trace(content: &str) -> Result<Vec<Sample>, String> {
+    let mut samples: Vec<Sample> = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(3, char::is_whitespace);
+        let (ts, device, stat) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(ts), Some(device), Some(stat)) => (ts, device, stat),
+            _ => return Err(format!("{}: expected `<unix time> <device> <stat fields>`", number + 1)),
+        };
+        let ts = ts.parse::<u64>().map_err(|_| format!("{}: invalid unix time `{}`", number + 1, ts))?;
+        let stats = parse_stat_file(device, stat).map_err(|e| format!("{}: {}", number + 1, e.message))?;
+        samples.push(Sample { ts, device: device.to_string(), stats });
+    }
+    // the devices may have been recorded one after another
+    samples.sort_by_key(|sample| sample.ts);
+    Ok(samples)
+}
+
+// what the daemon would know about a device at the time of a sample
+struct SimulatedDevice {
+    timeout: u64,
+    tolerance: u64,
+    stats: DiskStats,
+    first_ts: u64,
+    last_ts: u64,
+    last_update: u64,
+    // time of the standby and the counters at that time
+    standby_since: Option<(u64, DiskStats)>,
+    standby_count: u64,
+    wake_count: u64,
+    standby_seconds: u64,
+}
+
+/// Replays a trace with the timeouts of the specs and the I/O tolerance (unless set per device) and returns the
+/// standby timeline followed by a summary line per device. Every sample counts as a check:
+/// a device is put to sleep by the first sample without I/O past its timeout, so the daemon
+/// checking at the end of the timeout would spin it down up to one recording interval earlier.
+/// Any I/O on a sleeping device counts as a wake-up, as it reached the disk.
+pub fn simulate(samples: &[Sample], specs: &[DeviceSpec], tolerance: u64) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    let mut devices: HashMap<String, SimulatedDevice> = HashMap::new();
+    // in the order of their first sample
+    let mut names: Vec<String> = vec![];
+    for sample in samples {
+        let dev = match devices.get_mut(&sample.device) {
+            Some(dev) => dev,
+            None => {
+                // devices given by name take precedence over patterns
+                let spec = specs.iter().find(|spec| !spec.is_pattern() && spec.name == sample.device)
+                    .or_else(|| specs.iter().find(|spec| spec.matches(&sample.device)));
+                if let Some(spec) = spec {
+                    names.push(sample.device.clone());
+                    devices.insert(sample.device.clone(), SimulatedDevice {
+                        timeout: spec.timeout,
+                        tolerance: spec.iops_tolerance.unwrap_or(tolerance),
+                        stats: sample.stats.clone(),
+                        first_ts: sample.ts,
+                        last_ts: sample.ts,
+                        last_update: sample.ts,
+                        standby_since: None,
+                        standby_count: 0,
+                        wake_count: 0,
+                        standby_seconds: 0,
+                    });
+                }
+                continue;
+            }
+        };
+        let idle = is_idle(&dev.stats, &sample.stats, dev.tolerance);
+        let changed = dev.stats.read_iops != sample.stats.read_iops || dev.stats.write_iops != sample.stats.write_iops;
+        dev.stats = sample.stats.clone();
+        dev.last_ts = sample.ts;
+        if !idle {
+            dev.last_update = sample.ts;
+        }
+        match dev.standby_since.clone() {
+            // the I/O reached the disk, which the tolerance does not keep asleep
+            Some((since, stats)) if changed => {
+                lines.push(format!("{} {} spun up after {} in standby; +{} reads / +{} writes since standby",
+                                   sample.ts, sample.device, format_duration(sample.ts - since),
+                                   sample.stats.read_iops.saturating_sub(stats.read_iops),
+                                   sample.stats.write_iops.saturating_sub(stats.write_iops)));
+                dev.standby_seconds += sample.ts - since;
+                dev.wake_count += 1;
+                dev.standby_since = None;
+            }
+            None if idle && dev.timeout > 0 && sample.ts - dev.last_update > dev.timeout => {
+                lines.push(format!("{} {} standby after {} without I/O", sample.ts, sample.device,
+                                   format_duration(sample.ts - dev.last_update)));
+                dev.standby_count += 1;
+                dev.standby_since = Some((sample.ts, sample.stats.clone()));
+                // the timer restarts with the power command
+                dev.last_update = sample.ts;
+            }
+            _ => {}
+        }
+    }
+    for name in names {
+        let dev = &devices[&name];
+        let standby_seconds = dev.standby_seconds + dev.standby_since.as_ref().map_or(0, |(since, _)| dev.last_ts - since);
+        let recorded = dev.last_ts - dev.first_ts;
+        lines.push(format!("simulated device={} timeout={}s recorded_seconds={} standby_count={} wake_count={} \
+                            standby_seconds={} standby_percent={}",
+                           name, dev.timeout, recorded, dev.standby_count, dev.wake_count, standby_seconds,
+                           (standby_seconds * 100).checked_div(recorded).unwrap_or(0)));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::spindown_daemon::sysfs::StatSource;
+
+    use super::*;
+
+    fn trace(samples: &[(u64, u64)]) -> Vec<Sample> {
+        let content: Vec<String> = samples.iter()
+            .map(|(ts, reads)| format!("{} sdb {} 0 0 0 0 0 0 0 0 0 0", ts, reads))
+            .collect();
+        parse_trace(&content.join("\n")).unwrap()
+    }
+
+    fn specs(spec: &str) -> Vec<DeviceSpec> {
+        vec![spec.parse().unwrap()]
+    }
+
+    #[test]
+    fn standby_follows_the_first_sample_past_the_timeout() {
+        let samples = trace(&[(0, 5), (60, 5), (120, 5), (180, 5)]);
+        let lines = simulate(&samples, &specs("sdb:100"), 0);
+        assert_eq!(lines[0], "120 sdb standby after 2m0s without I/O");
+        assert_eq!(lines[1], "simulated device=sdb timeout=100s recorded_seconds=180 standby_count=1 wake_count=0 \
+                              standby_seconds=60 standby_percent=33");
+    }
+
+    #[test]
+    fn io_within_the_tolerance_does_not_restart_the_timeout() {
+        let samples = trace(&[(0, 5), (60, 6), (120, 6), (240, 9)]);
+        let lines = simulate(&samples, &specs("sd*:100"), 1);
+        assert_eq!(lines[0], "120 sdb standby after 2m0s without I/O");
+        assert_eq!(lines[1], "240 sdb spun up after 2m0s in standby; +3 reads / +0 writes since standby");
+        assert!(lines[2].contains("standby_count=1 wake_count=1 standby_seconds=120"));
+    }
+
+    #[test]
+    fn device_tolerance_overrides_the_global_one() {
+        let samples = trace(&[(0, 5), (60, 8), (120, 11), (180, 14)]);
+        let lines = simulate(&samples, &specs("sdb:100,tolerance=3"), 0);
+        assert_eq!(lines[0], "120 sdb standby after 2m0s without I/O");
+    }
+
+    #[test]
+    fn recorded_samples_are_read_back() {
+        let stats = DiskStats { read_iops: 812, write_iops: 40, read_sectors: 6496, write_sectors: 320,
+                                source: StatSource::Sysfs };
+        let samples = parse_trace(&format_sample(1700000000, "sdb", &stats, PowerState::Standby)).unwrap();
+        assert_eq!(samples[0].ts, 1700000000);
+        assert_eq!(samples[0].device, "sdb");
+        assert_eq!((samples[0].stats.read_iops, samples[0].stats.read_sectors), (812, 6496));
+        assert_eq!((samples[0].stats.write_iops, samples[0].stats.write_sectors), (40, 320));
+    }
+
+    #[test]
+    fn trace_errors_name_the_line() {
+        assert!(parse_trace("# comment\n\n10 sdb 1 2 3").unwrap_err().starts_with("3: "));
+        assert!(parse_trace("x sdb 0 0 0 0 0 0 0 0 0 0 0").unwrap_err().starts_with("1: invalid unix time"));
+        assert!(parse_trace("10 sdb").unwrap_err().starts_with("1: expected"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,209 @@
+use std::fs::read_to_string;
+use std::str::FromStr;
+
+use crate::spindown_daemon::ata::PowerTarget;
+use crate::spindown_daemon::hooks::HookCommands;
+
+// every check of an active device issues an ATA command, shorter intervals only add load
+pub const MIN_CHECK_INTERVAL: u64 = 5;
+
+/// A device given on the command line as `DEVICE:TIMEOUT[,OPTION=VALUE...]`
+#[derive(Debug, Clone)]
+pub struct DeviceSpec {
+    pub name: String,
+    pub timeout: u64,
+    // overrides the global --power-target
+    pub power_target: Option<PowerTarget>,
+    // overrides the global check interval
+    pub check_interval: Option<u64>,
+    // overrides the global --on-standby, --on-wake and --on-error
+    pub hooks: HookCommands,
+    // overrides the global --rated-load-cycles
+    pub rated_load_cycles: Option<u64>,
+    // overrides the global -t
+    pub iops_tolerance: Option<u64>,
+}
+
+impl DeviceSpec {
+    /// Kernel name of a device-mapper device given as /dev/mapper/NAME
+    pub fn mapper_name(&self) -> Option<&str> {
+        self.name.strip_prefix("/dev/mapper/")
+            .or_else(|| self.name.strip_prefix("mapper/"))
+    }
+
+    /// Whether the name is a glob pattern like `sd*` or `sd[b-e]`
+    pub fn is_pattern(&self) -> bool {
+        self.name.contains(['*', '?', '['])
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        glob_match(self.name.as_bytes(), name.as_bytes())
+    }
+
+    /// The specs of the devices matching the pattern, with its timeout and options
+    pub fn expand(&self, names: &[String]) -> Vec<DeviceSpec> {
+        names.iter()
+            .filter(|name| self.matches(name))
+            .map(|name| DeviceSpec { name: name.clone(), ..self.clone() })
+            .collect()
+    }
+}
+
+// supports `*`, `?` and bracket expressions with ranges, negated by a leading `!`
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(b'*') => (0..=name.len()).any(|skip| glob_match(&pattern[1..], &name[skip..])),
+        Some(b'?') => !name.is_empty() && glob_match(&pattern[1..], &name[1..]),
+        Some(b'[') => {
+            let end = match pattern.iter().skip(2).position(|&c| c == b']') {
+                Some(position) => position + 2,
+                None => return false,
+            };
+            let (negated, set) = match pattern[1] {
+                b'!' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let c = match name.first() {
+                Some(&c) => c,
+                None => return false,
+            };
+            let mut matched = false;
+            let mut index = 0;
+            while index < set.len() {
+                if index + 2 < set.len() && set[index + 1] == b'-' {
+                    matched |= set[index] <= c && c <= set[index + 2];
+                    index += 3;
+                } else {
+                    matched |= set[index] == c;
+                    index += 1;
+                }
+            }
+            matched != negated && glob_match(&pattern[end + 1..], &name[1..])
+        }
+        Some(&c) => name.first() == Some(&c) && glob_match(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Reads one `DEVICE:TIMEOUT[,OPTION=VALUE...]` per line, ignoring empty lines and
+/// everything after a `#`.
+pub fn read_device_file(path: &str) -> Result<Vec<DeviceSpec>, String> {
+    let content = read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+    let mut specs = vec![];
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let spec = line.parse::<DeviceSpec>().map_err(|e| format!("{}:{}: {}", path, index + 1, e))?;
+        specs.push(spec);
+    }
+    Ok(specs)
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let mut options = val.split(',');
+        let device = options.next().unwrap_or_default();
+        // the timeout is the last segment, so a device name may contain colons itself
+        let (device_name_str, device_timeout_str) = if let Some((a, b)) = device.rsplit_once(':') {
+            (a, b)
+        } else {
+            return Err("invalid amount of elements".to_string());
+        };
+
+        let device_name = String::from(device_name_str);
+        let is_pattern = device_name.contains(['*', '?', '[']) && !device_name.contains('/');
+        let is_disk = device_name.starts_with("sd") && device_name.ends_with(|v: char| {
+            // allow a-z only
+            let unicode = v as u32;
+            (97..=122).contains(&unicode)
+        });
+        let is_dm = device_name.strip_prefix("dm-")
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+        let is_mapper = device_name.strip_prefix("/dev/mapper/")
+            .or_else(|| device_name.strip_prefix("mapper/"))
+            .is_some_and(|n| !n.is_empty() && !n.contains('/'));
+        if !is_disk && !is_dm && !is_mapper && !is_pattern {
+            return Err("device name must have format `sd[a-z]`, `dm-N`, `/dev/mapper/NAME` or be a pattern like `sd*`"
+                .to_string());
+        }
+        // leading zeros are fine (03600), signs and whitespace are not
+        let timeout = match device_timeout_str.parse::<u64>() {
+            Ok(timeout) if device_timeout_str.bytes().all(|c| c.is_ascii_digit()) => timeout,
+            _ => return Err("device timeout must be a number".to_string()),
+        };
+
+        let mut spec = DeviceSpec { name: device_name, timeout, power_target: None, check_interval: None,
+            hooks: HookCommands::default(), rated_load_cycles: None, iops_tolerance: None };
+        for option in options {
+            match option.split_once('=') {
+                Some(("target", value)) => spec.power_target = Some(value.parse()?),
+                Some(("on-standby", value)) => spec.hooks.standby = Some(value.to_string()),
+                Some(("on-wake", value)) => spec.hooks.wake = Some(value.to_string()),
+                Some(("on-error", value)) => spec.hooks.error = Some(value.to_string()),
+                Some(("interval", value)) => match value.parse::<u64>() {
+                    Ok(interval) if interval > 0 => spec.check_interval = Some(interval),
+                    _ => return Err("device interval must be a number greater than 0".to_string()),
+                },
+                Some(("load-cycles", value)) => match value.parse::<u64>() {
+                    Ok(cycles) if cycles > 0 => spec.rated_load_cycles = Some(cycles),
+                    _ => return Err("device load-cycles must be a number greater than 0".to_string()),
+                },
+                Some(("tolerance", value)) => match value.parse::<u64>() {
+                    Ok(tolerance) => spec.iops_tolerance = Some(tolerance),
+                    _ => return Err("device tolerance must be a number".to_string()),
+                },
+                _ => return Err(format!("unknown device option `{}`", option)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn timeout_is_the_last_segment() {
+        let spec: DeviceSpec = "/dev/mapper/backup:2024:3600,target=idle".parse().unwrap();
+        assert_eq!(spec.name, "/dev/mapper/backup:2024");
+        assert_eq!(spec.timeout, 3600);
+        assert_eq!(spec.power_target, Some(PowerTarget::Idle));
+    }
+
+    #[test]
+    fn timeout_must_be_digits_only() {
+        for spec in ["sdb:+3600", "sdb: 3600", "sdb:", "sdb:-1", "sdb:3600s"] {
+            assert!(spec.parse::<DeviceSpec>().is_err(), "{} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn tolerance_is_a_device_option() {
+        let spec: DeviceSpec = "sdb:600,tolerance=20,interval=15".parse().unwrap();
+        assert_eq!(spec.iops_tolerance, Some(20));
+        assert_eq!("sdb:600".parse::<DeviceSpec>().unwrap().iops_tolerance, None);
+        assert!("sdb:600,tolerance=-1".parse::<DeviceSpec>().is_err());
+    }
+
+    #[test]
+    fn short_interval_is_left_to_the_validation() {
+        assert_eq!("sdb:600,interval=1".parse::<DeviceSpec>().unwrap().check_interval, Some(1));
+        assert!("sdb:600,interval=0".parse::<DeviceSpec>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn zero_padded_timeout_is_accepted(timeout in 0..1_000_000u64, zeros in 0..5usize) {
+            let spec: DeviceSpec = format!("sdb:{}{}", "0".repeat(zeros), timeout).parse().unwrap();
+            prop_assert_eq!(spec.timeout, timeout);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,473 @@
+use std::fs::{rename, write};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::events::{escape_json, unix_time};
+
+const STATE_VERSION: u64 = 1;
+// activity alone is saved at most this often, power state changes right away
+const SAVE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// State of a device as saved in the state file. Devices are matched by serial, as the
+/// kernel name may change between boots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedDevice {
+    pub serial: String,
+    pub device: String,
+    // seconds since the unix epoch
+    pub last_activity: u64,
+    pub read_iops: u64,
+    pub write_iops: u64,
+    pub power_state: String,
+    pub standby_count: u64,
+    pub wake_count: u64,
+    pub error_count: u64,
+    pub standby_seconds: u64,
+    pub longest_standby_seconds: u64,
+    pub managed_seconds: u64,
+}
+
+impl SavedDevice {
+    fn from_device(dev: &DeviceInfo, ts: u64, now: Instant) -> Option<SavedDevice> {
+        Some(SavedDevice {
+            serial: dev.serial.clone()?,
+            device: dev.name.clone(),
+            last_activity: ts.saturating_sub(dev.idle_seconds(now)),
+            read_iops: dev.last_read_iops,
+            write_iops: dev.last_write_iops,
+            power_state: dev.power_state.to_string(),
+            standby_count: dev.statistics.standby_count,
+            wake_count: dev.statistics.wake_count,
+            error_count: dev.statistics.error_count,
+            standby_seconds: dev.total_standby_seconds(now),
+            longest_standby_seconds: dev.longest_standby_seconds(now),
+            managed_seconds: dev.statistics.managed_seconds(now),
+        })
+    }
+
+    // changes that are saved right away
+    fn same_history(&self, other: &SavedDevice) -> bool {
+        self.serial == other.serial && self.device == other.device && self.power_state == other.power_state &&
+            self.standby_count == other.standby_count && self.wake_count == other.wake_count &&
+            self.error_count == other.error_count
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{\"serial\":\"{}\",\"device\":\"{}\",\"last_activity\":{},\"read_iops\":{},\"write_iops\":{},\
+                 \"power_state\":\"{}\",\"standby_count\":{},\"wake_count\":{},\"error_count\":{},\
+                 \"standby_seconds\":{},\"longest_standby_seconds\":{},\"managed_seconds\":{}}}",
+                escape_json(&self.serial), escape_json(&self.device), self.last_activity, self.read_iops,
+                self.write_iops, escape_json(&self.power_state), self.standby_count, self.wake_count,
+                self.error_count, self.standby_seconds, self.longest_standby_seconds, self.managed_seconds)
+    }
+
+    fn from_json(value: &Json) -> Result<SavedDevice, String> {
+        Ok(SavedDevice {
+            serial: value.string("serial")?,
+            device: value.string("device")?,
+            last_activity: value.number("last_activity")?,
+            read_iops: value.number("read_iops")?,
+            write_iops: value.number("write_iops")?,
+            power_state: value.string("power_state")?,
+            standby_count: value.number("standby_count")?,
+            wake_count: value.number("wake_count")?,
+            error_count: value.number("error_count")?,
+            standby_seconds: value.number("standby_seconds")?,
+            longest_standby_seconds: value.number("longest_standby_seconds")?,
+            managed_seconds: value.number("managed_seconds")?,
+        })
+    }
+}
+
+/// Keeps the idle timers and statistics across restarts. The file is replaced atomically,
+/// a crash while saving leaves the previous state.
+pub struct StateFile {
+    path: String,
+    saved: Vec<SavedDevice>,
+    last_saved: Option<Instant>,
+}
+
+impl StateFile {
+    pub fn new(path: &str) -> StateFile {
+        StateFile { path: path.to_string(), saved: vec![], last_saved: None }
+    }
+
+    /// Reads the saved devices, a missing file is an empty state
+    pub fn load(&mut self) -> Result<(), String> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("unable to read {}: {}", self.path, e)),
+        };
+        let invalid = |e: String| format!("invalid state file {}: {}", self.path, e);
+        let root = Json::parse(&content).map_err(invalid)?;
+        let version = root.number("version").map_err(invalid)?;
+        if version != STATE_VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        let devices = match root.get("devices") {
+            Some(Json::Array(devices)) => devices,
+            _ => return Err(invalid("missing devices".to_string())),
+        };
+        self.saved = devices.iter().map(SavedDevice::from_json).collect::<Result<_, _>>().map_err(invalid)?;
+        Ok(())
+    }
+
+    /// Saves the state if the power history of a device changed, or its activity since the
+    /// last save a while ago
+    pub fn save_if_changed(&mut self, devices: &[Box<DeviceInfo>], now: Instant) -> Result<(), String> {
+        let current = saved_devices(devices, now);
+        let previous = |dev: &SavedDevice| self.saved.iter().find(|saved| saved.serial == dev.serial);
+        let history_changed = current.iter()
+            .any(|dev| previous(dev).is_none_or(|saved| !dev.same_history(saved)));
+        let activity_changed = current.iter()
+            .any(|dev| previous(dev).is_none_or(|saved| dev.read_iops != saved.read_iops || dev.write_iops != saved.write_iops));
+        let save_due = self.last_saved.is_none_or(|saved| now.saturating_duration_since(saved) >= SAVE_INTERVAL);
+        if history_changed || (activity_changed && save_due) {
+            self.write(current, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self, devices: &[Box<DeviceInfo>], now: Instant) -> Result<(), String> {
+        self.write(saved_devices(devices, now), now)
+    }
+
+    // devices not present right now keep their saved state
+    fn write(&mut self, mut devices: Vec<SavedDevice>, now: Instant) -> Result<(), String> {
+        let absent: Vec<SavedDevice> = self.saved.iter()
+            .filter(|saved| !devices.iter().any(|dev| dev.serial == saved.serial))
+            .cloned()
+            .collect();
+        devices.extend(absent);
+        let entries: Vec<String> = devices.iter().map(|dev| format!("    {}", dev.to_json())).collect();
+        let content = format!("{{\n  \"version\": {},\n  \"saved\": {},\n  \"devices\": [\n{}\n  ]\n}}\n",
+                              STATE_VERSION, unix_time(), entries.join(",\n"));
+        let temporary = format!("{}.tmp", self.path);
+        write(&temporary, content).map_err(|e| format!("unable to write {}: {}", temporary, e))?;
+        rename(&temporary, &self.path).map_err(|e| format!("unable to replace {}: {}", self.path, e))?;
+        log::debug!("saved state of {} devices to {}", devices.len(), self.path);
+        self.saved = devices;
+        self.last_saved = Some(now);
+        Ok(())
+    }
+
+    /// Takes over the saved state of a device with the same serial. The statistics belong
+    /// to the disk and are always restored. The idle timer only is, if the counters are
+    /// unchanged since the save: any I/O in between happened at an unknown time, and changed
+    /// counters also tell of a reboot or of the disk having been attached to another system.
+    pub fn restore(&self, dev: &mut DeviceInfo, now: Instant) {
+        let serial = match &dev.serial {
+            Some(serial) => serial,
+            None => return,
+        };
+        if let Some(entry) = self.saved.iter().find(|entry| entry.serial == *serial) {
+            restore_device(dev, entry, now);
+        }
+    }
+}
+
+fn saved_devices(devices: &[Box<DeviceInfo>], now: Instant) -> Vec<SavedDevice> {
+    let ts = unix_time();
+    devices.iter().filter_map(|dev| SavedDevice::from_device(dev, ts, now)).collect()
+}
+
+fn restore_device(dev: &mut DeviceInfo, entry: &SavedDevice, now: Instant) {
+    dev.statistics.standby_count = entry.standby_count;
+    dev.statistics.wake_count = entry.wake_count;
+    dev.statistics.error_count = entry.error_count;
+    dev.statistics.standby_seconds = entry.standby_seconds;
+    dev.statistics.longest_standby_seconds = entry.longest_standby_seconds.min(entry.standby_seconds);
+    dev.statistics.earlier_managed_seconds = entry.managed_seconds;
+
+    let ts = unix_time();
+    let discarded = if entry.last_activity > ts {
+        Some("its last activity lies in the future".to_string())
+    } else if dev.last_read_iops != entry.read_iops || dev.last_write_iops != entry.write_iops {
+        Some(format!("the counters changed from {} reads / {} writes to {} / {}", entry.read_iops,
+                     entry.write_iops, dev.last_read_iops, dev.last_write_iops))
+    } else {
+        let idle = Duration::from_secs(ts - entry.last_activity);
+        match now.checked_sub(idle) {
+            Some(last_update) => {
+                dev.last_update = last_update;
+                None
+            }
+            None => Some("its last activity lies before the system start".to_string()),
+        }
+    };
+    match discarded {
+        Some(reason) => println!("discarding the saved idle time of {} (was {}), {}", dev.name, entry.device, reason),
+        None => println!("restored state of {} (was {}), idle for {}s", dev.name, entry.device,
+                         dev.idle_seconds(now)),
+    }
+}
+
+// Just enough JSON for the state file: objects, arrays, strings and unsigned integers
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Object(Vec<(String, Json)>),
+    Array(Vec<Json>),
+    String(String),
+    Number(u64),
+}
+
+impl Json {
+    fn parse(input: &str) -> Result<Json, String> {
+        let mut parser = Parser { chars: input.chars().collect(), position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position < parser.chars.len() {
+            return Err(format!("unexpected content at {}", parser.position));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn number(&self, key: &str) -> Result<u64, String> {
+        match self.get(key) {
+            Some(Json::Number(value)) => Ok(*value),
+            _ => Err(format!("missing number {}", key)),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<String, String> {
+        match self.get(key) {
+            Some(Json::String(value)) => Ok(value.clone()),
+            _ => Err(format!("missing string {}", key)),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.position).is_some_and(|c| c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        let c = self.chars.get(self.position).copied().ok_or("unexpected end")?;
+        self.position += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => Err(format!("expected {} at {}, found {}", expected, self.position - 1, c)),
+        }
+    }
+
+    // true if the list continues, false if it ends with `end`
+    fn separator(&mut self, end: char) -> Result<bool, String> {
+        self.skip_whitespace();
+        match self.next()? {
+            ',' => Ok(true),
+            c if c == end => Ok(false),
+            c => Err(format!("expected , or {} at {}, found {}", end, self.position - 1, c)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.position) {
+            Some('{') => {
+                self.position += 1;
+                let mut fields = vec![];
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    if !self.separator('}')? {
+                        return Ok(Json::Object(fields));
+                    }
+                }
+            }
+            Some('[') => {
+                self.position += 1;
+                let mut values = vec![];
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&']') {
+                    self.position += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    if !self.separator(']')? {
+                        return Ok(Json::Array(values));
+                    }
+                }
+            }
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.position;
+                while self.chars.get(self.position).is_some_and(|c| c.is_ascii_digit()) {
+                    self.position += 1;
+                }
+                let digits: String = self.chars[start..self.position].iter().collect();
+                digits.parse().map(Json::Number).map_err(|e| format!("invalid number {}: {}", digits, e))
+            }
+            Some(c) => Err(format!("unexpected {} at {}", c, self.position)),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(value),
+                '\\' => match self.next()? {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| self.next()).collect::<Result<_, _>>()?;
+                        let code = u32::from_str_radix(&hex, 16).map_err(|e| format!("invalid escape {}: {}", hex, e))?;
+                        value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::spindown_daemon::backend::{AtaBackend, Probes};
+    use crate::spindown_daemon::get_device_info;
+    use crate::spindown_daemon::paths::Paths;
+
+    use super::*;
+
+    fn saved(serial: &str, read_iops: u64, write_iops: u64, last_activity: u64) -> SavedDevice {
+        SavedDevice {
+            serial: serial.to_string(),
+            device: "sdx".to_string(),
+            last_activity,
+            read_iops,
+            write_iops,
+            power_state: "ActiveOrIdle".to_string(),
+            standby_count: 4,
+            wake_count: 3,
+            error_count: 1,
+            standby_seconds: 7200,
+            longest_standby_seconds: 3600,
+            managed_seconds: 86400,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spindown-test-state-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // a device with these counters, which is only watched as there is no device node
+    fn device(name: &str, read_iops: u64, write_iops: u64) -> DeviceInfo {
+        let root = temp_dir(name);
+        fs::create_dir_all(root.join("block/sdx")).unwrap();
+        fs::write(root.join("block/sdx/stat"), format!("{} 0 0 0 {} 0 0 0 0 0 0", read_iops, write_iops)).unwrap();
+        let paths = Paths::new(&root.to_string_lossy(), &root.join("dev").to_string_lossy());
+        let dev = get_device_info("sdx", &paths, &Probes::new(Arc::new(AtaBackend))).ok().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        dev
+    }
+
+    #[test]
+    fn saved_device_survives_json() {
+        let device = saved("WD-\"quoted\" back\\slash", 120, 45, 1700000000);
+        let parsed = Json::parse(&device.to_json()).unwrap();
+        assert_eq!(SavedDevice::from_json(&parsed).unwrap(), device);
+    }
+
+    #[test]
+    fn saved_file_is_loaded_again() {
+        let dir = temp_dir("reload");
+        let path = dir.join("state.json").to_string_lossy().to_string();
+        let devices = vec![saved("A1", 1, 2, 3), saved("B\\2", 4, 5, 6)];
+        StateFile::new(&path).write(devices.clone(), Instant::now()).unwrap();
+
+        let mut state = StateFile::new(&path);
+        state.load().unwrap();
+        assert_eq!(state.saved, devices);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_version_or_truncated_file_is_rejected() {
+        let dir = temp_dir("invalid");
+        let path = dir.join("state.json").to_string_lossy().to_string();
+        let mut state = StateFile::new(&path);
+        assert!(state.load().is_ok());
+        assert!(state.saved.is_empty());
+
+        fs::write(&path, "{\"version\": 2, \"saved\": 0, \"devices\": []}").unwrap();
+        let e = state.load().err().unwrap();
+        assert!(e.ends_with("unsupported version 2"), "{}", e);
+
+        StateFile::new(&path).write(vec![saved("A1", 1, 2, 3)], Instant::now()).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        for length in [0, 1, content.len() / 2, content.len() - 3] {
+            fs::write(&path, &content[..length]).unwrap();
+            assert!(state.load().is_err(), "loaded {} of {} bytes", length, content.len());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn idle_time_is_restored_with_unchanged_counters() {
+        let mut dev = device("restore", 120, 45);
+        let now = dev.last_update;
+        restore_device(&mut dev, &saved("A1", 120, 45, unix_time() - 60), now);
+        assert!(dev.idle_seconds(now) >= 60);
+        assert_eq!(dev.statistics.standby_count, 4);
+    }
+
+    #[test]
+    fn idle_time_is_discarded_with_changed_counters() {
+        let mut dev = device("changed", 120, 45);
+        let now = dev.last_update;
+        restore_device(&mut dev, &saved("A1", 120, 44, unix_time() - 60), now);
+        assert_eq!(dev.idle_seconds(now), 0);
+        // the statistics belong to the disk and are kept anyway
+        assert_eq!(dev.statistics.standby_count, 4);
+        assert_eq!(dev.statistics.wake_count, 3);
+    }
+
+    #[test]
+    fn idle_time_is_discarded_with_activity_in_the_future() {
+        let mut dev = device("future", 120, 45);
+        let now = dev.last_update;
+        restore_device(&mut dev, &saved("A1", 120, 45, unix_time() + 3600), now);
+        assert_eq!(dev.idle_seconds(now), 0);
+        assert_eq!(dev.statistics.standby_count, 4);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,42 @@
+use std::time::Instant;
+
+/// Lifetime statistics of a device, as far as the daemon can tell them
+#[derive(Debug, Clone)]
+pub struct DeviceStatistics {
+    // issued power commands, detected wake-ups and failed checks or power commands
+    pub standby_count: u64,
+    pub wake_count: u64,
+    pub error_count: u64,
+    // power commands repeated because the disk did not reach the target
+    pub standby_retries: u64,
+    // of the finished standby stretches
+    pub standby_seconds: u64,
+    pub longest_standby_seconds: u64,
+    // since the device is watched by the daemon, plus the seconds of earlier runs
+    pub managed_since: Instant,
+    pub earlier_managed_seconds: u64,
+}
+
+impl DeviceStatistics {
+    pub fn new(managed_since: Instant) -> DeviceStatistics {
+        DeviceStatistics {
+            standby_count: 0,
+            wake_count: 0,
+            error_count: 0,
+            standby_retries: 0,
+            standby_seconds: 0,
+            longest_standby_seconds: 0,
+            managed_since,
+            earlier_managed_seconds: 0,
+        }
+    }
+
+    pub fn add_standby_stretch(&mut self, seconds: u64) {
+        self.standby_seconds += seconds;
+        self.longest_standby_seconds = self.longest_standby_seconds.max(seconds);
+    }
+
+    pub fn managed_seconds(&self, now: Instant) -> u64 {
+        self.earlier_managed_seconds + now.saturating_duration_since(self.managed_since).as_secs()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,121 @@
+use std::time::Instant;
+
+use crate::spindown_daemon::{DeviceInfo, StandbyMethod};
+use crate::spindown_daemon::events::unix_time;
+
+/// The view of the suspend logic after the last check
+pub struct SuspendStatus {
+    pub enabled: bool,
+    pub disks_running: bool,
+    pub latest_update: Option<Instant>,
+    pub inhibited: bool,
+}
+
+/// Renders one `key=value` line per device plus one for the suspend logic. The keys
+/// are stable, so the output can be grepped.
+pub fn render(devices: &[Box<DeviceInfo>], suspend: &SuspendStatus, now: Instant) -> Vec<String> {
+    let mut lines: Vec<String> = devices.iter().map(|dev| render_device(dev, now)).collect();
+    let since_update = match suspend.latest_update {
+        Some(latest) => format!("{}s", now.saturating_duration_since(latest).as_secs()),
+        None => "-".to_string(),
+    };
+    lines.push(format!("status suspend enabled={} disks_running={} since_latest_update={} inhibited={}",
+                       suspend.enabled, suspend.disks_running, since_update, suspend.inhibited));
+    lines
+}
+
+/// Renders the `status device=...` line of a device
+pub fn render_device(dev: &DeviceInfo, now: Instant) -> String {
+    let idle = dev.idle_seconds(now);
+    // why the device is (not yet) put to sleep and when it will be; standby is due once the
+    // idle time exceeds the timeout and is issued by the following check
+    let (standby, remaining) = if dev.timeout == 0 {
+        ("watch-only", None)
+    } else if dev.power_target.is_reached(dev.power_state) {
+        ("reached", None)
+    } else if idle > dev.timeout {
+        ("due", Some(0))
+    } else {
+        ("waiting", Some(dev.timeout + 1 - idle))
+    };
+    let (standby_in, standby_at) = match remaining {
+        Some(remaining) => {
+            (format!("{}s", remaining), (unix_time() + remaining).to_string())
+        }
+        None => ("-".to_string(), "-".to_string()),
+    };
+    let error = match &dev.error {
+        Some(message) => format!("error message={:?}", message),
+        None => "ok".to_string(),
+    };
+    let confirmed = match dev.power_confirmed {
+        Some(confirmed) => format!("{}s", now.saturating_duration_since(confirmed).as_secs()),
+        None => "-".to_string(),
+    };
+    let method = match dev.standby_method {
+        StandbyMethod::AtaPassthrough => "ata",
+        StandbyMethod::None => "none",
+    };
+    let not_parking = dev.not_parking.map_or("-".to_string(), |power_state| power_state.to_string());
+    let load_cycles = dev.load_cycles.map_or("-".to_string(), |cycles| format!("{}/{}", cycles, dev.rated_load_cycles));
+    format!("status device={} method={} power_state={} activity={} power_confirmed={} idle={}s timeout={}s \
+            standby={} standby_in={} standby_at={} not_parking={} load_cycles={} read_iops={} write_iops={} {} state={}",
+            dev.name, method, dev.power_state, dev.activity().unwrap_or("-"), confirmed, idle, dev.timeout,
+            standby, standby_in, standby_at, not_parking, load_cycles,
+            dev.last_read_iops, dev.last_write_iops, render_statistics(dev, now), error)
+}
+
+/// Renders the lifetime statistics of a device, e.g. for the shutdown summary
+pub fn render_summary(dev: &DeviceInfo, now: Instant) -> String {
+    format!("summary device={} {}", dev.name, render_statistics(dev, now))
+}
+
+fn render_statistics(dev: &DeviceInfo, now: Instant) -> String {
+    format!("standby_count={} wake_count={} error_count={} standby_retries={} standby_seconds={} active_seconds={} \
+            longest_standby_seconds={}",
+            dev.statistics.standby_count, dev.statistics.wake_count, dev.statistics.error_count,
+            dev.statistics.standby_retries, dev.total_standby_seconds(now), dev.active_seconds(now),
+            dev.longest_standby_seconds(now))
+}
+
+/// Formats seconds like `5h32m`, `12m5s` or `40s`
+pub fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Parses durations like `2h`, `1h30m`, `45m`, `1d` or plain seconds into seconds
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(seconds);
+    }
+    let invalid = || format!("invalid duration {}, expected e.g. 90, 45m, 2h or 1h30m", value);
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let amount = number.parse::<u64>().map_err(|_| invalid())?;
+        seconds = amount.checked_mul(unit).and_then(|amount| seconds.checked_add(amount)).ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid());
+    }
+    Ok(seconds)
+}
\ No newline at end of file
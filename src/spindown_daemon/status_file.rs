@@ -0,0 +1,38 @@
+use std::fs::{rename, write};
+use std::time::Instant;
+
+use crate::spindown_daemon::DeviceInfo;
+use crate::spindown_daemon::events::unix_time;
+use crate::spindown_daemon::status;
+
+/// A file rewritten after every check round, e.g. for a container healthcheck testing its
+/// modification time. It holds the time of the round, a one-line summary and the status
+/// lines of the devices, which are left behind as the last known state after a crash.
+pub struct StatusFile {
+    path: String,
+}
+
+impl StatusFile {
+    pub fn new(path: &str) -> StatusFile {
+        StatusFile { path: path.to_string() }
+    }
+
+    pub fn write(&self, devices: &[Box<DeviceInfo>], summary: &str, now: Instant) -> Result<(), String> {
+        let failed: Vec<String> = devices.iter()
+            .filter_map(|dev| dev.error.as_ref().map(|message| format!("{}: {}", dev.name, message)))
+            .collect();
+        let state = if !devices.is_empty() && failed.len() == devices.len() {
+            format!("error all devices failed ({})", failed.join("; "))
+        } else {
+            format!("ok {}", summary)
+        };
+        let mut content = format!("ts={} state={}\n", unix_time(), state);
+        for dev in devices.iter() {
+            content.push_str(&status::render_device(dev, now));
+            content.push('\n');
+        }
+        let temporary = format!("{}.tmp", self.path);
+        write(&temporary, content).map_err(|e| format!("unable to write {}: {}", temporary, e))?;
+        rename(&temporary, &self.path).map_err(|e| format!("unable to replace {}: {}", self.path, e))
+    }
+}
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::spindown_daemon::hooks::{run_with_timeout, CommandError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckLogic {
+    // every script must allow the suspend
+    All,
+    // one allowing script is enough
+    Any,
+}
+
+impl Display for CheckLogic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckLogic::All => write!(f, "all"),
+            CheckLogic::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl FromStr for CheckLogic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(CheckLogic::All),
+            "any" => Ok(CheckLogic::Any),
+            _ => Err(format!("unknown check logic `{}`, expected all or any", s)),
+        }
+    }
+}
+
+/// The scripts deciding whether the system may be suspended, exit code 0 allows it
+pub struct SuspendChecks {
+    scripts: Vec<String>,
+    shell: Option<String>,
+    timeout: Duration,
+    logic: CheckLogic,
+}
+
+impl SuspendChecks {
+    pub fn new(scripts: Vec<String>, shell: Option<String>, timeout: Duration, logic: CheckLogic) -> SuspendChecks {
+        SuspendChecks { scripts, shell, timeout, logic }
+    }
+
+    /// Reports the scripts that cannot be run at startup, instead of at the first suspend
+    pub fn validate(&self) {
+        for script in self.scripts.iter() {
+            // an interpreter only needs to read the script, run directly it must be executable
+            let usable = match (&self.shell, std::fs::metadata(script)) {
+                (_, Err(e)) => Err(e.to_string()),
+                (None, Ok(metadata)) if metadata.permissions().mode() & 0o111 == 0 => {
+                    Err("not executable".to_string())
+                }
+                (Some(_), Ok(_)) => std::fs::File::open(script).map(|_| ()).map_err(|e| e.to_string()),
+                (None, Ok(_)) => Ok(()),
+            };
+            if let Err(e) = usable {
+                println!("unable to use suspend check script {}: {}; it blocks the suspend until it is usable",
+                         script, e);
+            }
+        }
+    }
+
+    /// Runs the scripts in order until the result is known
+    pub fn allow_suspend(&self) -> bool {
+        if self.scripts.is_empty() {
+            return true;
+        }
+        match self.logic {
+            CheckLogic::All => match self.scripts.iter().find(|script| !self.run(script)) {
+                Some(script) => {
+                    log::info!("suspend blocked by check script {}", script);
+                    false
+                }
+                None => true,
+            },
+            CheckLogic::Any => {
+                if self.scripts.iter().any(|script| self.run(script)) {
+                    true
+                } else {
+                    log::info!("suspend blocked, none of the check scripts allowed it");
+                    false
+                }
+            }
+        }
+    }
+
+    // a script that cannot run says no
+    fn run(&self, script: &str) -> bool {
+        log::debug!("executing check script {}", script);
+        let mut command = match &self.shell {
+            Some(shell) => {
+                let mut command = Command::new(shell);
+                command.arg(script);
+                command
+            }
+            None => Command::new(script),
+        };
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+        // the checks pause while the script runs, so a hanging one is killed
+        match run_with_timeout(&mut command, self.timeout) {
+            Ok(status) if status.success() => true,
+            Ok(status) => {
+                match status.code() {
+                    Some(code) => log::debug!("check script {} exited with non zero code ({})", script, code),
+                    None => println!("suspend check script {} was terminated ({})", script, status),
+                }
+                false
+            }
+            Err(CommandError::Timeout) => {
+                println!("suspend check script {} did not finish within {} seconds, killed it",
+                         script, self.timeout.as_secs());
+                false
+            }
+            Err(CommandError::Spawn(e)) | Err(CommandError::Wait(e)) => {
+                println!("suspend check script {} failed to run: {}", script, e);
+                false
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Why the system is not suspended yet, the check scripts aside
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuspendBlocker {
+    DisksRunning,
+    AwakeAfterResume,
+    Timeout,
+    Cooldown,
+}
+
+impl Display for SuspendBlocker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SuspendBlocker::DisksRunning => write!(f, "disk(s) still running"),
+            SuspendBlocker::AwakeAfterResume => write!(f, "minimum awake time after resume not met"),
+            SuspendBlocker::Timeout => write!(f, "suspend timeout not met"),
+            SuspendBlocker::Cooldown => write!(f, "suspend cooldown not met"),
+        }
+    }
+}
+
+/// How the system is put to sleep, the verb of systemctl
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuspendMode {
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    SuspendThenHibernate,
+}
+
+impl Display for SuspendMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SuspendMode::Suspend => write!(f, "suspend"),
+            SuspendMode::Hibernate => write!(f, "hibernate"),
+            SuspendMode::HybridSleep => write!(f, "hybrid-sleep"),
+            SuspendMode::SuspendThenHibernate => write!(f, "suspend-then-hibernate"),
+        }
+    }
+}
+
+impl FromStr for SuspendMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suspend" => Ok(SuspendMode::Suspend),
+            "hibernate" => Ok(SuspendMode::Hibernate),
+            "hybrid-sleep" => Ok(SuspendMode::HybridSleep),
+            "suspend-then-hibernate" => Ok(SuspendMode::SuspendThenHibernate),
+            _ => Err(format!("unknown suspend mode `{}`, expected suspend, hibernate, hybrid-sleep or \
+                              suspend-then-hibernate", s)),
+        }
+    }
+}
+
+/// The timing of the system suspend: it follows the suspend timeout after the last I/O,
+/// is not attempted again within the cooldown unless there was new I/O, and not within
+/// the minimum awake time after a resume. The times are passed in, so it can be tested
+/// without waiting.
+pub struct SuspendGate {
+    pub timeout: Duration,
+    pub cooldown: Duration,
+    pub min_awake_after_resume: Duration,
+    pub last_attempt: Option<Instant>,
+    pub resumed_at: Option<Instant>,
+}
+
+impl SuspendGate {
+    pub fn new(timeout: Duration, cooldown: Duration, min_awake_after_resume: Duration) -> SuspendGate {
+        SuspendGate { timeout, cooldown, min_awake_after_resume, last_attempt: None, resumed_at: None }
+    }
+
+    /// Decides about a suspend attempt at `now` after a round; an allowed attempt starts the
+    /// cooldown. A `forced` suspend (SIGUSR2) skips the timeout and the minimum awake time.
+    pub fn check(&mut self, disks_running: bool, latest_update: Option<Instant>, forced: bool,
+                 now: Instant) -> Result<(), SuspendBlocker> {
+        if disks_running {
+            return Err(SuspendBlocker::DisksRunning);
+        }
+        // prevents bouncing in and out of suspend
+        if !forced && self.resumed_at.is_some_and(|resumed| now < resumed + self.min_awake_after_resume) {
+            return Err(SuspendBlocker::AwakeAfterResume);
+        }
+        if !forced && latest_update.is_some_and(|latest| now < latest + self.timeout) {
+            return Err(SuspendBlocker::Timeout);
+        }
+        // new activity since the last attempt resets the cooldown
+        if let Some(attempt) = self.last_attempt {
+            if latest_update.is_none_or(|latest| latest <= attempt) && now < attempt + self.cooldown {
+                return Err(SuspendBlocker::Cooldown);
+            }
+        }
+        self.last_attempt = Some(now);
+        Ok(())
+    }
+
+    /// When the system is to be suspended with all disks asleep since `latest_update`, so the
+    /// main loop wakes up for it rather than with the next check after it
+    pub fn suspend_at(&self, latest_update: Instant) -> Instant {
+        let mut suspend_at = latest_update + self.timeout;
+        if let Some(attempt) = self.last_attempt.filter(|attempt| latest_update <= *attempt) {
+            suspend_at = suspend_at.max(attempt + self.cooldown);
+        }
+        if let Some(resumed) = self.resumed_at {
+            suspend_at = suspend_at.max(resumed + self.min_awake_after_resume);
+        }
+        suspend_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gate() -> SuspendGate {
+        SuspendGate::new(Duration::from_secs(60), Duration::from_secs(600), Duration::from_secs(300))
+    }
+
+    #[test]
+    fn suspend_modes_are_the_systemctl_verbs() {
+        for verb in ["suspend", "hibernate", "hybrid-sleep", "suspend-then-hibernate"] {
+            assert_eq!(verb.parse::<SuspendMode>().unwrap().to_string(), verb);
+        }
+        assert!("sleep".parse::<SuspendMode>().is_err());
+    }
+
+    #[test]
+    fn running_disks_block_the_suspend() {
+        let start = Instant::now();
+        let mut gate = gate();
+        assert_eq!(gate.check(true, Some(start), true, start + Duration::from_secs(3600)),
+                   Err(SuspendBlocker::DisksRunning));
+        assert_eq!(gate.last_attempt, None);
+    }
+
+    #[test]
+    fn suspend_follows_the_timeout() {
+        let start = Instant::now();
+        let mut gate = gate();
+        assert_eq!(gate.check(false, Some(start), false, start + Duration::from_secs(59)), Err(SuspendBlocker::Timeout));
+        assert_eq!(gate.suspend_at(start), start + Duration::from_secs(60));
+        assert_eq!(gate.check(false, Some(start), false, start + Duration::from_secs(60)), Ok(()));
+        assert_eq!(gate.last_attempt, Some(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn cooldown_holds_until_new_activity() {
+        let start = Instant::now();
+        let mut gate = gate();
+        let attempt = start + Duration::from_secs(60);
+        assert_eq!(gate.check(false, Some(start), false, attempt), Ok(()));
+        // e.g. blocked by a check script, so the disks are still asleep
+        assert_eq!(gate.check(false, Some(start), false, attempt + Duration::from_secs(10)),
+                   Err(SuspendBlocker::Cooldown));
+        assert_eq!(gate.suspend_at(start), attempt + Duration::from_secs(600));
+        assert_eq!(gate.check(false, Some(start), false, attempt + Duration::from_secs(600)), Ok(()));
+
+        // I/O after the attempt only waits for the timeout again
+        let latest = attempt + Duration::from_secs(620);
+        assert_eq!(gate.suspend_at(latest), latest + Duration::from_secs(60));
+        assert_eq!(gate.check(false, Some(latest), false, latest + Duration::from_secs(60)), Ok(()));
+    }
+
+    #[test]
+    fn forced_suspend_skips_the_timeout_and_the_awake_time_but_not_the_cooldown() {
+        let start = Instant::now();
+        let mut gate = gate();
+        gate.resumed_at = Some(start);
+        assert_eq!(gate.check(false, Some(start), false, start + Duration::from_secs(60)),
+                   Err(SuspendBlocker::AwakeAfterResume));
+        assert_eq!(gate.check(false, Some(start), true, start + Duration::from_secs(1)), Ok(()));
+        assert_eq!(gate.check(false, Some(start), true, start + Duration::from_secs(2)), Err(SuspendBlocker::Cooldown));
+    }
+
+    #[test]
+    fn resume_delays_the_suspend_by_the_minimum_awake_time() {
+        let start = Instant::now();
+        let mut gate = gate();
+        let resumed = start + Duration::from_secs(1000);
+        gate.resumed_at = Some(resumed);
+        assert_eq!(gate.suspend_at(start), resumed + Duration::from_secs(300));
+        assert_eq!(gate.check(false, Some(start), false, resumed + Duration::from_secs(299)),
+                   Err(SuspendBlocker::AwakeAfterResume));
+        assert_eq!(gate.check(false, Some(start), false, resumed + Duration::from_secs(300)), Ok(()));
+    }
+}
\ No newline at end of file
@@ -1,18 +1,508 @@
-use std::fs::read_to_string;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::{read_dir, read_to_string};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 
+use nix::sys::stat::{major, minor};
+
+use crate::spindown_daemon::ata::PowerState;
 use crate::spindown_daemon::errors::DeviceError;
+use crate::spindown_daemon::paths::Paths;
+
+const PROC_DISKSTATS: &str = "/proc/diskstats";
+const PROC_MEMINFO: &str = "/proc/meminfo";
+const PROC_MOUNTS: &str = "/proc/mounts";
+// Fields of the stat file by absolute index, see https://www.kernel.org/doc/Documentation/block/stat.txt:
+// 0 read I/Os, 1 read merges, 2 read sectors, 3 read ticks, 4 write I/Os, 5 write merges,
+// 6 write sectors, 7 write ticks, 8 in flight, 9 io ticks, 10 time in queue, followed by the
+// discard (since 4.18) and flush (since 5.5) fields
+const STAT_READ_IOS: usize = 0;
+const STAT_READ_SECTORS: usize = 2;
+const STAT_WRITE_IOS: usize = 4;
+const STAT_WRITE_SECTORS: usize = 6;
+// /proc/diskstats prefixes the same fields by major, minor and device name
+const DISKSTATS_NAME: usize = 2;
+const DISKSTATS_PREFIX_LEN: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatSource {
+    Sysfs,
+    ProcDiskstats,
+}
+
+impl Display for StatSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StatSource::Sysfs => write!(f, "sysfs"),
+            StatSource::ProcDiskstats => write!(f, "{}", PROC_DISKSTATS),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskStats {
+    pub read_iops: u64,
+    pub write_iops: u64,
+    pub read_sectors: u64,
+    pub write_sectors: u64,
+    pub source: StatSource,
+}
+
+/// Dirty data waiting to be written back
+#[derive(Debug, Clone, Copy)]
+pub struct PendingWriteback {
+    pub kib: u64,
+    // without debugfs, only the data of the whole system is known
+    pub system_wide: bool,
+}
+
+/// The dirty and writeback data of the backing device (bdi) of a device, from debugfs,
+/// or of the whole system if debugfs is not mounted
+pub fn get_pending_writeback(dev: &str, paths: &Paths) -> Option<PendingWriteback> {
+    let bdi_stats = read_to_string(paths.sysfs(&format!("block/{}/dev", dev))).ok()
+        .and_then(|number| read_to_string(paths.sysfs(&format!("kernel/debug/bdi/{}/stats", number.trim()))).ok());
+    match bdi_stats {
+        Some(content) => Some(PendingWriteback {
+            kib: sum_kib(&content, &["BdiWriteback", "BdiReclaimable"])?,
+            system_wide: false,
+        }),
+        None => Some(PendingWriteback {
+            kib: sum_kib(&read_to_string(PROC_MEMINFO).ok()?, &["Dirty", "Writeback"])?,
+            system_wide: true,
+        }),
+    }
+}
+
+// Sums the `KEY: VALUE kB` lines of the keys, all of which must be present
+fn sum_kib(content: &str, keys: &[&str]) -> Option<u64> {
+    let mut sum: u64 = 0;
+    let mut found = 0;
+    for (key, value) in content.lines().filter_map(|line| line.split_once(':')) {
+        if keys.contains(&key.trim()) {
+            sum = sum.saturating_add(value.split_whitespace().next()?.parse().ok()?);
+            found += 1;
+        }
+    }
+    (found == keys.len()).then_some(sum)
+}
+
+/// Whether the counters moved by at most the tolerance. Counters going backwards (a
+/// re-registered device or a wraparound) never count as idle.
+pub fn is_idle(prev: &DiskStats, cur: &DiskStats, tolerance: u64) -> bool {
+    let within_tolerance = |prev: u64, cur: u64| cur.checked_sub(prev).is_some_and(|delta| delta <= tolerance);
+    within_tolerance(prev.read_iops, cur.read_iops) && within_tolerance(prev.write_iops, cur.write_iops)
+}
+
+pub fn get_device_stats(dev: &str, paths: &Paths) -> Result<DiskStats, DeviceError> {
+    let filename: String = paths.sysfs(&format!("block/{}/stat", dev));
+    match read_to_string(&filename) {
+        Err(e) if e.kind() == ErrorKind::NotFound => get_proc_diskstats(dev),
+        Err(e) => Err(DeviceError::new(filename, e.to_string())),
+        Ok(content) => parse_stat_file(&filename, &content),
+    }
+}
+
+/// Reads the stats of all partitions of a disk, partitionless disks have none.
+pub fn get_partition_stats(dev: &str, paths: &Paths) -> Vec<(String, DiskStats)> {
+    let mut partitions = vec![];
+    let entries = match read_dir(paths.sysfs(&format!("block/{}", dev))) {
+        Ok(entries) => entries,
+        Err(_) => return partitions,
+    };
+    for entry in entries.flatten() {
+        if !entry.path().join("partition").exists() {
+            continue;
+        }
+        let filename = entry.path().join("stat").to_string_lossy().to_string();
+        let stats = read_to_string(&filename).ok()
+            .and_then(|content| parse_stat_file(&filename, &content).ok());
+        if let Some(stats) = stats {
+            partitions.push((entry.file_name().to_string_lossy().to_string(), stats));
+        }
+    }
+    partitions.sort_by(|a, b| a.0.cmp(&b.0));
+    partitions
+}
+
+pub fn parse_stat_file(filename: &str, content: &str) -> Result<DiskStats, DeviceError> {
+    let fields: Vec<&str> = content.split_whitespace().collect();
+    parse_stat_fields(filename, content, &fields, StatSource::Sysfs)
+}
+
+fn parse_stat_fields(filepath: &str, content: &str, fields: &[&str],
+                     source: StatSource) -> Result<DiskStats, DeviceError> {
+    let read_iops = parse_stat_field(filepath, content, fields.get(STAT_READ_IOS).copied())?;
+    let write_iops = parse_stat_field(filepath, content, fields.get(STAT_WRITE_IOS).copied())?;
+    let read_sectors = parse_stat_field(filepath, content, fields.get(STAT_READ_SECTORS).copied())?;
+    let write_sectors = parse_stat_field(filepath, content, fields.get(STAT_WRITE_SECTORS).copied())?;
+    Ok(DiskStats { read_iops, write_iops, read_sectors, write_sectors, source })
+}
 
-pub fn get_device_stats(dev: &String) -> Result<(u64, u64), DeviceError> {
-    let filename: String = format!("/sys/block/{}/stat", dev);
-    let read_result = read_to_string(filename.clone());
-    if read_result.is_err() {
-        return Err(DeviceError::new(filename, read_result.unwrap_err().to_string()));
+fn get_proc_diskstats(dev: &str) -> Result<DiskStats, DeviceError> {
+    match read_to_string(PROC_DISKSTATS) {
+        Ok(content) => parse_diskstats(&content, dev),
+        Err(e) => Err(DeviceError::new(PROC_DISKSTATS.to_string(), e.to_string())),
+    }
+}
+
+// Parses the line of the device out of the content of /proc/diskstats,
+// see https://www.kernel.org/doc/Documentation/ABI/testing/procfs-diskstats
+fn parse_diskstats(content: &str, dev: &str) -> Result<DiskStats, DeviceError> {
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(DISKSTATS_NAME) != Some(&dev) {
+            continue;
+        }
+        return parse_stat_fields(PROC_DISKSTATS, line, &fields[DISKSTATS_PREFIX_LEN..], StatSource::ProcDiskstats);
+    }
+    Err(DeviceError::new(PROC_DISKSTATS.to_string(), format!("no entry for {}", dev)))
+}
+
+fn parse_stat_field(filepath: &str, content: &str, field: Option<&str>) -> Result<u64, DeviceError> {
+    match field.map(|value| value.parse::<u64>()) {
+        Some(Ok(value)) => Ok(value),
+        _ => {
+            let snippet: String = content.trim().chars().take(64).collect();
+            Err(DeviceError::new(filepath.to_string(), format!("malformed stats `{}`", snippet)))
+        }
+    }
+}
+
+/// Lists the whole disks and stacked devices in /sys/block, skipping loop and ram devices.
+pub fn list_block_devices(paths: &Paths) -> Result<Vec<String>, DeviceError> {
+    let block_dir = paths.sysfs("block");
+    let entries = match read_dir(&block_dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(DeviceError::new(block_dir, e.to_string())),
+    };
+    let mut devices: Vec<String> = entries.flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|dev| !["loop", "ram", "zram"].iter().any(|prefix| dev.starts_with(prefix)))
+        .collect();
+    devices.sort();
+    Ok(devices)
+}
+
+pub fn is_rotational(dev: &str, paths: &Paths) -> Option<bool> {
+    read_to_string(paths.sysfs(&format!("block/{}/queue/rotational", dev)))
+        .ok()
+        .map(|content| content.trim() == "1")
+}
+
+pub fn get_device_model(dev: &str, paths: &Paths) -> Option<String> {
+    read_to_string(paths.sysfs(&format!("block/{}/device/model", dev)))
+        .ok()
+        .map(|content| content.trim().to_string())
+        .filter(|model| !model.is_empty())
+}
+
+/// Resolves a device-mapper name (as found in /dev/mapper) to its kernel name `dm-N`.
+pub fn resolve_mapper_name(name: &str, paths: &Paths) -> Result<String, DeviceError> {
+    let block_dir = paths.sysfs("block");
+    let entries = match read_dir(&block_dir) {
+        Ok(entries) => entries,
+        Err(e) => return Err(DeviceError::new(block_dir, e.to_string())),
     };
-    let content = read_result.unwrap();
+    for entry in entries.flatten() {
+        let dev = entry.file_name().to_string_lossy().to_string();
+        if !dev.starts_with("dm-") {
+            continue;
+        }
+        if let Ok(dm_name) = read_to_string(paths.sysfs(&format!("block/{}/dm/name", dev))) {
+            if dm_name.trim() == name {
+                return Ok(dev);
+            }
+        }
+    }
+    Err(DeviceError::new(paths.dev(&format!("mapper/{}", name)), "no such device-mapper device".to_string()))
+}
+
+/// Walks the `slaves` of a stacked device (dm, LVM, ...) down to the physical disks.
+/// A plain disk resolves to itself, partitions resolve to their parent disk.
+pub fn get_physical_disks(dev: &str, paths: &Paths) -> Result<Vec<String>, DeviceError> {
+    let mut disks = vec![];
+    collect_physical_disks(dev, paths, &mut disks)?;
+    Ok(disks)
+}
+
+fn collect_physical_disks(dev: &str, paths: &Paths, disks: &mut Vec<String>) -> Result<(), DeviceError> {
+    let base = paths.sysfs(&format!("class/block/{}", dev));
+    let slaves: Vec<String> = match read_dir(format!("{}/slaves", base)) {
+        Ok(entries) => entries.flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => vec![],
+    };
+    if !slaves.is_empty() {
+        for slave in slaves {
+            collect_physical_disks(&slave, paths, disks)?;
+        }
+        return Ok(());
+    }
+
+    let mut disk = dev.to_string();
+    if Path::new(&format!("{}/partition", base)).exists() {
+        let resolved = match Path::new(&base).canonicalize() {
+            Ok(resolved) => resolved,
+            Err(e) => return Err(DeviceError::new(base, e.to_string())),
+        };
+        if let Some(parent) = resolved.parent().and_then(|p| p.file_name()) {
+            disk = parent.to_string_lossy().to_string();
+        }
+    }
+    if !disks.contains(&disk) {
+        disks.push(disk);
+    }
+    Ok(())
+}
+
+/// The source of the root filesystem (e.g. `/dev/sda2` or `/dev/mapper/vg-root`) with the
+/// physical disks holding it, None if they cannot be told, e.g. for a network root
+pub fn get_root_disks(paths: &Paths) -> Option<(String, Vec<String>)> {
+    let source = read_to_string(PROC_MOUNTS).ok().and_then(|mounts| root_mount_source(&mounts).map(str::to_string))?;
+    // /dev/mapper and /dev/disk/by-* are symlinks to the kernel name, /dev/root is not
+    let by_source = Path::new(&source).canonicalize().ok()
+        .and_then(|resolved| resolved.file_name().map(|name| name.to_string_lossy().to_string()))
+        .filter(|name| Path::new(&paths.sysfs(&format!("class/block/{}", name))).exists());
+    let by_number = || {
+        let number = std::fs::metadata("/").ok()?.dev();
+        let resolved = Path::new(&paths.sysfs(&format!("dev/block/{}:{}", major(number), minor(number)))).canonicalize().ok()?;
+        resolved.file_name().map(|name| name.to_string_lossy().to_string())
+    };
+    let dev = by_source.or_else(by_number)?;
+    let disks = get_physical_disks(&dev, paths).ok()?;
+    Some((source, disks))
+}
+
+// The source of the last mount on / in the format of /proc/mounts, which escapes spaces
+fn root_mount_source(mounts: &str) -> Option<&str> {
+    mounts.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source = fields.next()?;
+            (fields.next()? == "/").then_some(source)
+        })
+        .next_back()
+}
+
+/// Standby while the kernel has the disk runtime suspended (`device/power/runtime_status`),
+/// None if it is active or runtime PM is not tracked for it. Active tells nothing about the
+/// spindle: a disk spun down by a power command stays runtime active.
+pub fn get_runtime_power_state(dev: &str, paths: &Paths) -> Option<PowerState> {
+    let status = read_to_string(paths.sysfs(&format!("block/{}/device/power/runtime_status", dev))).ok()?;
+    match status.trim() {
+        "suspended" | "suspending" => Some(PowerState::Standby),
+        _ => None,
+    }
+}
+
+/// Returns an identifier of the physical device behind a kernel name, used to notice
+/// a replaced disk re-using the same name.
+pub fn get_device_serial(dev: &str, paths: &Paths) -> Option<String> {
+    for attribute in ["device/wwid", "dm/uuid"] {
+        if let Ok(content) = read_to_string(paths.sysfs(&format!("block/{}/{}", dev, attribute))) {
+            if !content.trim().is_empty() {
+                return Some(content.trim().to_string());
+            }
+        }
+    }
+    // unit serial number VPD page: 4 byte header followed by the serial
+    if let Ok(content) = std::fs::read(paths.sysfs(&format!("block/{}/device/vpd_pg80", dev))) {
+        if content.len() > 4 {
+            let serial = String::from_utf8_lossy(&content[4..]).trim().to_string();
+            if !serial.is_empty() {
+                return Some(serial);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn stats(read_iops: u64, write_iops: u64) -> DiskStats {
+        DiskStats { read_iops, write_iops, read_sectors: 0, write_sectors: 0, source: StatSource::Sysfs }
+    }
+
+    // A temporary sysfs root with the directory of sdx, removed again when dropped
+    struct SysfsRoot {
+        path: PathBuf,
+    }
+
+    impl Drop for SysfsRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn sysfs_root(name: &str) -> SysfsRoot {
+        let path = std::env::temp_dir().join(format!("spindown-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(path.join("block/sdx")).unwrap();
+        SysfsRoot { path }
+    }
+
+    proptest! {
+        #[test]
+        fn activity_within_tolerance_is_idle(read in 0..u64::MAX / 2, write in 0..u64::MAX / 2,
+                                             tolerance in 0..1000u64, read_delta in 0..1000u64,
+                                             write_delta in 0..1000u64) {
+            let read_delta = read_delta.min(tolerance);
+            let write_delta = write_delta.min(tolerance);
+            prop_assert!(is_idle(&stats(read, write), &stats(read + read_delta, write + write_delta), tolerance));
+        }
+
+        #[test]
+        fn activity_beyond_tolerance_is_not_idle(read in 0..u64::MAX / 2, write in 0..u64::MAX / 2,
+                                                 tolerance in 0..1000u64, delta in 1..1000u64, on_read: bool) {
+            let cur = if on_read {
+                stats(read + tolerance + delta, write)
+            } else {
+                stats(read, write + tolerance + delta)
+            };
+            prop_assert!(!is_idle(&stats(read, write), &cur, tolerance));
+        }
+
+        #[test]
+        fn counters_going_backwards_are_not_idle(read in 1..u64::MAX, write: u64, back in 1..u64::MAX,
+                                                 tolerance: u64) {
+            let back = back.min(read);
+            prop_assert!(!is_idle(&stats(read, write), &stats(read - back, write), tolerance));
+        }
+
+        #[test]
+        fn parse_stat_file_never_panics(content in ".*") {
+            let _ = parse_stat_file("stat", &content);
+        }
+
+        #[test]
+        fn parse_stat_file_reads_read_iops(fields in proptest::collection::vec(any::<u64>(), 11..17)) {
+            let content = fields.iter().map(|field| field.to_string()).collect::<Vec<String>>().join(" ");
+            let parsed = parse_stat_file("stat", &content).ok().map(|stats| stats.read_iops);
+            prop_assert_eq!(parsed, Some(fields[0]));
+        }
+
+        #[test]
+        fn parse_stat_file_reads_write_iops(fields in proptest::collection::vec(any::<u64>(), 11..17)) {
+            let content = fields.iter().map(|field| field.to_string()).collect::<Vec<String>>().join(" ");
+            let parsed = parse_stat_file("stat", &content).ok().map(|stats| stats.write_iops);
+            prop_assert_eq!(parsed, Some(fields[4]));
+        }
+
+        #[test]
+        fn parse_stat_file_reads_sectors(fields in proptest::collection::vec(any::<u64>(), 11..17)) {
+            let content = fields.iter().map(|field| field.to_string()).collect::<Vec<String>>().join(" ");
+            let parsed = parse_stat_file("stat", &content).ok().map(|stats| (stats.read_sectors, stats.write_sectors));
+            prop_assert_eq!(parsed, Some((fields[2], fields[6])));
+        }
+
+        #[test]
+        fn root_mount_source_never_panics(content in ".*") {
+            let _ = root_mount_source(&content);
+        }
+
+        #[test]
+        fn root_mount_source_is_the_last_mount_on_root(disk in "/dev/[a-z]{3}[0-9]", other in "/dev/[a-z]{3}[0-9]") {
+            let mounts = format!("sysfs /sys sysfs rw 0 0\n{} / ext4 rw 0 0\n{} /home ext4 rw 0 0\n\
+                                  overlay / overlay rw 0 0\n", other, disk);
+            prop_assert_eq!(root_mount_source(&mounts), Some("overlay"));
+            let mounts = format!("{} / ext4 rw 0 0\n{} /home ext4 rw 0 0\n", disk, other);
+            prop_assert_eq!(root_mount_source(&mounts), Some(disk.as_str()));
+        }
+
+        #[test]
+        fn sum_kib_never_panics(content in ".*") {
+            let _ = sum_kib(&content, &["Dirty", "Writeback"]);
+        }
+
+        #[test]
+        fn runtime_status_is_standby_only_while_suspended(status in "[a-z]{0,12}") {
+            let root = sysfs_root("runtime");
+            fs::create_dir_all(root.path.join("block/sdx/device/power")).unwrap();
+            fs::write(root.path.join("block/sdx/device/power/runtime_status"), format!("{}\n", status)).unwrap();
+            let paths = Paths::new(&root.path.to_string_lossy(), "/dev");
+            let expected = ["suspended", "suspending"].contains(&status.as_str()).then_some(PowerState::Standby);
+            prop_assert_eq!(get_runtime_power_state("sdx", &paths), expected);
+        }
+
+        #[test]
+        fn get_device_stats_never_panics(content: Vec<u8>) {
+            let root = sysfs_root("stats");
+            fs::write(root.path.join("block/sdx/stat"), &content).unwrap();
+            let paths = Paths::new(&root.path.to_string_lossy(), "/dev");
+            let _ = get_device_stats("sdx", &paths);
+        }
+    }
+
+    #[test]
+    fn empty_stat_file_is_malformed() {
+        let e = parse_stat_file("stat", "").err().unwrap();
+        assert_eq!(e.filepath, "stat");
+        assert_eq!(e.message, "malformed stats ``");
+        assert!(parse_stat_file("stat", " \n").is_err());
+    }
+
+    #[test]
+    fn short_stat_line_is_malformed() {
+        // the write I/Os are the fifth field, the write sectors the seventh
+        let e = parse_stat_file("stat", "1200 30 96000 400\n").err().unwrap();
+        assert_eq!(e.message, "malformed stats `1200 30 96000 400`");
+        assert!(parse_stat_file("stat", "1200 30 96000 400 350 20\n").is_err());
+        assert!(parse_stat_file("stat", "1200 30 96000 400 350 20 7000\n").is_ok());
+    }
+
+    #[test]
+    fn non_numeric_stat_field_is_malformed() {
+        let e = parse_stat_file("stat", "1200 30 96000 400 n/a 20 7000 900 0 1100 1300\n").err().unwrap();
+        assert!(e.message.starts_with("malformed stats `1200 30"), "{}", e.message);
+        assert!(parse_stat_file("stat", "-1 0 0 0 0 0 0 0 0 0 0").is_err());
+        // only the fields that are read have to be numbers
+        assert!(parse_stat_file("stat", "1200 x 96000 x 350 x 7000 x x x x").is_ok());
+    }
+
+    const DISKSTATS: &str = concat!(
+        "   8       0 sda 1200 30 96000 400 350 20 7000 900 0 1100 1300 0 0 0 0\n",
+        "   8       1 sda1 1100 30 88000 380 300 20 6000 850 0 1000 1230 0 0 0 0\n",
+        "   8      16 sdb 17 0 136 5 0 0 0 0 0 12 5\n",
+    );
+
+    #[test]
+    fn diskstats_fields_follow_major_minor_and_name() {
+        let stats = parse_diskstats(DISKSTATS, "sda").ok().unwrap();
+        assert_eq!((stats.read_iops, stats.write_iops), (1200, 350));
+        assert_eq!((stats.read_sectors, stats.write_sectors), (96000, 7000));
+        assert_eq!(stats.source, StatSource::ProcDiskstats);
+        // a kernel before 4.18 has only the first 11 fields
+        assert_eq!(parse_diskstats(DISKSTATS, "sdb").ok().unwrap().read_iops, 17);
+    }
+
+    #[test]
+    fn diskstats_name_has_to_match_exactly() {
+        let stats = parse_diskstats(DISKSTATS, "sda1").ok().unwrap();
+        assert_eq!((stats.read_iops, stats.write_iops), (1100, 300));
+        assert!(parse_diskstats(DISKSTATS, "sd").is_err());
+    }
+
+    #[test]
+    fn missing_diskstats_entry_is_an_error() {
+        let e = parse_diskstats(DISKSTATS, "sdc").err().unwrap();
+        assert_eq!(e.filepath, PROC_DISKSTATS);
+        assert_eq!(e.message, "no entry for sdc");
+        assert!(parse_diskstats("", "sda").is_err());
+    }
 
-    // see https://www.kernel.org/doc/Documentation/block/stat.txt
-    let mut elements = content.split_whitespace();
-    let read_iops = elements.nth(0).unwrap().parse().unwrap();
-    let write_iops = elements.nth(4).unwrap().parse().unwrap();
-    Ok((read_iops, write_iops))
+    #[test]
+    fn short_diskstats_line_is_malformed() {
+        let e = parse_diskstats("   8       0 sda 1200 30 96000\n", "sda").err().unwrap();
+        assert!(e.message.starts_with("malformed stats"), "{}", e.message);
+        assert!(parse_diskstats("   8       0 sda\n", "sda").is_err());
+    }
 }
\ No newline at end of file
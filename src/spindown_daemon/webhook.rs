@@ -0,0 +1,41 @@
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use crate::spindown_daemon::events::{Event, EventKind};
+
+/// Posts events as JSON to a URL. Requests are sent from a background thread, so a slow
+/// endpoint never delays the checks.
+pub struct Webhook {
+    sender: Sender<Event>,
+    events: Vec<EventKind>,
+}
+
+impl Webhook {
+    pub fn start(url: &str, events: Vec<EventKind>) -> Result<Webhook, String> {
+        let (sender, receiver) = channel::<Event>();
+        let url = url.to_string();
+        thread::Builder::new()
+            .name("webhook".to_string())
+            .spawn(move || {
+                for event in receiver {
+                    let result = ureq::post(&url)
+                        .set("Content-Type", "application/json")
+                        .send_string(&event.to_json());
+                    if let Err(e) = result {
+                        log::warn!("unable to send {} event for {} to webhook: {}", event.kind, event.device, e);
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(Webhook { sender, events })
+    }
+
+    pub fn notify(&self, event: Event) {
+        if !self.events.contains(&event.kind) {
+            return;
+        }
+        if let Err(e) = self.sender.send(event) {
+            log::warn!("webhook thread is gone: {}", e);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::spindown_daemon::{check_device, DeviceInfo, KnownState, StandbyMethod};
+use crate::spindown_daemon::ata::{PowerState, PowerTarget};
+use crate::spindown_daemon::backend::{PowerBackend, Probes};
+use crate::spindown_daemon::errors::DeviceError;
+use crate::spindown_daemon::paths::Paths;
+use crate::spindown_daemon::signals;
+
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+// wait before verifying a power command, doubled for every retry
+const STANDBY_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+enum Request {
+    Check(Option<KnownState>),
+    PowerTarget(String, PowerTarget),
+}
+
+/// Outcome of a power command sent to one of the disks of the device
+pub struct PowerResult {
+    pub disk: String,
+    pub target: PowerTarget,
+    pub result: Result<(), DeviceError>,
+    // commands sent, more than one if the disk did not reach the target right away
+    pub attempts: u32,
+    // the power state the disk still reported after the last retry
+    pub not_reached: Option<PowerState>,
+}
+
+/// How often a power command is repeated while the disk does not report the target
+/// afterwards, e.g. for USB bridges that need the command twice
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32) -> RetryPolicy {
+        RetryPolicy { retries, delay: STANDBY_RETRY_DELAY }
+    }
+}
+
+/// Runs all commands of a single device (checks and power commands) on its own thread,
+/// so a device hanging in SG_IO until the command timeout does not delay the other devices.
+pub struct DeviceWorker {
+    name: String,
+    requests: Sender<Request>,
+    results: Receiver<Result<DeviceInfo, DeviceError>>,
+    power_results: Receiver<PowerResult>,
+    handle: JoinHandle<()>,
+    pending: bool,
+}
+
+impl DeviceWorker {
+    pub fn spawn(name: &str, paths: &Paths, probes: Probes, standby_method: StandbyMethod,
+                 retry_policy: RetryPolicy) -> Result<DeviceWorker, String> {
+        let (requests, request_receiver) = channel::<Request>();
+        let (result_sender, results) = channel();
+        let (power_result_sender, power_results) = channel();
+        let thread_name = name.to_string();
+        let paths = paths.clone();
+        let handle = thread::Builder::new()
+            .name(format!("check-{}", name))
+            .spawn(move || {
+                for request in request_receiver {
+                    // queued commands are dropped once the daemon shuts down
+                    if signals::shutdown_requested() {
+                        break;
+                    }
+                    let sent = match request {
+                        Request::Check(known) => {
+                            result_sender.send(check_device(&thread_name, &paths, &probes, known, standby_method)).is_ok()
+                        }
+                        Request::PowerTarget(disk, target) => {
+                            let result = power_target(probes.power.as_ref(), &disk, &paths, target, retry_policy);
+                            power_result_sender.send(result).is_ok()
+                        }
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(DeviceWorker { name: name.to_string(), requests, results, power_results, handle, pending: false })
+    }
+
+    /// Starts a check unless the previous one is still running
+    pub fn request_check(&mut self, known: Option<KnownState>) {
+        if self.pending {
+            log::debug!("previous check of {} is still running", self.name);
+            return;
+        }
+        self.pending = self.requests.send(Request::Check(known)).is_ok();
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Waits until the deadline for the result of the running check
+    pub fn collect(&mut self, deadline: Instant) -> Option<Result<DeviceInfo, DeviceError>> {
+        if !self.pending {
+            return None;
+        }
+        match self.results.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(result) => {
+                self.pending = false;
+                Some(result)
+            }
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.pending = false;
+                Some(Err(DeviceError::new(self.name.clone(), "check thread terminated".to_string())))
+            }
+        }
+    }
+
+    /// Queues a power command for one of the disks of the device, its outcome is
+    /// returned by a later `power_results`.
+    pub fn request_power_target(&self, disk: &str, target: PowerTarget) -> Result<(), DeviceError> {
+        self.requests.send(Request::PowerTarget(disk.to_string(), target))
+            .map_err(|_| DeviceError::new(disk.to_string(), "check thread terminated".to_string()))
+    }
+
+    /// Returns the outcomes of the power commands finished since the last call
+    pub fn power_results(&self) -> Vec<PowerResult> {
+        self.power_results.try_iter().collect()
+    }
+
+    /// Stops the thread after its running command, waiting for it until the deadline.
+    /// Returns false if the thread is still blocked in a command.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        drop(self.requests);
+        while !self.handle.is_finished() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(SHUTDOWN_POLL);
+        }
+        self.handle.join().is_ok()
+    }
+}
+
+// Sends the power command and, with retries, repeats it until the disk reports the target.
+// The sleep target is not verified, the power check would need the reset that wakes it up.
+fn power_target(backend: &dyn PowerBackend, disk: &str, paths: &Paths, target: PowerTarget,
+                retry_policy: RetryPolicy) -> PowerResult {
+    let mut result = PowerResult { disk: disk.to_string(), target, result: Ok(()), attempts: 0, not_reached: None };
+    let mut delay = retry_policy.delay;
+    loop {
+        result.attempts += 1;
+        result.result = backend.power_target(disk, paths, target);
+        if result.result.is_err() || retry_policy.retries == 0 || target == PowerTarget::Sleep {
+            return result;
+        }
+        thread::sleep(delay);
+        match backend.power_state(disk, paths) {
+            Ok(power_state) if target.is_reached(power_state) => {
+                result.not_reached = None;
+                return result;
+            }
+            Ok(power_state) => {
+                log::debug!("{} still reports {} after {} attempt(s) of {}", disk, power_state, result.attempts, target);
+                result.not_reached = Some(power_state);
+            }
+            Err(e) => {
+                log::debug!("unable to verify {} of {}: {}", target, disk, e.message);
+                return result;
+            }
+        }
+        if result.attempts > retry_policy.retries || signals::shutdown_requested() {
+            return result;
+        }
+        delay *= 2;
+    }
+}
\ No newline at end of file